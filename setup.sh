@@ -0,0 +1,5 @@
+# The files of a brand new image :
+#   cargo run -- --mmap disk.img setup.sh
+
+echo hello world pattern toto bibli ! > bar
+mkdir foo
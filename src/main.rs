@@ -5,5 +5,7 @@
 mod shell;
 
 fn main(){
-    shell::setup()
+    // the file system is dropped (and its image unmapped) before leaving
+    let status = shell::setup();
+    std::process::exit(status as i32)
 }
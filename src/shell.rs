@@ -2,14 +2,37 @@
     shell.rs
 */
 
+mod arith;
 mod fs;
+mod glob;
+mod lexer;
+mod parser;
 use fs::Fs;
 use fs::Format;
 use fs::Fdesc;
 use fs::FsErr;
 use fs::hd::Hd;
+use fs::hd::HdErr;
+use glob::NoMatch;
+use parser::AndOr;
+use parser::Case;
+use parser::Command;
+use parser::Connector;
+use parser::For;
+use parser::If;
+use parser::List;
+use parser::Loop;
+use parser::Pipeline;
+use parser::Redirect;
+use parser::RedirectKind;
+use parser::Simple;
+use parser::Word;
+use lexer::Part;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::io;
 use std::io::Write;
+use std::rc::Rc;
 
 #[derive(Debug)]
 enum ParsingErr {
@@ -17,8 +40,27 @@ enum ParsingErr {
     TooManyArgs,
     UnknownCommand,
     IncorrectRedirect,
-    MultipleInputs,
-    MultipleOutputs,
+    InvalidOption,
+    UnterminatedQuote,
+    TrailingEscape,
+    UnterminatedHereDoc,
+    MissingCommand,
+    BadFd,
+    InvalidVariable,
+    NoMatch,
+    UnterminatedSubst,
+    BadExpression,
+    DivisionByZero,
+    UnexpectedToken,
+    IncompleteCommand,
+    NotInFunction,
+}
+
+// a parsing error and the column (from 1) of the command line where it occurred
+#[derive(Debug)]
+struct SyntaxErr {
+    err : ParsingErr,
+    col : usize,
 }
 
 #[derive(Debug)]
@@ -30,11 +72,42 @@ enum CmdType {
     Mkfs,
     Mount,
     Mv,
+    Cp,
     Rm,
     Rmdir,
     Touch,
     Grep,
     Echo,
+    Iostat,
+    Df,
+    Overlay,
+    Snapshot,
+    Dedupe,
+    Defrag,
+    Sync,
+    Chattr,
+    Stat,
+    Pwrite,
+    Truncate,
+    Xattr,
+    Encrypt,
+    Unlock,
+    Lock,
+    Quota,
+    Setquota,
+    Resize,
+    Trash,
+    Undelete,
+    Export,
+    Unset,
+    Env,
+    Set,
+    Local,
+    Break,
+    Continue,
+    Return,
+    True,
+    False,
     Exit,
     Empty,
 }
@@ -46,66 +119,42 @@ struct SimpleCommand {
 }
 
 impl SimpleCommand {
-    fn split(input: &Format) -> Vec<Format> {
-        let mut res = Vec::new();
-        let mut i : usize = 0;
-        let mut curr = Vec::new();
-
-        while i < input.len() {
-            if input[i] == ' ' {
-                if curr.len() > 0 {
-                    res.push(curr);
-                    curr = Vec::new();
-                }
-            } else {
-                curr.push(input[i]);
-            }
-            i += 1;
-        }
-
-        if curr.len() > 0 {
-            res.push(curr);
-        }
-        res
-    }
-
     fn unsplit(input: &Vec<Format>) -> Format {
         let mut res = Vec::new();
         
         for w in input {
+            if !res.is_empty() {
+                res.push(' ');
+            }
             for &c in w {
                 res.push(c);
             }
-            res.push(' ');
         }
 
         res
     }
 
-    fn add_args(&mut self, stdin: Format) {
-        let mut splitted_stdin = Self::split(&stdin);
-        
-        match self.args.clone() {
-            Some(mut args) => {
-                args.append(&mut splitted_stdin);
-                self.args = Some(args)
-            },
-            None => {self.args = Some(splitted_stdin)}
+    // runs op on each operand : a failure is reported and the next operands
+    // go on, the status is the one of the last failure
+    fn each(args: &[Format], io: &mut Io, mut op: impl FnMut(&str, &mut Io) -> Option<FsErr>) -> EvalResult {
+        let mut status = 0;
+        for arg in args {
+            let arg = arg.iter().collect::<String>();
+            if let Some(err) = op(arg.trim(), io) {
+                status = fs_status(&err);
+                io.stderr.write(&fs_error(err));
+            }
         }
+        EvalResult::new(status)
     }
 
-    fn parse(input: Format) -> Result<SimpleCommand, ParsingErr> {
-        let input = Self::split(&input);
-
+    fn parse(input: Vec<Format>) -> Result<SimpleCommand, ParsingErr> {
         if input.len() == 0 {return Ok(SimpleCommand {name: CmdType::Empty, args: None});}
 
         match input[0].iter().collect::<String>().as_str().trim() {
             //TODO deeper checks of args
+            // the file commands take any number of operands (see each)
             "cat" => { 
-                if input.len() > 2 {
-                    return Err(ParsingErr::TooManyArgs);
-                }
-    
                 Ok(SimpleCommand {name: CmdType::Cat, args: if input.len() == 1 {None} else {Some(input[1..].to_vec())}})
             },
     
@@ -118,64 +167,65 @@ impl SimpleCommand {
             },
     
             "ls" => {
-                if input.len() > 2 {
-                    return Err(ParsingErr::TooManyArgs);
-                }
-    
                 Ok(SimpleCommand {name: CmdType::Ls, args: if input.len() == 1 {None} else {Some(input[1..].to_vec())}})
             },
     
             "mkdir" => {
-                if input.len() > 2 {
-                    return Err(ParsingErr::TooManyArgs);
+                if input.len() == 1 {
+                    return Err(ParsingErr::NotEnoughArgs);
                 }
     
-                Ok(SimpleCommand {name: CmdType::Mkdir, args: if input.len() == 1 {None} else {Some(input[1..].to_vec())}})
+                Ok(SimpleCommand {name: CmdType::Mkdir, args: Some(input[1..].to_vec())})
             },
             "mkfs" => Ok(SimpleCommand {name: CmdType::Mkfs, args: Some(input[1..].to_vec())}),
             "mount" => Ok(SimpleCommand {name: CmdType::Mount, args: Some(input[1..].to_vec())}),
+            // mv <src> <dest>, mv <src>... <dir>
             "mv" => {
-                if input.len() == 2 {
+                if input.len() < 3 {
                     return Err(ParsingErr::NotEnoughArgs);
                 }
+    
+                Ok(SimpleCommand {name: CmdType::Mv, args: Some(input[1..].to_vec())})
+            },
 
-                if input.len() > 3 {
-                    return Err(ParsingErr::TooManyArgs);
+            "cp" => {
+                if input.len() < 3 {
+                    return Err(ParsingErr::NotEnoughArgs);
                 }
-    
-                Ok(SimpleCommand {name: CmdType::Mv, args: if input.len() == 1  {None} else {Some(input[1..].to_vec())}})
+
+                Ok(SimpleCommand {name: CmdType::Cp, args: Some(input[1..].to_vec())})
             },
 
             "grep" => {
-                if input.len() > 3 {
-                    return Err(ParsingErr::TooManyArgs);
+                if input.len() == 1 {
+                    return Err(ParsingErr::NotEnoughArgs);
                 }
     
                 Ok(SimpleCommand {name: CmdType::Grep, args: if input.len() == 1  {None} else {Some(input[1..].to_vec())}})
             },
     
             "rm" => {
-                if input.len() > 2 {
-                    return Err(ParsingErr::TooManyArgs);
+                if input.len() == 1 {
+                    return Err(ParsingErr::NotEnoughArgs);
                 } 
     
-                Ok(SimpleCommand {name: CmdType::Rm, args: if input.len() == 1 {None} else {Some(input[1..].to_vec())}})
+                Ok(SimpleCommand {name: CmdType::Rm, args: Some(input[1..].to_vec())})
             },
     
             "rmdir" => {
-                if input.len() > 2 {
-                    return Err(ParsingErr::TooManyArgs);
+                if input.len() == 1 {
+                    return Err(ParsingErr::NotEnoughArgs);
                 }
     
-                Ok(SimpleCommand {name: CmdType::Rmdir, args: if input.len() == 1 {None} else {Some(input[1..].to_vec())}})
+                Ok(SimpleCommand {name: CmdType::Rmdir, args: Some(input[1..].to_vec())})
             },
     
             "touch" => {
-                if input.len() > 2 {
-                    return Err(ParsingErr::TooManyArgs);
+                if input.len() == 1 {
+                    return Err(ParsingErr::NotEnoughArgs);
                 }
     
-                Ok(SimpleCommand {name: CmdType::Touch, args: if input.len() == 1 {None} else {Some(input[1..].to_vec())}})
+                Ok(SimpleCommand {name: CmdType::Touch, args: Some(input[1..].to_vec())})
             },
     
             "echo" => {
@@ -183,13 +233,327 @@ impl SimpleCommand {
                 Ok(SimpleCommand {name: CmdType::Echo, args: if input.len() == 1 {None} else {Some(input[1..].to_vec())}})
             },
 
-            "exit" => {
+            "iostat" => {
+                if input.len() > 2 {
+                    return Err(ParsingErr::TooManyArgs);
+                }
+
+                if input.len() == 2 {
+                    match input[1].iter().collect::<String>().as_str() {
+                        "-l" | "-t" => (),
+                        _ => return Err(ParsingErr::InvalidOption),
+                    }
+                }
+
+                Ok(SimpleCommand {name: CmdType::Iostat, args: if input.len() == 1 {None} else {Some(input[1..].to_vec())}})
+            },
+
+            "df" => {
+                if input.len() > 1 {
+                    return Err(ParsingErr::TooManyArgs);
+                }
+
+                Ok(SimpleCommand {name: CmdType::Df, args: None})
+            },
+
+            "overlay" => {
+                if input.len() > 2 {
+                    return Err(ParsingErr::TooManyArgs);
+                }
+
+                if input.len() == 2 {
+                    match input[1].iter().collect::<String>().as_str() {
+                        "commit" | "discard" => (),
+                        _ => return Err(ParsingErr::InvalidOption),
+                    }
+                }
+
+                Ok(SimpleCommand {name: CmdType::Overlay, args: if input.len() == 1 {None} else {Some(input[1..].to_vec())}})
+            },
+
+            "snapshot" => {
+                if input.len() == 1 {
+                    return Err(ParsingErr::NotEnoughArgs);
+                }
+
+                match input[1].iter().collect::<String>().as_str() {
+                    "list" => if input.len() > 2 {return Err(ParsingErr::TooManyArgs)},
+                    "create" | "delete" | "restore" => {
+                        if input.len() == 2 {
+                            return Err(ParsingErr::NotEnoughArgs);
+                        }
+                        if input.len() > 3 {
+                            return Err(ParsingErr::TooManyArgs);
+                        }
+                    },
+                    _ => return Err(ParsingErr::InvalidOption),
+                }
+
+                Ok(SimpleCommand {name: CmdType::Snapshot, args: Some(input[1..].to_vec())})
+            },
+
+            "dedupe" => {
+                if input.len() > 1 {
+                    return Err(ParsingErr::TooManyArgs);
+                }
+
+                Ok(SimpleCommand {name: CmdType::Dedupe, args: None})
+            },
+
+            "defrag" => {
+                if input.len() > 1 {
+                    return Err(ParsingErr::TooManyArgs);
+                }
+
+                Ok(SimpleCommand {name: CmdType::Defrag, args: None})
+            },
+
+            "sync" => {
+                if input.len() > 1 {
+                    return Err(ParsingErr::TooManyArgs);
+                }
+
+                Ok(SimpleCommand {name: CmdType::Sync, args: None})
+            },
+
+            "chattr" => {
+                if input.len() < 3 {
+                    return Err(ParsingErr::NotEnoughArgs);
+                }
+                if input.len() > 3 {
+                    return Err(ParsingErr::TooManyArgs);
+                }
+
+                match input[1].iter().collect::<String>().as_str() {
+                    "+c" | "-c" => (),
+                    _ => return Err(ParsingErr::InvalidOption),
+                }
+
+                Ok(SimpleCommand {name: CmdType::Chattr, args: Some(input[1..].to_vec())})
+            },
+
+            "stat" => {
+                if input.len() == 1 {
+                    return Err(ParsingErr::NotEnoughArgs);
+                }
+
+                Ok(SimpleCommand {name: CmdType::Stat, args: Some(input[1..].to_vec())})
+            },
+
+            "pwrite" => {
+                if input.len() < 4 {
+                    return Err(ParsingErr::NotEnoughArgs);
+                }
+
+                if input[2].iter().collect::<String>().parse::<usize>().is_err() {
+                    return Err(ParsingErr::InvalidOption);
+                }
+
+                Ok(SimpleCommand {name: CmdType::Pwrite, args: Some(input[1..].to_vec())})
+            },
+
+            "truncate" => {
+                if input.len() < 4 {
+                    return Err(ParsingErr::NotEnoughArgs);
+                }
+                if input.len() > 4 {
+                    return Err(ParsingErr::TooManyArgs);
+                }
+
+                if input[1].iter().collect::<String>() != "-s" || parse_size(&input[2].iter().collect::<String>()).is_none() {
+                    return Err(ParsingErr::InvalidOption);
+                }
+
+                Ok(SimpleCommand {name: CmdType::Truncate, args: Some(input[2..].to_vec())})
+            },
+
+            "xattr" => {
+                if input.len() < 3 {
+                    return Err(ParsingErr::NotEnoughArgs);
+                }
+
+                match input[1].iter().collect::<String>().as_str() {
+                    "list" => if input.len() > 3 {return Err(ParsingErr::TooManyArgs)},
+                    "get" | "rm" => {
+                        if input.len() < 4 {
+                            return Err(ParsingErr::NotEnoughArgs);
+                        }
+                        if input.len() > 4 {
+                            return Err(ParsingErr::TooManyArgs);
+                        }
+                    },
+                    "set" => if input.len() < 5 {return Err(ParsingErr::NotEnoughArgs)},
+                    _ => return Err(ParsingErr::InvalidOption),
+                }
+
+                Ok(SimpleCommand {name: CmdType::Xattr, args: Some(input[1..].to_vec())})
+            },
+
+            "encrypt" | "unlock" => {
+                if input.len() < 3 {
+                    return Err(ParsingErr::NotEnoughArgs);
+                }
+                if input.len() > 3 {
+                    return Err(ParsingErr::TooManyArgs);
+                }
+
+                let name = if input[0].iter().collect::<String>() == "encrypt" {CmdType::Encrypt} else {CmdType::Unlock};
+                Ok(SimpleCommand {name, args: Some(input[1..].to_vec())})
+            },
+
+            "lock" => {
+                if input.len() > 2 {
+                    return Err(ParsingErr::TooManyArgs);
+                }
+
+                Ok(SimpleCommand {name: CmdType::Lock, args: if input.len() == 1 {None} else {Some(input[1..].to_vec())}})
+            },
+
+            "quota" => {
+                if input.len() > 2 {
+                    return Err(ParsingErr::TooManyArgs);
+                }
+
+                Ok(SimpleCommand {name: CmdType::Quota, args: if input.len() == 1 {None} else {Some(input[1..].to_vec())}})
+            },
+
+            "setquota" => {
+                if input.len() < 4 {
+                    return Err(ParsingErr::NotEnoughArgs);
+                }
+                if input.len() > 4 {
+                    return Err(ParsingErr::TooManyArgs);
+                }
+
+                if input[2..].iter().any(|arg| arg.iter().collect::<String>().trim().parse::<usize>().is_err()) {
+                    return Err(ParsingErr::InvalidOption);
+                }
+
+                Ok(SimpleCommand {name: CmdType::Setquota, args: Some(input[1..].to_vec())})
+            },
+
+            "trash" => {
+                if input.len() == 1 {
+                    return Err(ParsingErr::NotEnoughArgs);
+                }
+
+                match input[1].iter().collect::<String>().trim() {
+                    "on" | "off" | "list" | "empty" => if input.len() > 2 {return Err(ParsingErr::TooManyArgs)},
+                    "restore" => {
+                        if input.len() == 2 {
+                            return Err(ParsingErr::NotEnoughArgs);
+                        }
+                        if input.len() > 4 {
+                            return Err(ParsingErr::TooManyArgs);
+                        }
+                    },
+                    _ => return Err(ParsingErr::InvalidOption),
+                }
+
+                Ok(SimpleCommand {name: CmdType::Trash, args: Some(input[1..].to_vec())})
+            },
+
+            "undelete" => {
+                if input.len() > 1 {
+                    return Err(ParsingErr::TooManyArgs);
+                }
+
+                Ok(SimpleCommand {name: CmdType::Undelete, args: None})
+            },
+
+            "resize" => {
                 if input.len() == 1 {
-                    return Ok(SimpleCommand {name: CmdType::Exit, args: None});
+                    return Err(ParsingErr::NotEnoughArgs);
+                }
+                if input.len() > 2 {
+                    return Err(ParsingErr::TooManyArgs);
+                }
+
+                if parse_size(input[1].iter().collect::<String>().trim()).is_none() {
+                    return Err(ParsingErr::InvalidOption);
+                }
+
+                Ok(SimpleCommand {name: CmdType::Resize, args: Some(input[1..].to_vec())})
+            },
+
+            "export" | "unset" => {
+                let export = input[0].iter().collect::<String>() == "export";
+                if !export && input.len() == 1 {
+                    return Err(ParsingErr::NotEnoughArgs);
+                }
+                // only export takes a value
+                for arg in &input[1..] {
+                    let name = if export {arg.split(|&c| c == '=').next().unwrap_or(arg)} else {&arg[..]};
+                    if !parser::is_name(&name.to_vec()) {
+                        return Err(ParsingErr::InvalidVariable);
+                    }
+                }
+
+                Ok(SimpleCommand {name: if export {CmdType::Export} else {CmdType::Unset}, args: if input.len() == 1 {None} else {Some(input[1..].to_vec())}})
+            },
+
+            "env" => {
+                if input.len() > 1 {
+                    return Err(ParsingErr::TooManyArgs);
+                }
+
+                Ok(SimpleCommand {name: CmdType::Env, args: None})
+            },
+
+            // set [-o|+o [nullglob|failglob]]
+            "set" => {
+                if input.len() > 3 {
+                    return Err(ParsingErr::TooManyArgs);
+                }
+                let args : Vec<String> = input[1..].iter().map(|arg| arg.iter().collect()).collect();
+                match args.iter().map(|arg| arg.as_str()).collect::<Vec<&str>>()[..] {
+                    [] | ["-o"] | ["-o" | "+o", "nullglob" | "failglob"] => (),
+                    _ => return Err(ParsingErr::InvalidOption),
                 }
-                else {
+
+                Ok(SimpleCommand {name: CmdType::Set, args: if input.len() == 1 {None} else {Some(input[1..].to_vec())}})
+            },
+
+            "local" => {
+                for arg in &input[1..] {
+                    let name = arg.split(|&c| c == '=').next().unwrap_or(arg);
+                    if !parser::is_name(&name.to_vec()) {
+                        return Err(ParsingErr::InvalidVariable);
+                    }
+                }
+
+                Ok(SimpleCommand {name: CmdType::Local, args: if input.len() == 1 {None} else {Some(input[1..].to_vec())}})
+            },
+
+            // break [n], continue [n] : out of n loops
+            "break" | "continue" => {
+                if input.len() > 2 {
+                    return Err(ParsingErr::TooManyArgs);
+                }
+                if input.len() == 2 && !input[1].iter().collect::<String>().parse::<usize>().is_ok_and(|n| n > 0) {
+                    return Err(ParsingErr::InvalidOption);
+                }
+
+                let name = if input[0].iter().collect::<String>() == "break" {CmdType::Break} else {CmdType::Continue};
+                Ok(SimpleCommand {name, args: if input.len() == 1 {None} else {Some(input[1..].to_vec())}})
+            },
+
+            // return [status], exit [status]
+            "return" | "exit" => {
+                if input.len() > 2 {
                     return Err(ParsingErr::TooManyArgs);
                 }
+                if input.len() == 2 && input[1].iter().collect::<String>().parse::<u8>().is_err() {
+                    return Err(ParsingErr::InvalidOption);
+                }
+
+                let name = if input[0].iter().collect::<String>() == "return" {CmdType::Return} else {CmdType::Exit};
+                Ok(SimpleCommand {name, args: if input.len() == 1 {None} else {Some(input[1..].to_vec())}})
+            },
+
+            "true" | "false" => {
+                let name = if input[0].iter().collect::<String>() == "true" {CmdType::True} else {CmdType::False};
+                Ok(SimpleCommand {name, args: None})
             },
 
             "" => {
@@ -201,7 +565,7 @@ impl SimpleCommand {
 
     }
 
-    fn eval(&self, fs: &mut Fs, cur: &Fdesc) -> Result<EvalResult, FsErr> {
+    fn eval(&self, fs: &mut Fs, cur: &Fdesc, env: &mut Env, io: &mut Io) -> Result<EvalResult, FsErr> {
         
         let args = match &self.args {
             Some(args) => args.clone(),
@@ -215,75 +579,58 @@ impl SimpleCommand {
                 let res = fs.cd(cur, true_args)?;
                 return Ok(EvalResult {
                     fdesc: Some(res),
-                    stdout: None,
                     exit: false,
+                    status: 0,
+                    jump: None,
                 })
             },
     
             CmdType::Ls => {
-                let tmp : String;
-                let true_args = if args.len() == 0 {"."} else {tmp = args[0].iter().collect::<String>(); tmp.trim()};
-                let res = fs.ls(cur, true_args)?;
-                return Ok(EvalResult {
-                    fdesc: None,
-                    stdout: Some(res),
-                    exit: false,
-                })
+                let args = if args.is_empty() {vec![vec!['.']]} else {args};
+                // several directories are listed under their names
+                let named = args.len() > 1;
+                let mut first = true;
+                Ok(Self::each(&args, io, |path, io| {
+                    let res = match fs.ls(cur, path) {
+                        Ok(res) => res,
+                        Err(err) => return Some(err),
+                    };
+                    if named {
+                        io.stdout.write(&fmt_from(&format!("{}{path}:\n", if first {""} else {"\n"})));
+                        first = false;
+                    }
+                    io.stdout.write(&res);
+                    None
+                }))
             },
     
             CmdType::Cat => {
-                let tmp = args[0].iter().collect::<String>(); 
-                let true_args = tmp.trim();
-                let res = fs.cat(cur, true_args)?;
-                return Ok(EvalResult {
-                    fdesc: None,
-                    stdout: Some(res),
-                    exit: false,
-                })
+                // without a file, the input goes through
+                if args.is_empty() {
+                    let res = std::mem::take(&mut io.stdin);
+                    io.stdout.write(&res);
+                    return Ok(EvalResult::new(0))
+                }
+                Ok(Self::each(&args, io, |path, io| match fs.cat(cur, path) {
+                    Ok(res) => {io.stdout.write(&res); None},
+                    Err(err) => Some(err),
+                }))
             },
     
             CmdType::Mkdir => {
-                let tmp = args[0].iter().collect::<String>(); 
-                let true_args = tmp.trim();
-                if let Some(err) = fs.mkdir(cur, true_args) {return Err(err)};
-                return Ok(EvalResult{
-                    fdesc: None,
-                    stdout: None,
-                    exit: false,
-                })
+                Ok(Self::each(&args, io, |path, _| fs.mkdir(cur, path)))
             },
 
             CmdType::Touch => {
-                let tmp = args[0].iter().collect::<String>(); 
-                let true_args = tmp.trim();
-                if let Some(err) = fs.touch(cur, true_args) {return Err(err)};
-                return Ok(EvalResult{
-                    fdesc: None,
-                    stdout: None,
-                    exit: false,
-                })
+                Ok(Self::each(&args, io, |path, _| fs.touch(cur, path)))
             },
 
             CmdType::Rmdir => {
-                let tmp = args[0].iter().collect::<String>(); 
-                let true_args = tmp.trim();
-                if let Some(err) = fs.rmdir(cur, true_args) {return Err(err)};
-                return Ok(EvalResult{
-                    fdesc: None,
-                    stdout: None,
-                    exit: false,
-                })
+                Ok(Self::each(&args, io, |path, _| fs.rmdir(cur, path)))
             },
 
             CmdType::Rm => {
-                let tmp = args[0].iter().collect::<String>();
-                let true_args = tmp.trim();
-                if let Some(err) = fs.rm(cur, true_args) {return Err(err)};
-                return Ok(EvalResult{
-                    fdesc: None,
-                    stdout: None,
-                    exit: false,
-                })
+                Ok(Self::each(&args, io, |path, _| fs.rm(cur, path)))
             },
     
             CmdType::Mkfs => {todo!();},
@@ -291,251 +638,1030 @@ impl SimpleCommand {
             CmdType::Mount => {todo!();},
     
             CmdType::Mv => {
+                let (dest, srcs) = args.split_last().unwrap();
+                let dest = dest.iter().collect::<String>();
+                // several files only move into a directory
+                if srcs.len() > 1 {fs.cd(cur, dest.trim())?;}
+                Ok(Self::each(srcs, io, |path, _| fs.mv(cur, path, dest.trim())))
+            },
+
+            CmdType::Cp => {
+                let (dest, srcs) = args.split_last().unwrap();
+                let dest = dest.iter().collect::<String>();
+                // several files only copy into a directory
+                if srcs.len() > 1 {fs.cd(cur, dest.trim())?;}
+                Ok(Self::each(srcs, io, |path, _| fs.cp(cur, path, dest.trim())))
+            },
+
+            CmdType::Grep => {
                 let tmp1 = args[0].iter().collect::<String>();
-                let tmp2 = args[1].iter().collect::<String>();
-                if let Some(err) = fs.mv(cur, tmp1.trim(), tmp2.trim()) {return Err(err)};
+                if args.len() == 1 {
+                    let res = fs::grep_words(&std::mem::take(&mut io.stdin), tmp1.trim());
+                    io.stdout.write(&res);
+                    // nothing found is a failure
+                    return Ok(EvalResult::new(if res.is_empty() {1} else {0}))
+                }
+                // the words found in several files follow the name of their file
+                let named = args.len() > 2;
+                let mut found = false;
+                let mut res = Self::each(&args[1..], io, |path, io| {
+                    let words = match fs.grep(cur, path, tmp1.trim()) {
+                        Ok(words) => words,
+                        Err(err) => return Some(err),
+                    };
+                    found |= !words.is_empty();
+                    for word in words.split_inclusive(|&c| c == '\n') {
+                        if named {io.stdout.write(&fmt_from(&format!("{path}:")))};
+                        io.stdout.write(&word.to_vec());
+                    }
+                    None
+                });
+                if res.status == 0 && !found {res.status = 1};
+                Ok(res)
+            },
+    
+            CmdType::Echo => {//TODO
+                let mut res = Self::unsplit(&args); 
+                res.push('\n');
+                if !args.is_empty() {io.stdout.write(&res)};
+                return Ok(EvalResult{
+                    fdesc: None,
+                    exit: false,
+                    status: 0,
+                    jump: None,
+                })
+            },
+    
+            CmdType::Iostat => {
+                let opt = if args.is_empty() {String::new()} else {args[0].iter().collect::<String>()};
+                let res = match opt.as_str() {
+                    // toggle the trace mode
+                    "-t" => {
+                        let trace = !fs.is_tracing();
+                        fs.set_trace(trace);
+                        (if trace {"trace on\n"} else {"trace off\n"}).chars().collect()
+                    },
+                    "-l" => fs.iostat(true),
+                    _ => fs.iostat(false),
+                };
+                io.stdout.write(&res);
                 return Ok(EvalResult{
                     fdesc: None,
-                    stdout: None,
                     exit: false,
+                    status: 0,
+                    jump: None,
                 })
             },
 
-            CmdType::Grep => {
+            CmdType::Df => {
+                io.stdout.write(&fs.df());
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Overlay => {
+                let action = if args.is_empty() {String::new()} else {args[0].iter().collect::<String>()};
+                match action.as_str() {
+                    "commit" => if let Some(err) = fs.overlay_commit() {return Err(err)},
+                    // the current directory may not exist in the base
+                    "discard" => {
+                        if let Some(err) = fs.overlay_discard() {return Err(err)};
+                        return Ok(EvalResult{
+                            fdesc: Some(fs.get_home_fdesc()),
+                            exit: false,
+                            status: 0,
+                            jump: None,
+                        })
+                    },
+                    _ => {
+                        io.stdout.write(&fs.overlay_status()?);
+                        return Ok(EvalResult{
+                            fdesc: None,
+                            exit: false,
+                            status: 0,
+                            jump: None,
+                        })
+                    },
+                };
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Snapshot => {
+                let action = args[0].iter().collect::<String>();
+                let name = if args.len() > 1 {args[1].iter().collect::<String>()} else {String::new()};
+                let err = match action.as_str() {
+                    "list" => {
+                        io.stdout.write(&fs.snapshot_list()?);
+                        return Ok(EvalResult{
+                            fdesc: None,
+                            exit: false,
+                            status: 0,
+                            jump: None,
+                        })
+                    },
+                    "create" => fs.snapshot_create(name.trim()),
+                    "delete" => fs.snapshot_delete(name.trim()),
+                    // the current directory may not exist in the snapshot
+                    _ => {
+                        if let Some(err) = fs.snapshot_restore(name.trim()) {return Err(err)};
+                        return Ok(EvalResult{
+                            fdesc: Some(fs.get_home_fdesc()),
+                            exit: false,
+                            status: 0,
+                            jump: None,
+                        })
+                    },
+                };
+                if let Some(err) = err {return Err(err)};
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Dedupe => {
+                io.stdout.write(&fs.dedupe()?);
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Defrag => {
+                io.stdout.write(&fs.defrag()?);
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Sync => {
+                if let Some(err) = fs.sync() {return Err(err)};
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Chattr => {
+                let compr = args[0].iter().collect::<String>() == "+c";
+                let tmp = args[1].iter().collect::<String>();
+                if let Some(err) = fs.chattr(cur, tmp.trim(), compr) {return Err(err)};
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Stat => {
+                Ok(Self::each(&args, io, |path, io| match fs.stat(cur, path) {
+                    Ok(res) => {io.stdout.write(&res); None},
+                    Err(err) => Some(err),
+                }))
+            },
+
+            CmdType::Pwrite => {
+                let tmp = args[0].iter().collect::<String>();
+                let offset = args[1].iter().collect::<String>().parse::<usize>().unwrap_or(0);
+                let data = Self::unsplit(&args[2..].to_vec());
+                if let Some(err) = fs.write_at(cur, tmp.trim(), offset, &data) {return Err(err)};
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Truncate => {
+                let len = parse_size(&args[0].iter().collect::<String>()).unwrap_or(0);
+                let tmp = args[1].iter().collect::<String>();
+                if let Some(err) = fs.truncate(cur, tmp.trim(), len) {return Err(err)};
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Xattr => {
+                let action = args[0].iter().collect::<String>();
+                let tmp = args[1].iter().collect::<String>();
+                let path = tmp.trim();
+                let name = if args.len() > 2 {args[2].iter().collect::<String>()} else {String::new()};
+                let err = match action.as_str() {
+                    "list" => {
+                        io.stdout.write(&fs.listxattr(cur, path)?);
+                        return Ok(EvalResult{
+                            fdesc: None,
+                            exit: false,
+                            status: 0,
+                            jump: None,
+                        })
+                    },
+                    "get" => {
+                        io.stdout.write(&fs.getxattr(cur, path, name.trim())?);
+                        return Ok(EvalResult{
+                            fdesc: None,
+                            exit: false,
+                            status: 0,
+                            jump: None,
+                        })
+                    },
+                    "set" => {
+                        let value = Self::unsplit(&args[3..].to_vec());
+                        fs.setxattr(cur, path, name.trim(), &value)
+                    },
+                    _ => fs.removexattr(cur, path, name.trim()),
+                };
+                if let Some(err) = err {return Err(err)};
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Encrypt => {
+                let tmp1 = args[0].iter().collect::<String>();
+                let tmp2 = args[1].iter().collect::<String>();
+                if let Some(err) = fs.encrypt(cur, tmp1.trim(), tmp2.trim()) {return Err(err)};
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Unlock => {
                 let tmp1 = args[0].iter().collect::<String>();
                 let tmp2 = args[1].iter().collect::<String>();
-                let res = fs.grep(cur, tmp2.trim(), tmp1.trim())?;
+                if let Some(err) = fs.unlock(cur, tmp1.trim(), tmp2.trim()) {return Err(err)};
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Lock => {
+                let tmp = if args.is_empty() {String::new()} else {args[0].iter().collect::<String>()};
+                let path = if args.is_empty() {None} else {Some(tmp.trim())};
+                if let Some(err) = fs.lock(cur, path) {return Err(err)};
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Quota => {
+                let tmp = if args.is_empty() {String::new()} else {args[0].iter().collect::<String>()};
+                let path = if args.is_empty() {None} else {Some(tmp.trim())};
+                let res = fs.quota(cur, path)?;
+                io.stdout.write(&res);
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Setquota => {
+                let tmp = args[0].iter().collect::<String>();
+                let blocks = args[1].iter().collect::<String>().trim().parse::<usize>().unwrap_or(0);
+                let inodes = args[2].iter().collect::<String>().trim().parse::<usize>().unwrap_or(0);
+                if let Some(err) = fs.setquota(cur, tmp.trim(), blocks, inodes) {return Err(err)};
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Trash => {
+                let action = args[0].iter().collect::<String>();
+                let name = if args.len() > 1 {args[1].iter().collect::<String>()} else {String::new()};
+                let path = if args.len() > 2 {args[2].iter().collect::<String>()} else {String::new()};
+                let err = match action.trim() {
+                    "list" => {
+                        io.stdout.write(&fs.trash_list()?);
+                        return Ok(EvalResult{
+                            fdesc: None,
+                            exit: false,
+                            status: 0,
+                            jump: None,
+                        })
+                    },
+                    "on" => fs.set_trash(true),
+                    "off" => fs.set_trash(false),
+                    "empty" => fs.trash_empty(),
+                    _ => fs.trash_restore(cur, name.trim(), if args.len() > 2 {Some(path.trim())} else {None}),
+                };
+                if let Some(err) = err {return Err(err)};
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Undelete => {
+                io.stdout.write(&fs.undelete()?);
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Resize => {
+                let size = parse_size(args[0].iter().collect::<String>().trim()).unwrap_or(0);
+                if let Some(err) = fs.grow(size) {return Err(err)};
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Export => {
+                // without a name, the exported variables are listed
+                if args.is_empty() {io.stdout.write(&env.list(true))};
+                for arg in &args {
+                    let eq = arg.iter().position(|&c| c == '=');
+                    let name = arg[..eq.unwrap_or(arg.len())].iter().collect::<String>();
+                    if let Some(eq) = eq {env.set(&name, arg[eq + 1..].to_vec())};
+                    env.vars.entry(name).or_insert(Var {value: Vec::new(), exported: true}).exported = true;
+                }
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Unset => {
+                for arg in &args {
+                    env.vars.remove(&arg.iter().collect::<String>());
+                }
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Env => {
+                io.stdout.write(&env.list(true));
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Set => {
+                let args : Vec<String> = args.iter().map(|arg| arg.iter().collect()).collect();
+                match args.iter().map(|arg| arg.as_str()).collect::<Vec<&str>>()[..] {
+                    [] => io.stdout.write(&env.list(false)),
+                    // the options of the patterns without match, the last one set wins
+                    [_] => {
+                        let on = |nomatch| if env.nomatch == nomatch {"on"} else {"off"};
+                        io.stdout.write(&format!("nullglob {}\nfailglob {}\n", on(NoMatch::Empty), on(NoMatch::Fail)).chars().collect());
+                    },
+                    ["-o", "nullglob"] => env.nomatch = NoMatch::Empty,
+                    ["-o", _] => env.nomatch = NoMatch::Fail,
+                    ["+o", "nullglob"] if env.nomatch == NoMatch::Empty => env.nomatch = NoMatch::Keep,
+                    ["+o", "failglob"] if env.nomatch == NoMatch::Fail => env.nomatch = NoMatch::Keep,
+                    _ => (),
+                }
+                Ok(EvalResult::new(0))
+            },
+
+            CmdType::Local => {
+                // the variables come back when the function returns (the
+                // caller checks that one runs)
+                let frame = env.locals.last_mut().expect("local outside of a function");
+                for arg in &args {
+                    let eq = arg.iter().position(|&c| c == '=');
+                    let name = arg[..eq.unwrap_or(arg.len())].iter().collect::<String>();
+                    if !frame.iter().any(|(saved, _)| *saved == name) {frame.push((name.clone(), env.vars.get(&name).cloned()))};
+                    let value = eq.map_or(Vec::new(), |eq| arg[eq + 1..].to_vec());
+                    env.vars.insert(name, Var {value, exported: false});
+                }
                 return Ok(EvalResult{
                     fdesc: None,
-                    stdout: Some(res),
                     exit: false,
+                    status: 0,
+                    jump: None,
                 })
             },
-    
-            CmdType::Echo => {//TODO
-                let mut res = Self::unsplit(&args); 
-                res.push('\n');
-                return Ok(EvalResult{
+
+            CmdType::Break | CmdType::Continue => {
+                let n = args.first().map_or(1, |arg| arg.iter().collect::<String>().parse::<usize>().unwrap_or(1));
+                Ok(EvalResult{
                     fdesc: None,
-                    stdout: if args.len() > 0 { Some(res) } else { None },
                     exit: false,
+                    status: 0,
+                    jump: Some(if matches!(self.name, CmdType::Break) {Jump::Break(n)} else {Jump::Continue(n)}),
                 })
             },
-    
-            CmdType::Exit => {
-                return Ok(EvalResult{
+
+            // without status, the one of the last command
+            CmdType::Return | CmdType::Exit => {
+                let status = args.first().map_or(env.status, |arg| arg.iter().collect::<String>().parse::<u8>().unwrap_or(0));
+                let exit = matches!(self.name, CmdType::Exit);
+                Ok(EvalResult{
                     fdesc: None,
-                    stdout: None,
-                    exit: true,
+                    exit,
+                    status,
+                    jump: if exit {None} else {Some(Jump::Return)},
                 })
-            }
+            },
+
+            CmdType::True | CmdType::False => {
+                Ok(EvalResult::new(if matches!(self.name, CmdType::True) {0} else {1}))
+            },
 
             CmdType::Empty => {
                 return Ok(EvalResult{
                     fdesc: None,
-                    stdout: None,
                     exit: false,
+                    status: 0,
+                    jump: None,
                 })
             },
         }
     }
 }
 
-#[derive(Debug)]
-struct Piped {
-    cmd: SimpleCommand,
-    output : Option<Format>,
-    input  : Option<Format>
+// An output stream : the terminal, or a buffer (a pipe, a file written once
+// the command is done). The clones of a buffer share it, as 2>&1 does.
+#[derive(Clone)]
+enum Out {
+    Stdout,
+    Stderr,
+    Buf(Rc<RefCell<Format>>),
 }
 
-impl Piped {
-    fn get_first_word(input: Format, offset: usize) -> (usize, usize) {
-        let mut start = 0;
+impl Out {
+    fn buf() -> Self {
+        Out::Buf(Rc::new(RefCell::new(Vec::new())))
+    }
 
-        while start < input.len() && input[start] == ' ' {
-            start += 1;
+    fn write(&self, data : &Format) {
+        match self {
+            Out::Stdout => print(data),
+            Out::Stderr => eprint!("{}", data.iter().collect::<String>()),
+            Out::Buf(buf) => buf.borrow_mut().extend_from_slice(data),
         }
+    }
 
-        let mut end = start;
-
-        while end < input.len() && input[end] != ' ' {
-            end += 1;
+    // empties a buffer, returns what has been written into it
+    fn take(&self) -> Format {
+        match self {
+            Out::Buf(buf) => std::mem::take(&mut *buf.borrow_mut()),
+            _ => Vec::new(),
         }
-        (start+offset, end+offset)
     }
+}
 
-    fn parse(input: Format) -> Result<Self, ParsingErr> {
-        let mut inpt = None; let mut outpt = None;
-        let mut start = 0;
-        let mut end = 0;
-        let mut simple_cmd = None;
+// the standard streams of a command : the input is read at once
+struct Io {
+    stdin  : Format,
+    stdout : Out,
+    stderr : Out,
+}
 
-        while end < input.len() {
-            match input[end] {
-                '>' => {
-                    match SimpleCommand::parse(input[start..end].to_vec()) {
-                        Ok(cmd) => {
+impl Io {
+    fn term() -> Self {
+        Io {stdin: Vec::new(), stdout: Out::Stdout, stderr: Out::Stderr}
+    }
 
-                            let (wstart, wend) = Self::get_first_word(input[end+1..].to_vec(), end+1);
-                            let file = input[wstart..wend].to_vec();
+    fn out(&mut self, fd : usize) -> &mut Out {
+        if fd == 2 {&mut self.stderr} else {&mut self.stdout}
+    }
+}
 
-                            simple_cmd = Some(cmd);
-                            outpt = Some(file);
-                            start = wend+1;
-                            end = start;
-                            break;
-                        },
+// a shell variable, listed by env when exported
+#[derive(Clone)]
+struct Var {
+    value    : Format,
+    exported : bool,
+}
 
-                        Err(err) => return Err(err),
-                    }
-                },
+// the state of the shell kept from a command to the next one, a command
+// substitution runs in a copy of it
+#[derive(Clone)]
+struct Env {
+    status : u8,                    // of the last pipeline
+    vars   : BTreeMap<String, Var>,
+    args   : Vec<Format>,           // the positional parameters, from $0
+    nomatch : NoMatch,              // of the patterns
+    funcs  : BTreeMap<String, Rc<Command>>,
+    locals : Vec<Vec<(String, Option<Var>)>>,  // the variables hidden by local, per running function
+    subst  : Option<u8>,            // of the last command substitution of the command being expanded
+}
 
-                '<' => {
-                    match SimpleCommand::parse(input[start..end].to_vec()) {
-                        Ok(cmd) => {
+impl Env {
+    fn new() -> Self {
+        let mut env = Env {status: 0, vars: BTreeMap::new(), args: vec![fmt_from("fs")], nomatch: NoMatch::Keep, funcs: BTreeMap::new(), locals: Vec::new(), subst: None};
+        let user = std::env::var("USER").unwrap_or("user".to_string());
+        for (name, value) in [("HOME", "/"), ("USER", user.as_str()), ("PWD", "/")] {
+            env.vars.insert(name.to_string(), Var {value: fmt_from(value), exported: true});
+        }
+        env
+    }
 
-                            let (wstart, wend) = Self::get_first_word(input[end+1..].to_vec(), end+1);
-                            let file = input[wstart..wend].to_vec();
+    // an existing variable stays exported or not
+    fn set(&mut self, name : &str, value : Format) {
+        match self.vars.get_mut(name) {
+            Some(var) => var.value = value,
+            None => {self.vars.insert(name.to_string(), Var {value, exported: false});},
+        }
+    }
 
-                            simple_cmd = Some(cmd);
-                            inpt = Some(file);
-                            start = wend+1;
-                            end = start;
-                            break;
-                        },
-                        Err(err) => return Err(err),
+    // NAME=value lines, of the exported variables only or of all of them
+    fn list(&self, exported : bool) -> Format {
+        let mut res = Vec::new();
+        for (name, var) in self.vars.iter().filter(|(_, var)| var.exported || !exported) {
+            res.extend(name.chars());
+            res.push('=');
+            res.extend_from_slice(&var.value);
+            res.push('\n');
+        }
+        res
+    }
+
+    // an unset parameter is empty
+    fn param(&self, name : &Format) -> Format {
+        match name[..] {
+            ['?'] => self.status.to_string().chars().collect(),
+            ['#'] => (self.args.len() - 1).to_string().chars().collect(),
+            ['@'] => SimpleCommand::unsplit(&self.args[1..].to_vec()),
+            [c, ..] if c.is_ascii_digit() => {
+                let n = name.iter().collect::<String>().parse::<usize>().unwrap_or(usize::MAX);
+                self.args.get(n).cloned().unwrap_or_default()
+            },
+            _ => self.vars.get(&name.iter().collect::<String>()).map(|var| var.value.clone()).unwrap_or_default(),
+        }
+    }
+
+    // the value of a parameter, of the output of a command or of an expression
+    fn value(&mut self, part : &Part, fs : &mut Fs, cur : &Fdesc, stderr : &Out) -> Result<Format, ParsingErr> {
+        match part {
+            Part::Lit(text) | Part::Quoted(text) => Ok(text.clone()),
+            Part::Param(name, _) => Ok(self.param(name)),
+            Part::Cmd(cmd, _) => {
+                let (res, status) = self.substitute(cmd, fs, cur, stderr);
+                self.subst = Some(status);
+                Ok(res)
+            },
+            Part::Arith(expr) => {
+                let mut text = Vec::new();
+                for part in lexer::quoted_parts(expr, 0, expr.len(), &mut Vec::new()) {
+                    text.append(&mut self.value(&part, fs, cur, stderr)?);
+                }
+                let res = arith::eval(&text, &|name| self.param(&name.chars().collect()))?;
+                Ok(res.to_string().chars().collect())
+            },
+        }
+    }
+
+    // runs a command line in a copy of the shell, returns its output without
+    // the newlines at the end, and its status
+    fn substitute(&self, cmd : &Format, fs : &mut Fs, cur : &Fdesc, stderr : &Out) -> (Format, u8) {
+        // the lexer has already checked it
+        let list = match parser::parse(cmd) {
+            Ok(list) => list,
+            Err(errs) => return (Vec::new(), parsing_status(&errs[0].err)),
+        };
+        let out = Out::buf();
+        let mut io = Io {stdin: Vec::new(), stdout: out.clone(), stderr: stderr.clone()};
+        let status = list.eval(fs, cur, &mut self.clone(), &mut io).status;
+        let mut res = out.take();
+        while res.last() == Some(&'\n') {res.pop();}
+        (res, status)
+    }
+
+    // a word as a single field (a redirection target, a value)
+    fn expand(&mut self, word : &Word, fs : &mut Fs, cur : &Fdesc, stderr : &Out) -> Result<Format, ParsingErr> {
+        let mut res = Vec::new();
+        for part in &word.parts {
+            res.append(&mut self.value(part, fs, cur, stderr)?);
+        }
+        Ok(res)
+    }
+
+    // the fields of an argument : the unquoted parameters and commands are
+    // split on the blanks, "$@" gives a field per positional parameter, and a
+    // word left empty without quotes gives no field at all. The fields are
+    // patterns, the quoted chars are escaped.
+    fn fields(&mut self, word : &Word, fs : &mut Fs, cur : &Fdesc, stderr : &Out) -> Result<Vec<Format>, ParsingErr> {
+        let mut fields = Vec::new();
+        let mut field : Option<Format> = None;
+        for part in &word.parts {
+            match part {
+                Part::Lit(text) => field.get_or_insert_with(Vec::new).extend_from_slice(text),
+                Part::Quoted(text) => field.get_or_insert_with(Vec::new).append(&mut glob::quote(text)),
+                Part::Param(name, true) if name[..] == ['@'] => {
+                    for (k, arg) in self.args[1..].iter().enumerate() {
+                        if k > 0 {fields.push(field.take().unwrap_or_default())};
+                        field.get_or_insert_with(Vec::new).append(&mut glob::quote(arg));
                     }
                 },
+                Part::Param(_, true) | Part::Cmd(_, true) | Part::Arith(_) => {
+                    field.get_or_insert_with(Vec::new).append(&mut glob::quote(&self.value(part, fs, cur, stderr)?));
+                },
+                Part::Param(_, false) | Part::Cmd(_, false) => {
+                    let value = self.value(part, fs, cur, stderr)?;
+                    for (k, piece) in value.split(|c| c.is_whitespace()).enumerate() {
+                        if k > 0 {fields.extend(field.take())};
+                        // the value stays a pattern
+                        let piece : Format = piece.iter().flat_map(|&c| if c == '\\' {vec!['\\', c]} else {vec![c]}).collect();
+                        if !piece.is_empty() {field.get_or_insert_with(Vec::new).extend_from_slice(&piece)};
+                    }
+                },
+            }
+        }
+        fields.extend(field);
+        Ok(fields)
+    }
 
-                _ => end += 1,
+    // the arguments of a command (or of a for loop) : the fields of the
+    // words, with their patterns expanded
+    fn words(&mut self, words : &[Word], fs : &mut Fs, cur : &Fdesc, stderr : &Out) -> Result<Vec<Format>, SyntaxErr> {
+        let mut res = Vec::new();
+        for word in words {
+            let fields = self.fields(word, fs, cur, stderr).map_err(|err| SyntaxErr {err, col: word.col})?;
+            for field in fields {
+                match glob::glob(fs, cur, &field) {
+                    Some(mut names) => res.append(&mut names),
+                    None if self.nomatch == NoMatch::Keep => res.push(glob::unquote(&field)),
+                    None if self.nomatch == NoMatch::Empty => (),
+                    None => return Err(SyntaxErr {err: ParsingErr::NoMatch, col: word.col}),
+                }
             }
         }
-        
-        if let None = simple_cmd {
-            match SimpleCommand::parse(input) {
-                Err(err) => return Err(err),
-                Ok(cmd) => return Ok(Piped {
-                    cmd     : cmd,
-                    output  : outpt,
-                    input   : inpt
-                }),
+        Ok(res)
+    }
+
+    // a word as a pattern of a case : the quoted chars are escaped
+    fn pattern(&mut self, word : &Word, fs : &mut Fs, cur : &Fdesc, stderr : &Out) -> Result<Format, ParsingErr> {
+        let mut res = Vec::new();
+        for part in &word.parts {
+            match part {
+                Part::Lit(text) => res.extend_from_slice(text),
+                Part::Param(_, false) | Part::Cmd(_, false) => res.append(&mut self.value(part, fs, cur, stderr)?),
+                _ => res.append(&mut glob::quote(&self.value(part, fs, cur, stderr)?)),
             }
         }
+        Ok(res)
+    }
 
-        while end < input.len() {
-            match input[end] {
-                ' ' => {start += 1; end += 1},
+    // runs a function with its own positional parameters (words but the
+    // name) and local variables
+    fn call(&mut self, body : &Command, words : Vec<Format>, fs : &mut Fs, cur : &Fdesc, io : &mut Io) -> EvalResult {
+        let mut args = vec![self.args[0].clone()];
+        args.extend(words.into_iter().skip(1));
+        let args = std::mem::replace(&mut self.args, args);
+        self.locals.push(Vec::new());
+        let mut res = body.eval(fs, cur, self, io);
+        for (name, var) in self.locals.pop().unwrap_or_default().into_iter().rev() {
+            match var {
+                Some(var) => {self.vars.insert(name, var);},
+                None => {self.vars.remove(&name);},
+            }
+        }
+        self.args = args;
+        // a return stops here, a break or a continue out of the loops as well
+        res.jump = None;
+        res
+    }
+}
+
+trait Exec {
+    fn eval(&self, fs: &mut Fs, cur: &Fdesc, env: &mut Env, io: &mut Io) -> EvalResult;
+}
+
+impl Exec for Simple {
+    fn eval(&self, fs: &mut Fs, cur: &Fdesc, env: &mut Env, io: &mut Io) -> EvalResult {
+        let mut res = EvalResult {
+            fdesc: None,
+            exit: false,
+            status: 0,
+            jump: None,
+        };
+
+        env.subst = None;
+        let (mut cmd_io, files, status) = open_redirects(&self.redirects, fs, cur, env, io);
+        res.status = status;
+
+        // without a command, the assignments stay, else they are exported to
+        // the command only
+        let mut words = Vec::new();
+        if res.status == 0 {
+            match env.words(&self.words, fs, cur, &cmd_io.stderr) {
+                Ok(fields) => words = fields,
+                Err(err) => res.status = report(err, &cmd_io.stderr),
+            }
+        }
+        let mut saved = Vec::new();
+        for assign in &self.assigns {
+            if res.status != 0 {break}
+            let name = assign.name.iter().collect::<String>();
+            let value = match env.expand(&assign.value, fs, cur, &cmd_io.stderr) {
+                Ok(value) => value,
+                Err(err) => {res.status = report(SyntaxErr {err, col: assign.value.col}, &cmd_io.stderr); break},
+            };
+            if words.is_empty() {env.set(&name, value); continue};
+            let old = env.vars.insert(name.clone(), Var {value, exported: true});
+            saved.push((name, old));
+        }
 
-                '>' => {
-                    if let Some(_) = outpt {return Err(ParsingErr::MultipleOutputs)}
-                    let (wstart, wend) = Self::get_first_word(input[end+1..].to_vec(), end+1);
-                    let file = input[wstart..wend].to_vec();
-                    outpt = Some(file);
-                    start = wend+1;
-                    end = start;
+        // without a command, the status is the one of the last substitution
+        let no_command = words.is_empty();
+        // the functions come before the builtins
+        let func = words.first().and_then(|name| env.funcs.get(&name.iter().collect::<String>())).cloned();
+        if res.status == 0 {
+            res = match func {
+                Some(body) => env.call(&body, words, fs, cur, &mut cmd_io),
+                None => match SimpleCommand::parse(words) {
+                    // the arguments are checked as a whole : the error points at the command
+                    Err(err) => {res.status = report(SyntaxErr {err, col: self.words[0].col}, &cmd_io.stderr); res},
+                    Ok(cmd) if matches!(cmd.name, CmdType::Local) && env.locals.is_empty() => {
+                        res.status = report(SyntaxErr {err: ParsingErr::NotInFunction, col: self.words[0].col}, &cmd_io.stderr);
+                        res
+                    },
+                    Ok(cmd) => match cmd.eval(fs, cur, env, &mut cmd_io) {
+                        Ok(res) => res,
+                        Err(err) => {res.status = fs_status(&err); cmd_io.stderr.write(&fs_error(err)); res},
+                    },
                 },
+            };
+            if no_command {res.status = env.subst.unwrap_or(0)};
+        }
+        for (name, old) in saved.into_iter().rev() {
+            match old {
+                Some(var) => {env.vars.insert(name, var);},
+                None => {env.vars.remove(&name);},
+            }
+        }
+        if let Some(fdesc) = &res.fdesc {
+            if let Ok(path) = fs.pwd(fdesc) {env.set("PWD", fmt_from(&path))};
+        }
+        if let Some(status) = close_redirects(&self.redirects, fs, cur, io, cmd_io, files) {res.status = status};
+        res
+    }
+}
 
-                '<' => {
-                    if let Some(_) = inpt {return Err(ParsingErr::MultipleInputs);}
-                    let (wstart, wend) = Self::get_first_word(input[end+1..].to_vec(), end+1);
-                    let file = input[wstart..wend].to_vec();
-                    inpt = Some(file);
-                    start = wend+1;
-                    end = start;
+// a file written by a redirection : its name, whether it is appended, and
+// what the command writes into it
+type Output = (String, bool, Out);
+
+// the io of a command with its redirections, done from left to right, the
+// files of the outputs (written by close_redirects) and the status of the
+// redirection which failed, else 0 : the command does not run
+fn open_redirects(redirects: &[Redirect], fs: &mut Fs, cur: &Fdesc, env: &mut Env, io: &mut Io) -> (Io, Vec<Output>, u8) {
+    let own_input = redirects.iter().any(|redirect| redirect.fd == 0);
+    let mut cmd_io = Io {
+        stdin  : if own_input {Vec::new()} else {std::mem::take(&mut io.stdin)},
+        stdout : io.stdout.clone(),
+        stderr : io.stderr.clone(),
+    };
+    let mut files = Vec::new();
+    for redirect in redirects {
+        let mut target = match env.expand(&redirect.target, fs, cur, &io.stderr) {
+            Ok(target) => target,
+            Err(err) => return (cmd_io, files, report(SyntaxErr {err, col: redirect.target.col}, &io.stderr)),
+        };
+        let name = target.iter().collect::<String>();
+        let name = name.trim();
+        match redirect.kind {
+            RedirectKind::Input | RedirectKind::ReadWrite => match fs.cat(cur, name) {
+                Ok(content) => cmd_io.stdin = content,
+                Err(FsErr::FileNotFound) if redirect.kind == RedirectKind::ReadWrite => {
+                    if let Some(err) = fs.touch(cur, name) {
+                        let status = fs_status(&err);
+                        io.stderr.write(&fs_error(err));
+                        return (cmd_io, files, status)
+                    };
+                    cmd_io.stdin = Vec::new();
                 },
+                Err(err) => {
+                    let status = fs_status(&err);
+                    io.stderr.write(&fs_error(err));
+                    return (cmd_io, files, status)
+                },
+            },
+            RedirectKind::HereDoc => cmd_io.stdin = target,
+            RedirectKind::HereString => {
+                target.push('\n');
+                cmd_io.stdin = target;
+            },
+            RedirectKind::Output | RedirectKind::Append => {
+                // the file is created (or emptied) before the command runs
+                let err = if redirect.kind == RedirectKind::Output {write_file(fs, cur, name, &Vec::new())} else {
+                    match fs.cat(cur, name) {
+                        Ok(_) => None,
+                        Err(FsErr::FileNotFound) => fs.touch(cur, name),
+                        Err(err) => Some(err),
+                    }
+                };
+                if let Some(err) = err {
+                    let status = fs_status(&err);
+                    io.stderr.write(&fs_error(err));
+                    return (cmd_io, files, status)
+                };
+                let out = Out::buf();
+                files.push((name.to_string(), redirect.kind == RedirectKind::Append, out.clone()));
+                *cmd_io.out(redirect.fd) = out;
+            },
+            RedirectKind::Dup => {
+                let out = cmd_io.out(name.parse::<usize>().unwrap_or(1)).clone();
+                *cmd_io.out(redirect.fd) = out;
+            },
+        }
+    }
+    (cmd_io, files, 0)
+}
 
-                _ => return Err(ParsingErr::IncorrectRedirect),
-            }
+// once the command is done : what is left of its input goes on to the next
+// command, and the files are written (the status of a failure)
+fn close_redirects(redirects: &[Redirect], fs: &mut Fs, cur: &Fdesc, io: &mut Io, mut cmd_io: Io, files: Vec<Output>) -> Option<u8> {
+    if !redirects.iter().any(|redirect| redirect.fd == 0) {io.stdin = std::mem::take(&mut cmd_io.stdin)};
+    let mut status = None;
+    for (name, append, out) in files {
+        let mut data = out.take();
+        if data.last() == Some(&'\n') {data.pop();}
+        let err = if append {append_file(fs, cur, &name, &data)} else {write_file(fs, cur, &name, &data)};
+        if let Some(err) = err {status = Some(fs_status(&err)); io.stderr.write(&fs_error(err))};
+    }
+    status
+}
+
+impl Exec for Pipeline {
+    // the output of a stage is kept in memory as the input of the next one,
+    // the status is the one of the last stage
+    fn eval(&self, fs: &mut Fs, cur: &Fdesc, env: &mut Env, io: &mut Io) -> EvalResult {
+        let last = self.cmds.len() - 1;
+        let mut pipe = std::mem::take(&mut io.stdin);
+        for cmd in &self.cmds[..last] {
+            let out = Out::buf();
+            let mut stage_io = Io {stdin: pipe, stdout: out.clone(), stderr: io.stderr.clone()};
+            cmd.eval(fs, cur, env, &mut stage_io);
+            pipe = out.take();
         }
 
-        Ok(Piped {
-            cmd     : simple_cmd.unwrap(),
-            output  : outpt,
-            input   : inpt
-        })
+        let mut stage_io = Io {stdin: pipe, stdout: io.stdout.clone(), stderr: io.stderr.clone()};
+        let res = self.cmds[last].eval(fs, cur, env, &mut stage_io);
+        if last == 0 {io.stdin = stage_io.stdin};
+        env.status = res.status;
+        res
     }
+}
 
-    fn eval(&mut self, fs: &mut Fs, cur: &Fdesc) -> Result<EvalResult, FsErr> {
-        let empty_res = Ok( EvalResult {
-            stdout: None,
+impl Exec for AndOr {
+    fn eval(&self, fs: &mut Fs, cur: &Fdesc, env: &mut Env, io: &mut Io) -> EvalResult {
+        let mut res = self.first.eval(fs, cur, env, io);
+        for (connector, pipeline) in &self.rest {
+            if !res.goes_on() {break};
+            if (res.status == 0) != (*connector == Connector::And) {continue};
+            let new = pipeline.eval(fs, res.fdesc.as_ref().unwrap_or(cur), env, io);
+            res.then(new);
+        }
+        res
+    }
+}
+
+impl Exec for List {
+    // the items run one after the other
+    fn eval(&self, fs: &mut Fs, cur: &Fdesc, env: &mut Env, io: &mut Io) -> EvalResult {
+        let mut res = EvalResult {
             fdesc: None,
             exit: false,
-        });
-
-        let res = if let Some(input) = self.input.clone() {
-            self.cmd.add_args(input);
-            self.cmd.eval(fs,cur)?
-        }
-        else {self.cmd.eval(fs,cur)?};
-
-        if let Some(output) = &self.output {
-            let output_name = output.iter().collect::<String>();
-            let output_name = output_name.trim();
-            let mut stdout = if let Some(stdout) = res.stdout {stdout} else {vec![]};
-            stdout.pop();
-            match fs.write(cur, &output_name, &stdout) {
-                Some(FsErr::FileNotFound) => {
-                    if let Some(err) = fs.touch(cur, &output_name) {return Err(err)};
-                    if let Some(err) = fs.write(cur, &output_name, &stdout) {return Err(err)};
-                    return empty_res
-                },
-                Some(err) => return Err(err),
-                None => return empty_res
-            }
+            status: env.status,
+            jump: None,
+        };
+
+        for item in &self.items {
+            let new = item.eval(fs, res.fdesc.as_ref().unwrap_or(cur), env, io);
+            res.then(new);
+            if !res.goes_on() {break};
         }
-        return Ok(res)
+        res
     }
 }
 
-type Command = Vec<Piped>;
-trait Exec {
-    fn parse(input: Format) -> Result<Self, ParsingErr> where Self: Sized;
-    fn eval(&mut self, fs: &mut Fs, cur: &Fdesc) -> Result<EvalResult, FsErr>;
+impl Exec for Command {
+    fn eval(&self, fs: &mut Fs, cur: &Fdesc, env: &mut Env, io: &mut Io) -> EvalResult {
+        match self {
+            Command::Simple(simple) => simple.eval(fs, cur, env, io),
+            Command::If(clause) => clause.eval(fs, cur, env, io),
+            Command::Loop(clause) => clause.eval(fs, cur, env, io),
+            Command::For(clause) => clause.eval(fs, cur, env, io),
+            Command::Case(clause) => clause.eval(fs, cur, env, io),
+            Command::Group(list) => list.eval(fs, cur, env, io),
+            Command::Function(name, body) => {
+                env.funcs.insert(name.iter().collect(), body.clone());
+                EvalResult::new(0)
+            },
+            Command::Redirected(cmd, redirects) => {
+                let (mut cmd_io, files, status) = open_redirects(redirects, fs, cur, env, io);
+                let mut res = if status == 0 {cmd.eval(fs, cur, env, &mut cmd_io)} else {EvalResult::new(status)};
+                if let Some(status) = close_redirects(redirects, fs, cur, io, cmd_io, files) {res.status = status};
+                res
+            },
+        }
+    }
 }
 
-impl Exec for Command {
-    fn parse(input: Format) -> Result<Self, ParsingErr> {
-        let mut end = input.len();
-        while end > 0 {
-            end -= 1;
-            match input[end] {
-                '|' => {
-                    let mut prev = Self::parse(input[..end].to_vec())?;
-                    prev.push(Piped::parse(input[end+1..].to_vec())?);
-                    return Ok(prev);
-                }
-                _ => continue,
+impl Exec for If {
+    // without a branch run, the status is 0
+    fn eval(&self, fs: &mut Fs, cur: &Fdesc, env: &mut Env, io: &mut Io) -> EvalResult {
+        let mut res = EvalResult::new(0);
+        for (cond, body) in &self.branches {
+            let new = cond.eval(fs, res.fdesc.as_ref().unwrap_or(cur), env, io);
+            res.then(new);
+            if !res.goes_on() {return res}
+            if res.status == 0 {
+                let new = body.eval(fs, res.fdesc.as_ref().unwrap_or(cur), env, io);
+                res.then(new);
+                return res
             }
         }
-        return Ok(vec![Piped::parse(input)?]);
+        res.status = 0;
+        if let Some(body) = &self.otherwise {
+            let new = body.eval(fs, res.fdesc.as_ref().unwrap_or(cur), env, io);
+            res.then(new);
+        }
+        res
     }
+}
 
-    fn eval(&mut self, fs: &mut Fs, cur: &Fdesc) -> Result<EvalResult, FsErr> {
-        if self.len() == 1 {return Ok(self[0].eval(fs,cur)?)};
+impl Exec for Loop {
+    // the status is the one of the last turn, 0 without any
+    fn eval(&self, fs: &mut Fs, cur: &Fdesc, env: &mut Env, io: &mut Io) -> EvalResult {
+        let mut res = EvalResult::new(0);
+        loop {
+            let status = res.status;
+            let new = self.cond.eval(fs, res.fdesc.as_ref().unwrap_or(cur), env, io);
+            res.then(new);
+            if !res.next_turn() {break}
+            if (res.status == 0) == self.until {
+                res.status = status;
+                break
+            }
+            let new = self.body.eval(fs, res.fdesc.as_ref().unwrap_or(cur), env, io);
+            res.then(new);
+            if !res.next_turn() {break}
+        }
+        res
+    }
+}
 
-        let mut last = self.pop().unwrap();
-        match self.eval(fs,cur)?.stdout {
-            None => Ok(last.eval(fs,cur)?),
-            Some(mut stdout) => {
-                stdout.pop();
-                if let Some(err) = fs.touch(cur,"__tmp") {return Err(err)};
-                if let Some(err) = fs.write(cur,"__tmp",&stdout) {return Err(err)};
-                last.cmd.add_args(vec!['_','_','t','m','p']);
-                let res = Ok(last.eval(fs,cur)?);
-                if let Some(err) = fs.rm(cur, "__tmp") {return Err(err)};
-                res
+impl Exec for For {
+    fn eval(&self, fs: &mut Fs, cur: &Fdesc, env: &mut Env, io: &mut Io) -> EvalResult {
+        let mut res = EvalResult::new(0);
+        let words = match &self.words {
+            Some(words) => match env.words(words, fs, cur, &io.stderr) {
+                Ok(words) => words,
+                Err(err) => return EvalResult::new(report(err, &io.stderr)),
+            },
+            None => env.args[1..].to_vec(),
+        };
+        let var = self.var.iter().collect::<String>();
+        for word in words {
+            env.set(&var, word);
+            let new = self.body.eval(fs, res.fdesc.as_ref().unwrap_or(cur), env, io);
+            res.then(new);
+            if !res.next_turn() {break}
+        }
+        res
+    }
+}
+
+impl Exec for Case {
+    // without a matching pattern, the status is 0
+    fn eval(&self, fs: &mut Fs, cur: &Fdesc, env: &mut Env, io: &mut Io) -> EvalResult {
+        let word = match env.expand(&self.word, fs, cur, &io.stderr) {
+            Ok(word) => word,
+            Err(err) => return EvalResult::new(report(SyntaxErr {err, col: self.word.col}, &io.stderr)),
+        };
+        for (patterns, body) in &self.items {
+            for pattern in patterns {
+                match env.pattern(pattern, fs, cur, &io.stderr) {
+                    Ok(pattern) if glob::matches(&pattern, &word) => return body.eval(fs, cur, env, io),
+                    Ok(_) => (),
+                    Err(err) => return EvalResult::new(report(SyntaxErr {err, col: pattern.col}, &io.stderr)),
+                }
             }
         }
+        EvalResult::new(0)
     }
 }
 
+// writes a whole file, created if needed
+fn write_file(fs: &mut Fs, cur: &Fdesc, name: &str, data: &Format) -> Option<FsErr> {
+    match fs.write(cur, name, data) {
+        Some(FsErr::FileNotFound) => {
+            if let Some(err) = fs.touch(cur, name) {return Some(err)};
+            fs.write(cur, name, data)
+        },
+        err => err,
+    }
+}
+
+// adds a line at the end of a file, created if needed
+fn append_file(fs: &mut Fs, cur: &Fdesc, name: &str, data: &Format) -> Option<FsErr> {
+    let content = match fs.cat(cur, name) {
+        Ok(content) => content,
+        Err(FsErr::FileNotFound) => return write_file(fs, cur, name, data),
+        Err(err) => return Some(err),
+    };
+    // cat ends the content with a newline, which is written in front of the data
+    if content.len() == 1 {return fs.write_at(cur, name, 0, data)};
+    let mut line = vec!['\n'];
+    line.extend_from_slice(data);
+    fs.write_at(cur, name, content.len() - 1, &line)
+}
+
+// a way out of the loops (their number) or of a function
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Jump {
+    Break(usize),
+    Continue(usize),
+    Return,
+}
+
 struct EvalResult {
     fdesc: Option<Fdesc>,
-    stdout: Option<Format>,
-    exit: bool
+    exit: bool,
+    status: u8,
+    jump: Option<Jump>,     // the commands up to its target do not run
+}
+
+impl EvalResult {
+    fn new(status : u8) -> Self {
+        EvalResult {fdesc: None, exit: false, status, jump: None}
+    }
+
+    // the result of a command run after this one
+    fn then(&mut self, next : EvalResult) {
+        if next.fdesc.is_some() {self.fdesc = next.fdesc};
+        self.exit = next.exit;
+        self.status = next.status;
+        self.jump = next.jump;
+    }
+
+    // whether the commands after this one run
+    fn goes_on(&self) -> bool {
+        !self.exit && self.jump.is_none()
+    }
+
+    // after a turn of a loop : whether the loop goes on, a jump further
+    // out is left for the enclosing loops
+    fn next_turn(&mut self) -> bool {
+        if self.exit {return false}
+        match self.jump.take() {
+            None | Some(Jump::Continue(1)) => true,
+            Some(Jump::Break(1)) => false,
+            Some(Jump::Break(n)) => {self.jump = Some(Jump::Break(n - 1)); false},
+            Some(Jump::Continue(n)) => {self.jump = Some(Jump::Continue(n - 1)); false},
+            Some(Jump::Return) => {self.jump = Some(Jump::Return); false},
+        }
+    }
 }
 
-fn print(data : Format){
+fn print(data : &Format){
     for k in 0..data.len(){
         print!("{}",data[k]);
     }
@@ -547,13 +1673,18 @@ fn fmt_from(string : &str) -> Format {
     while let Some(c) = buff.next() {
         fmt.push(c);
     }
-    if fmt[fmt.len()-1] == '\n' {fmt.pop();}
+    if fmt.last() == Some(&'\n') {fmt.pop();}
     return fmt
 }
 
-// ultra basic for the moment
-fn fs_handler(err : FsErr) {
+// the message of an error, as written on the error output
+fn fs_error(err : FsErr) -> Format {
     let msg = match err {
+        FsErr::HdErr(HdErr::Io(err)) => return format!("Error : hard drive I/O : {err}\n").chars().collect(),
+        FsErr::HdErr(HdErr::InvalidImage) => "invalid disk image",
+        FsErr::HdErr(HdErr::NoOverlay) => "the hard drive has no overlay (start with --overlay)",
+        FsErr::HdErr(HdErr::NoResize) => "the hard drive cannot be resized",
+        FsErr::HdErr(HdErr::ReadOnly) => "the hard drive is read-only",
         FsErr::HdErr(_)     => "error due to hard drive",
         FsErr::InvalidName  => "command has invalids characters",
         FsErr::FileNotFound => "file not found",
@@ -568,74 +1699,317 @@ fn fs_handler(err : FsErr) {
         FsErr::UndefBlk     => "block is undefined",
         FsErr::RemoveDir    => "cannot remove a directory",
         FsErr::InvalidCur   => "the current directory has been removed",
-        FsErr::MvCurOrPrev  => "cannot move '.' or '..' directory"
+        FsErr::MvCurOrPrev  => "cannot move '.' or '..' directory",
+        FsErr::ReadOnly     => "snapshots are read-only",
+        FsErr::SnapExist    => "the snapshot already exist",
+        FsErr::SnapNotFound => "snapshot not found",
+        FsErr::SnapFull     => "no room for another snapshot",
+        FsErr::Corrupted    => "the data of the file is corrupted",
+        FsErr::Locked       => "the file is encrypted : unlock it first",
+        FsErr::BadPassphrase => "wrong passphrase",
+        FsErr::NotEncrypted => "this is not an encrypted directory",
+        FsErr::Encrypted    => "the directory is already encrypted",
+        FsErr::DirNotEmpty  => "the directory is not empty",
+        FsErr::FileTooBig   => "the file would be too big",
+        FsErr::NoXattr      => "no such attribute",
+        FsErr::XattrFull    => "no room left for the attributes of the file",
+        FsErr::QuotaExceeded => "the quota of the directory tree is exceeded",
+        FsErr::QuotaFull    => "no room for another quota",
+        FsErr::InvalidSize  => "the file system cannot have this size",
+        FsErr::ShrinkMounted => "a mounted file system can only grow (shrink it with --resize)",
+        FsErr::NoTrash      => "there is no trash (see trash on)",
+        FsErr::TrashFull    => "the trash is full : empty it first",
+        FsErr::NoOrigin     => "the original path is unknown : give a destination",
+        FsErr::NoFileSystem => "the hard drive does not hold a valid file system",
+        FsErr::NoncesExhausted => "no nonce left for the encrypted trees",
+        FsErr::GrowLimit    => "the file system cannot grow beyond 4096 data blocks (its bitmaps and reference counts take one block)",
     };
-    println!("Error : {msg}");
+    format!("Error : {msg}\n").chars().collect()
 }
 
-fn parsing_handler(err : ParsingErr) {
-    let msg = match err {
+// the exit status of a command failing with err
+fn fs_status(err : &FsErr) -> u8 {
+    match err {
+        FsErr::HdErr(_)      => 1,
+        FsErr::InvalidName   => 2,
+        FsErr::FileNotFound  => 3,
+        FsErr::NoDirectory   => 4,
+        FsErr::Occuped       => 5,
+        FsErr::ReadDir       => 6,
+        FsErr::WriteDir      => 7,
+        FsErr::FileExist     => 8,
+        FsErr::RemoveDir     => 9,
+        FsErr::DirFull       => 10,
+        FsErr::ImapFull      => 11,
+        FsErr::DmapFull      => 12,
+        FsErr::UndefBlk      => 13,
+        FsErr::InvalidCur    => 14,
+        FsErr::MvCurOrPrev   => 15,
+        FsErr::ReadOnly      => 16,
+        FsErr::SnapExist     => 17,
+        FsErr::SnapNotFound  => 18,
+        FsErr::SnapFull      => 19,
+        FsErr::Corrupted     => 20,
+        FsErr::Locked        => 21,
+        FsErr::BadPassphrase => 22,
+        FsErr::NotEncrypted  => 23,
+        FsErr::Encrypted     => 24,
+        FsErr::DirNotEmpty   => 25,
+        FsErr::FileTooBig    => 26,
+        FsErr::NoXattr       => 27,
+        FsErr::XattrFull     => 28,
+        FsErr::QuotaExceeded => 29,
+        FsErr::QuotaFull     => 30,
+        FsErr::InvalidSize   => 31,
+        FsErr::ShrinkMounted => 32,
+        FsErr::NoTrash       => 33,
+        FsErr::TrashFull     => 34,
+        FsErr::NoOrigin      => 35,
+        FsErr::NoFileSystem  => 36,
+        FsErr::NoncesExhausted => 37,
+        FsErr::GrowLimit     => 38,
+    }
+}
+
+// the exit status of a line (or a command) rejected with err
+fn parsing_status(err : &ParsingErr) -> u8 {
+    match err {
+        ParsingErr::NotEnoughArgs       => 64,
+        ParsingErr::TooManyArgs         => 65,
+        ParsingErr::IncorrectRedirect   => 66,
+        ParsingErr::InvalidOption       => 67,
+        ParsingErr::UnterminatedQuote   => 68,
+        ParsingErr::TrailingEscape      => 69,
+        ParsingErr::UnterminatedHereDoc => 70,
+        ParsingErr::MissingCommand      => 71,
+        ParsingErr::BadFd               => 72,
+        ParsingErr::InvalidVariable     => 73,
+        ParsingErr::NoMatch             => 74,
+        ParsingErr::UnterminatedSubst   => 75,
+        ParsingErr::BadExpression       => 76,
+        ParsingErr::DivisionByZero      => 77,
+        ParsingErr::UnexpectedToken     => 78,
+        ParsingErr::IncompleteCommand   => 79,
+        ParsingErr::NotInFunction       => 80,
+        ParsingErr::UnknownCommand      => 127,
+    }
+}
+
+fn fs_handler(err : FsErr) {
+    Out::Stderr.write(&fs_error(err));
+}
+
+fn parsing_error(err : SyntaxErr) -> Format {
+    format!("Error : {} (column {})\n", parsing_msg(&err.err), err.col).chars().collect()
+}
+
+// an error of a script, at its line and column
+fn script_error(input : &Format, err : SyntaxErr) -> Format {
+    let before = &input[..err.col.saturating_sub(1).min(input.len())];
+    let line = before.iter().filter(|&&c| c == '\n').count() + 1;
+    let col = err.col - before.iter().rposition(|&c| c == '\n').map_or(0, |k| k + 1);
+    format!("Error : {} (line {line}, column {col})\n", parsing_msg(&err.err)).chars().collect()
+}
+
+fn parsing_msg(err : &ParsingErr) -> &'static str {
+    match err {
         ParsingErr::NotEnoughArgs       => "not enough args",
         ParsingErr::TooManyArgs         => "too many args",
         ParsingErr::UnknownCommand      => "unknown command",
         ParsingErr::IncorrectRedirect   => "incorrect syntax for redirect",
-        ParsingErr::MultipleInputs      => "at most one input should be specifided",
-        ParsingErr::MultipleOutputs     => "at most one output should be specifided",
-    };
-    println!("Error : {msg}");
+        ParsingErr::InvalidOption       => "invalid option",
+        ParsingErr::UnterminatedQuote   => "unterminated quote",
+        ParsingErr::TrailingEscape      => "nothing to escape at the end of the line",
+        ParsingErr::UnterminatedHereDoc => "here-document without its delimiter line",
+        ParsingErr::MissingCommand      => "missing command around the operator",
+        ParsingErr::BadFd               => "bad file descriptor",
+        ParsingErr::InvalidVariable     => "not a valid variable name",
+        ParsingErr::NoMatch             => "no match for the pattern",
+        ParsingErr::UnterminatedSubst   => "unterminated substitution",
+        ParsingErr::BadExpression       => "invalid arithmetic expression",
+        ParsingErr::DivisionByZero      => "division by zero",
+        ParsingErr::UnexpectedToken     => "unexpected word or operator",
+        ParsingErr::IncompleteCommand   => "compound command not closed",
+        ParsingErr::NotInFunction       => "local outside of a function",
+    }
 }
 
-pub fn setup() {
+// writes an error found while running a command line, returns its status
+fn report(err : SyntaxErr, stderr : &Out) -> u8 {
+    let status = parsing_status(&err.err);
+    stderr.write(&parsing_error(err));
+    status
+}
 
-    let mut hd = Hd::new();
-    if let Some(err) = Fs::mkfs(&mut hd) {fs_handler(err)};
+fn parsing_handler(err : SyntaxErr) {
+    Out::Stderr.write(&parsing_error(err));
+}
 
-    let mut fs;
-    loop {
-        match Fs::mount(&mut hd) {
-            Ok(file_system) => {fs = file_system; break},
-            Err(err) => fs_handler(err),
-        };
+// parse a size in bytes, with an optional K, M or G suffix
+fn parse_size(arg : &str) -> Option<usize> {
+    let (digits, unit) = match arg.chars().last()? {
+        'K' => (&arg[..arg.len()-1], 1 << 10),
+        'M' => (&arg[..arg.len()-1], 1 << 20),
+        'G' => (&arg[..arg.len()-1], 1 << 30),
+        _   => (arg, 1),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+// select the hard drive backend from the command line, returns it and
+// whether it is brand new (Err(None) for an incorrect command line)
+fn open_hd(args : &[String]) -> Result<(Hd, bool), Option<HdErr>> {
+    let mut image = None;
+    let mut size = fs::hd::HD_SIZE;
+    let mut overlay = false;
+    let mut k = 1;
+    while k < args.len() {
+        match (args[k].as_str(), args.get(k+1)) {
+            ("--overlay", _) => {overlay = true; k += 1; continue},
+            ("--mmap", Some(path)) | ("--sparse", Some(path)) => image = Some((args[k].as_str(), path)),
+            ("--size", Some(arg)) => size = match parse_size(arg) {
+                Some(size) if size >= fs::hd::HD_SIZE => size,
+                _ => return Err(None),
+            },
+            _ => return Err(None),
+        }
+        k += 2;
     }
-    
-    let mut cur_desc = fs.get_home_fdesc();
 
-    // Optionnal setup
-    let mut cmd = match Command::parse(fmt_from("echo hello world pattern toto bibli ! > bar")) {
-        Ok(cmd) => cmd,
-        Err(err) => {parsing_handler(err);panic!("TEST SETUP FAILED !")}
+    let (hd, fresh) = match image {
+        None => (Hd::new(), true),
+        Some((backend, path)) => {
+            // an empty image is formatted like a new one
+            let fresh = std::fs::metadata(path).map_or(true, |meta| meta.len() == 0);
+            // the base of an overlay is only opened for writing by a commit
+            let hd = if backend == "--mmap" {Hd::mmap(path, size, !overlay)} else {Hd::sparse(path, size, !overlay)};
+            (hd.map_err(Some)?, fresh)
+        }
     };
-    if let Err(err) = cmd.eval(&mut fs, &cur_desc) {fs_handler(err);panic!("TEST SETUP FAILED !")};
-    let mut cmd = match Command::parse(fmt_from("mkdir foo")) {
-        Ok(cmd) => cmd,
-        Err(err) => {parsing_handler(err);panic!("TEST SETUP FAILED !")}
+    if overlay {return Ok((Hd::with_overlay(hd), fresh))}
+    Ok((hd, fresh))
+}
+
+// usage : fs [--mmap <image> | --sparse <image>] [--size <n>[K|M|G]] [--overlay] [--encrypt <passphrase>] [--resize <n>[K|M|G]] [<script> [<args>]]
+// returns the exit status of the shell
+pub fn setup() -> u8 {
+
+    let mut args : Vec<String> = std::env::args().collect();
+    let usage = format!("usage : {} [--mmap <image> | --sparse <image>] [--size <n>[K|M|G]] [--overlay] [--encrypt <passphrase>] [--resize <n>[K|M|G]] [<script> [<args>]]", args[0]);
+    // the first argument which is not an option is a script (on the host),
+    // the next ones are its positional parameters
+    let mut k = 1;
+    while k < args.len() && args[k].starts_with("--") {
+        k += if args[k] == "--overlay" {1} else {2};
+    }
+    let script = if k < args.len() {Some(args.split_off(k))} else {None};
+    // encrypt a brand new volume, unlock an existing one
+    let mut pass = None;
+    if let Some(k) = args.iter().position(|arg| arg == "--encrypt") {
+        if k + 1 >= args.len() {eprintln!("{usage}"); return 1}
+        pass = Some(args.remove(k + 1));
+        args.remove(k);
+    }
+    // the file system is resized before being mounted (the only way to shrink it)
+    let mut resize = None;
+    if let Some(k) = args.iter().position(|arg| arg == "--resize") {
+        match args.get(k + 1).and_then(|arg| parse_size(arg)) {
+            Some(size) => resize = Some(size),
+            None => {eprintln!("{usage}"); return 1},
+        }
+        args.drain(k..k+2);
+    }
+    let (mut hd, fresh) = match open_hd(&args) {
+        Ok(res) => res,
+        Err(Some(err)) => {fs_handler(FsErr::HdErr(err)); return 1},
+        Err(None) => {eprintln!("{usage}"); return 1},
     };
-    if let Err(err) = cmd.eval(&mut fs, &cur_desc) {fs_handler(err);panic!("TEST SETUP FAILED !")};
-    println!("SUCCESSFULL SETUP");
-    //
+    if fresh {
+        if let Some(err) = Fs::mkfs(&mut hd) {fs_handler(err)};
+    }
+    if let Some(size) = resize {
+        if let Some(err) = Fs::resize_offline(&mut hd, size) {fs_handler(err)};
+    }
+
+    // an image which is not formatted is refused, not overwritten
+    let mut fs = match Fs::mount(&mut hd) {
+        Ok(fs) => fs,
+        Err(err) => {fs_handler(err); return 1},
+    };
+    
+    let cur_desc = fs.get_home_fdesc();
+    if let Some(pass) = pass {
+        let err = if fresh {fs.encrypt(&cur_desc, "/", &pass)} else {fs.unlock(&cur_desc, "/", &pass)};
+        if let Some(err) = err {fs_handler(err)};
+    }
 
+    // every way out of the shell syncs the image, an exit included
+    let status = run(&mut fs, cur_desc, script);
+    match fs.sync() {
+        Some(err) => {let status = fs_status(&err); fs_handler(err); status},
+        None => status,
+    }
+}
+
+// runs a script or the lines of the terminal, returns the exit status
+fn run(fs : &mut Fs, mut cur_desc : Fdesc, script : Option<Vec<String>>) -> u8 {
+    let mut env = Env::new();
+    // a script runs as a whole, then the shell ends
+    if let Some(script) = script {
+        let input : Format = match std::fs::read_to_string(&script[0]) {
+            Ok(input) => input.chars().collect(),
+            Err(err) => {eprintln!("Error : {} : {err}", script[0]); return 1},
+        };
+        env.args = script.iter().map(|arg| arg.chars().collect()).collect();
+        let list = match parser::parse(&input) {
+            Ok(list) => list,
+            Err(errs) => {
+                let status = parsing_status(&errs[0].err);
+                errs.into_iter().for_each(|err| Out::Stderr.write(&script_error(&input, err)));
+                return status
+            },
+        };
+        fs.mark_cmd();
+        let result = list.eval(fs, &cur_desc, &mut env, &mut Io::term());
+        return result.status
+    }
+
+    let mut input = String::new();
     loop {
-        print!("> ");
+        // a here-document, a compound command or a line ending with a
+        // backslash goes on over the next lines
+        print!("{}", if input.is_empty() {"> "} else {"... "});
         io::stdout().flush().unwrap();
-        let mut input = String::new();
+        let mut line = String::new();
 
-        io::stdin()
-            .read_line(&mut input)
+        let len = io::stdin()
+            .read_line(&mut line)
             .expect("Error : failed to read line");
+        input.push_str(&line);
+
+        // the end of the input is an exit
+        if len == 0 {
+            if let Err(errs) = parser::parse(&fmt_from(input.as_str())) {errs.into_iter().for_each(parsing_handler)};
+            return env.status
+        }
         
-        let mut cmd = match Command::parse(fmt_from(input.as_str())) {
-            Ok(cmd) => cmd,
-            Err(err) => {parsing_handler(err); continue}
+        let list = match parser::parse(&fmt_from(input.as_str())) {
+            Ok(list) => list,
+            Err(errs) if errs.iter().all(|err| matches!(err.err, ParsingErr::UnterminatedHereDoc | ParsingErr::IncompleteCommand | ParsingErr::TrailingEscape)) => continue,
+            Err(errs) => {
+                input.clear();
+                env.status = parsing_status(&errs[0].err);
+                errs.into_iter().for_each(parsing_handler);
+                continue
+            }
         };
+        input.clear();
 
-        let result = match cmd.eval(&mut fs, &cur_desc) {
-            Err(err) => {fs_handler(err); continue},
-            Ok(res) => res
-        };
+        fs.mark_cmd();
+        let result = list.eval(fs, &cur_desc, &mut env, &mut Io::term());
 
-        if result.exit {break};
+        if result.exit {return result.status};
 
         if let Some(fdesc) = result.fdesc {cur_desc = fdesc};
-        if let Some(fmt) = result.stdout {print(fmt)};
     }
 }
@@ -0,0 +1,173 @@
+/*
+    shell/arith.rs
+*/
+
+use super::Format;
+use super::ParsingErr;
+
+// Evaluates the integer expression of a $((...)), once its expansions are
+// done. A name is the value of the variable (0 if it is empty).
+//   expr := or
+//   or   := and ('||' and)*
+//   and  := eq ('&&' eq)*
+//   eq   := cmp (('==' | '!=') cmp)*
+//   cmp  := sum (('<' | '<=' | '>' | '>=') sum)*
+//   sum  := prod (('+' | '-') prod)*
+//   prod := unary (('*' | '/' | '%') unary)*
+//   unary := ('+' | '-' | '!') unary | number | name | '(' expr ')'
+// The comparisons give 1 or 0, the operations wrap around.
+
+struct Arith<'a> {
+    expr : &'a Format,
+    pos  : usize,
+    var  : &'a dyn Fn(&str) -> Format,
+}
+
+pub fn eval(expr : &Format, var : &dyn Fn(&str) -> Format) -> Result<i64, ParsingErr> {
+    let mut arith = Arith {expr, pos: 0, var};
+    let res = arith.binary(0)?;
+    arith.blanks();
+    if arith.pos < arith.expr.len() {return Err(ParsingErr::BadExpression)}
+    Ok(res)
+}
+
+// the binary operators, from the lowest precedence
+const LEVELS : [&[&str]; 6] = [
+    &["||"],
+    &["&&"],
+    &["==", "!="],
+    &["<=", ">=", "<", ">"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+impl Arith<'_> {
+    fn blanks(&mut self) {
+        while self.expr.get(self.pos).is_some_and(|c| c.is_whitespace()) {self.pos += 1}
+    }
+
+    // reads op if it is next
+    fn eat(&mut self, op : &str) -> bool {
+        self.blanks();
+        let len = op.chars().count();
+        if self.pos + len > self.expr.len() || !self.expr[self.pos..self.pos + len].iter().copied().eq(op.chars()) {return false}
+        self.pos += len;
+        true
+    }
+
+    fn binary(&mut self, level : usize) -> Result<i64, ParsingErr> {
+        if level == LEVELS.len() {return self.unary()}
+        let mut res = self.binary(level + 1)?;
+        'ops: loop {
+            for op in LEVELS[level] {
+                if !self.eat(op) {continue}
+                let rhs = self.binary(level + 1)?;
+                res = match *op {
+                    "||" => (res != 0 || rhs != 0) as i64,
+                    "&&" => (res != 0 && rhs != 0) as i64,
+                    "==" => (res == rhs) as i64,
+                    "!=" => (res != rhs) as i64,
+                    "<=" => (res <= rhs) as i64,
+                    ">=" => (res >= rhs) as i64,
+                    "<" => (res < rhs) as i64,
+                    ">" => (res > rhs) as i64,
+                    "+" => res.wrapping_add(rhs),
+                    "-" => res.wrapping_sub(rhs),
+                    "*" => res.wrapping_mul(rhs),
+                    _ if rhs == 0 => return Err(ParsingErr::DivisionByZero),
+                    "/" => res.wrapping_div(rhs),
+                    _ => res.wrapping_rem(rhs),
+                };
+                continue 'ops;
+            }
+            return Ok(res)
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64, ParsingErr> {
+        if self.eat("+") {return self.unary()}
+        if self.eat("-") {return Ok(self.unary()?.wrapping_neg())}
+        if self.eat("!") {return Ok((self.unary()? == 0) as i64)}
+        if self.eat("(") {
+            let res = self.binary(0)?;
+            if !self.eat(")") {return Err(ParsingErr::BadExpression)}
+            return Ok(res)
+        }
+        let start = self.pos;
+        while self.expr.get(self.pos).is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_') {self.pos += 1}
+        let word : String = self.expr[start..self.pos].iter().collect();
+        match word.chars().next() {
+            None => Err(ParsingErr::BadExpression),
+            Some(c) if c.is_ascii_digit() => word.parse::<i64>().map_err(|_| ParsingErr::BadExpression),
+            Some(_) => {
+                let value : String = (self.var)(&word).iter().collect();
+                let value = value.trim();
+                if value.is_empty() {return Ok(0)}
+                value.parse::<i64>().map_err(|_| ParsingErr::BadExpression)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(name : &str) -> Format {
+        match name {
+            "x" => "6".chars().collect(),
+            "y" => " -2 ".chars().collect(),
+            "s" => "abc".chars().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn calc(expr : &str) -> Result<i64, ParsingErr> {
+        eval(&expr.chars().collect(), &var)
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(calc("1 + 2 * 3").unwrap(), 7);
+        assert_eq!(calc("(1 + 2) * 3").unwrap(), 9);
+        assert_eq!(calc("10 - 4 - 3").unwrap(), 3);
+        assert_eq!(calc("17 / 5 % 2").unwrap(), 1);
+        assert_eq!(calc("1 + 2 == 3 && 2 < 1 || 4 >= 4").unwrap(), 1);
+        assert_eq!(calc("1 < 2 == 1").unwrap(), 1);
+    }
+
+    #[test]
+    fn unary() {
+        assert_eq!(calc("-3 + +5").unwrap(), 2);
+        assert_eq!(calc("--3").unwrap(), 3);
+        assert_eq!(calc("!0 + !7").unwrap(), 1);
+        assert_eq!(calc("-(2 * 3)").unwrap(), -6);
+    }
+
+    #[test]
+    fn comparisons() {
+        assert_eq!(calc("3 <= 3").unwrap(), 1);
+        assert_eq!(calc("3 > 3").unwrap(), 0);
+        assert_eq!(calc("3 != 4").unwrap(), 1);
+        assert_eq!(calc("0 && 1").unwrap(), 0);
+        assert_eq!(calc("0 || 5").unwrap(), 1);
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(calc("x * y").unwrap(), -12);
+        assert_eq!(calc("unset + 1").unwrap(), 1);
+        assert!(matches!(calc("s + 1"), Err(ParsingErr::BadExpression)));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(calc(""), Err(ParsingErr::BadExpression)));
+        assert!(matches!(calc("1 +"), Err(ParsingErr::BadExpression)));
+        assert!(matches!(calc("(1"), Err(ParsingErr::BadExpression)));
+        assert!(matches!(calc("1 2"), Err(ParsingErr::BadExpression)));
+        assert!(matches!(calc("99999999999999999999"), Err(ParsingErr::BadExpression)));
+        assert!(matches!(calc("1 / 0"), Err(ParsingErr::DivisionByZero)));
+        assert!(matches!(calc("1 % (x - 6)"), Err(ParsingErr::DivisionByZero)));
+    }
+}
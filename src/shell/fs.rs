@@ -3,9 +3,13 @@
 */
 
 pub mod hd;
+mod crypt;
+mod lz;
+use crypt::Key;
 use hd::Hd;
 use hd::HdErr;
 use hd::Sector;
+use std::collections::BTreeMap;
 
 const BLK_SIZE      : usize = 0x1000;           // 4 kB blocks
 const SECT_SIZE     : usize = hd::SECT_SIZE ;   // 512 bytes sectors
@@ -21,6 +25,34 @@ const FDESC_PER_BLK : usize = BLK_SIZE/FDESC_SIZE; // = 64
 const DIRECT_BLK    : usize = 14;
 const MAX_NAME_LEN  : usize = 32;
 
+const SNAP_SIZE     : usize = 0x80;                     // 128 bytes snapshot entry
+const SNAP_PER_BLK  : usize = BLK_SIZE/SNAP_SIZE;       // = 32
+const SNAP_IBLK     : usize = 8;                        // max inode blocks of a snapshot
+const SNAP_IMAP     : usize = SNAP_IBLK*INODE_PER_BLK/8;// = 16 bytes of imap
+
+const QUOTA_SIZE    : usize = 0x10;                     // 16 bytes quota entry
+const QUOTA_PER_BLK : usize = BLK_SIZE/QUOTA_SIZE;      // = 256
+
+// inode ids seen through a snapshot are tagged : | snapshot nb + 1 (8 bits) | iid (24 bits) |
+const SNAP_SHIFT    : u32 = 24;
+const IID_MASK      : u32 = (1<<SNAP_SHIFT) - 1;
+const SNAPS_IID     : u32 = 0xFF<<SNAP_SHIFT;           // the virtual /.snapshots directory
+const SNAPS_NAME    : &str = ".snapshots";
+const TRASH_NAME    : &str = ".trash";                  // in the root directory
+
+const FLAG_COMPR    : u8 = 0b0000_0001;                 // data blocks hold the LZ compressed content
+const FLAG_CRYPT    : u8 = 0b0000_0010;                 // data blocks (and names of a directory) are encrypted
+const FLAG_INLINE   : u8 = 0b0000_0100;                 // no data block, the content is kept by the inode
+
+const INLINE_OFS    : usize = 64;
+const INLINE_MAX    : usize = INODE_SIZE - 4*(DIRECT_BLK + 1) - INLINE_OFS; // = 132 bytes
+const MAX_FILE_BLK  : usize = DIRECT_BLK + BLK_SIZE/4;  // = 1038 blocks
+
+const SUPER_MAGIC   : u32 = 0x4B46_5331;                // "KFS1", identifies a formatted hard drive
+const NONCE_DATA    : u32 = 0;                          // key stream domains
+const NONCE_NAMES   : u32 = 1;
+const NAME_CHARS    : &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789_.";
+
 const EMPTY_FILE : Fdesc = Fdesc { 
     name_len : 0,
     name     : ['\0'; MAX_NAME_LEN],
@@ -36,11 +68,27 @@ const EMPTY_SUPER: Super = Super {
     datas    : 0,
     imap     : 0,
     dmap     : 0,
+    rcnt     : 0,
+    snaps    : 0,
+    quota    : 0,
+    defrag   : 0,
+    trash    : 0,
+    nonces   : 0,
 
     root : Inode {
         id      : 0,
         ftype   : FType::Reg,
         size    : 0,
+        flags   : 0,
+        qid     : 0,
+        len     : 0,
+        clen    : 0,
+        kid     : 0,
+        gen     : 0,
+        salt    : [0; crypt::SALT_LEN],
+        check   : [0; crypt::CHECK_LEN],
+        xattr   : 0,
+        inline  : [0; INLINE_MAX],
         data_ptr : [0;DIRECT_BLK + 1],
     },
 };
@@ -86,6 +134,23 @@ fn is_alpha_num(c : char) -> bool {
     || (c >= '0' && c <= '9')
 }
 #[inline(always)]
+fn is_snap(iid : u32) -> bool {
+    (iid>>SNAP_SHIFT) != 0
+}
+#[inline(always)]
+fn nonce(iid : u32, gen : u32, domain : u32) -> [u8; crypt::NONCE_LEN] {
+    let mut nonce = [0; crypt::NONCE_LEN];
+    fill(&mut nonce, iid & IID_MASK, 0);
+    fill(&mut nonce, gen, 4);
+    fill(&mut nonce, domain, 8);
+    nonce
+}
+#[inline(always)]
+fn is_xattr_name(name : &str) -> bool {
+    !name.is_empty() && name.len() <= u8::MAX as usize
+    && name.chars().all(|c| is_alpha_num(c) || c == '.' || c == '_' || c == '-')
+}
+#[inline(always)]
 fn ceil(a : usize, b : usize) -> usize{
     if a%b == 0 {a/b}
     else {a/b + 1}
@@ -162,6 +227,28 @@ impl Bitmap {
         let byte = self.bmap[idx/8];
         return select_bit(byte,idx%8);
     }
+
+    // number of unfree entries among the first n ones
+    fn count_used(&self, n : usize) -> usize {
+        (0..n).filter(|&idx| select_bit(self.bmap[idx/8], idx%8)).count()
+    }
+}
+
+// number of references to each data block (aka how many inode tables,
+// the live one & the snapshots ones, point to it), 0 for a free block
+struct Refcounts {
+    cnt : [u8;BLK_SIZE],
+}
+
+impl Refcounts {
+    // without a reference counts block on disk, each used block has 1 reference
+    fn from_dmap(dmap : &Bitmap) -> Self {
+        let mut refs = Refcounts {cnt : [0;BLK_SIZE]};
+        for idx in 0..BLK_SIZE {
+            if dmap.is_free(idx) {refs.cnt[idx] = 1}
+        }
+        refs
+    }
 }
 
 #[derive(Debug)] // TODO : remove it
@@ -181,6 +268,29 @@ pub enum FsErr {
     UndefBlk,
     InvalidCur,
     MvCurOrPrev,
+    ReadOnly,
+    SnapExist,
+    SnapNotFound,
+    SnapFull,
+    Corrupted,
+    Locked,
+    BadPassphrase,
+    NotEncrypted,
+    Encrypted,
+    DirNotEmpty,
+    FileTooBig,
+    NoXattr,
+    XattrFull,
+    QuotaExceeded,
+    QuotaFull,
+    InvalidSize,
+    ShrinkMounted,
+    NoTrash,
+    TrashFull,
+    NoOrigin,
+    NoFileSystem,
+    NoncesExhausted,
+    GrowLimit,
 }
 
 #[derive(Debug)] // TODO : remove it
@@ -195,6 +305,16 @@ struct Inode {
     id      : u32,
     ftype   : FType,
     size    : usize, // size of corresponding file (in blocks)
+    flags   : u8,
+    qid     : u16,   // quota of the tree of the inode (0 if none)
+    len     : usize, // logical size of the file (in bytes)
+    clen    : usize, // compressed size of the file (in bytes, only with FLAG_COMPR)
+    kid     : u32,   // inode holding the key of an encrypted tree (only with FLAG_CRYPT)
+    gen     : u32,   // nonce counter of the last write of an encrypted inode (see next_gen)
+    salt    : [u8; crypt::SALT_LEN],  // of the key (only for the inode kid)
+    check   : [u8; crypt::CHECK_LEN], //
+    xattr   : u32,                    // block of the extended attributes (0 if none)
+    inline  : [u8; INLINE_MAX],       // content of a tiny file (only with FLAG_INLINE)
     data_ptr : [u32;DIRECT_BLK + 1], // 1 indirection (in blocks)
 }
 
@@ -211,6 +331,17 @@ impl Inode {
             FType::Dir => 0b0000_1111,
             FType::Undef => 0b0000_0000,
         };
+        hc[9] = self.flags;
+        hc[10] = (self.qid >> 8) as u8;
+        hc[11] = self.qid as u8;
+        fill(&mut hc, self.len as u32, 12);
+        fill(&mut hc, self.clen as u32, 16);
+        fill(&mut hc, self.kid, 20);
+        fill(&mut hc, self.gen, 24);
+        hc[28..28+crypt::SALT_LEN].copy_from_slice(&self.salt);
+        hc[44..44+crypt::CHECK_LEN].copy_from_slice(&self.check);
+        fill(&mut hc, self.xattr, 60);
+        hc[INLINE_OFS..INLINE_OFS+INLINE_MAX].copy_from_slice(&self.inline);
 
         for k in 0..(DIRECT_BLK + 1){
             let ofs = INODE_SIZE - 4*(DIRECT_BLK + 1 - k);
@@ -228,6 +359,16 @@ impl Inode {
                0b0000_1111 => FType::Dir,
                _ => FType::Undef,
             },
+            flags : hc[9],
+            qid : ((hc[10] as u16) << 8) | hc[11] as u16,
+            len : fetch(&hc, 12) as usize,
+            clen : fetch(&hc, 16) as usize,
+            kid : fetch(&hc, 20),
+            gen : fetch(&hc, 24),
+            salt : {let mut salt = [0; crypt::SALT_LEN]; salt.copy_from_slice(&hc[28..28+crypt::SALT_LEN]); salt},
+            check : {let mut check = [0; crypt::CHECK_LEN]; check.copy_from_slice(&hc[44..44+crypt::CHECK_LEN]); check},
+            xattr : fetch(&hc, 60),
+            inline : {let mut inline = [0; INLINE_MAX]; inline.copy_from_slice(&hc[INLINE_OFS..INLINE_OFS+INLINE_MAX]); inline},
             data_ptr : {
                 let mut data_ptr = [0;DIRECT_BLK + 1];
                 for k in 0..DIRECT_BLK + 1 {
@@ -251,6 +392,12 @@ struct Super {
     datas    : u32,   // emplacement of first data (in blocks)
     imap     : u32,   // emplacement of imap (in blocks)
     dmap     : u32,   // emplacement of dmap (in blocks)
+    rcnt     : u32,   // emplacement of the reference counts (in blocks, 0 if none)
+    snaps    : u32,   // emplacement of the snapshots table (in blocks, 0 if none)
+    quota    : u32,   // emplacement of the quotas table (in blocks, 0 if none)
+    defrag   : u32,   // 1 while a defragmentation runs (the blocks are counted again at mount)
+    trash    : u32,   // 1 when the deletions move the entries to /.trash
+    nonces   : u32,   // last nonce counter given to a write of an encrypted inode
 
     root : Inode,
 }
@@ -274,6 +421,13 @@ impl Super {
         fill(&mut hc,self.datas,INODE_SIZE+24);
         fill(&mut hc,self.imap,INODE_SIZE+28);
         fill(&mut hc,self.dmap,INODE_SIZE+32);
+        fill(&mut hc,self.rcnt,INODE_SIZE+36);
+        fill(&mut hc,self.snaps,INODE_SIZE+40);
+        fill(&mut hc,self.quota,INODE_SIZE+44);
+        fill(&mut hc,self.defrag,INODE_SIZE+48);
+        fill(&mut hc,self.trash,INODE_SIZE+52);
+        fill(&mut hc,SUPER_MAGIC,INODE_SIZE+56);
+        fill(&mut hc,self.nonces,INODE_SIZE+60);
         
         return hc
     }
@@ -293,10 +447,30 @@ impl Super {
             datas    : fetch(&blk, INODE_SIZE+24),
             imap     : fetch(&blk, INODE_SIZE+28),
             dmap     : fetch(&blk, INODE_SIZE+32),
+            rcnt     : fetch(&blk, INODE_SIZE+36),
+            snaps    : fetch(&blk, INODE_SIZE+40),
+            quota    : fetch(&blk, INODE_SIZE+44),
+            defrag   : fetch(&blk, INODE_SIZE+48),
+            trash    : fetch(&blk, INODE_SIZE+52),
+            nonces   : fetch(&blk, INODE_SIZE+60),
 
             root : Inode::from(hc_inode),
         }
     }
+
+    // whether the layout fits the tables and a hard drive of `sectors` sectors
+    fn is_valid(&self, sectors : usize) -> bool {
+        let (blk_nb, datas, inodes) = (self.blk_nb, self.datas as usize, self.inodes as usize);
+        let inside = |blk : u32| 0 < blk && (blk as usize) < blk_nb;
+        let optional = |blk : u32| blk == 0 || (datas <= blk as usize && (blk as usize) < blk_nb);
+        blk_nb.checked_mul(SECT_PER_BLK).is_some_and(|sects| sects <= sectors)
+            && inside(self.imap) && inside(self.dmap) && inside(self.inodes) && inside(self.datas)
+            && self.imap_sz == 1 && self.dmap_sz == 1
+            && 0 < self.iblk_nb && self.iblk_nb*INODE_PER_BLK <= BLK_SIZE*8 && inodes + self.iblk_nb <= datas
+            && self.dblk_nb <= BLK_SIZE && datas + self.dblk_nb <= blk_nb
+            && optional(self.rcnt) && optional(self.snaps) && optional(self.quota)
+            && self.root.id == 1
+    }
 }
 
 #[derive(Debug)] // TODO : remove it
@@ -306,7 +480,7 @@ pub struct Fdesc {
     iid : u32,
 }
 
-#[test] // TODO : remove it
+#[allow(dead_code)] // TODO : remove it
 impl std::fmt::Display for Fdesc {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> core::fmt::Result {
         let name: std::string::String = self.name.iter().collect();
@@ -365,6 +539,87 @@ impl Fdesc {
     }
 }
 
+// a read-only point-in-time copy of the inode table, sharing the data blocks
+// | name (32 chars) | name_len | ... | iblks (8 x 4 bytes) | imap (16 bytes) | ... |
+struct Snap {
+    name_len : usize, // 0 for a free entry
+    name     : [char; MAX_NAME_LEN],
+    iblks    : [u32; SNAP_IBLK],  // emplacement of the copied inode blocks
+    imap     : [u8; SNAP_IMAP],   // used inodes at the snapshot time
+}
+
+impl Snap {
+    fn hard_coded(&self) -> [u8; SNAP_SIZE] {
+        let mut hc = [0; SNAP_SIZE];
+        for (byte, &c) in hc.iter_mut().zip(&self.name) {
+            *byte = c as u8;
+        }
+        hc[MAX_NAME_LEN] = self.name_len as u8;
+        let ofs = MAX_NAME_LEN + 4;
+        for k in 0..SNAP_IBLK {
+            fill(&mut hc, self.iblks[k], ofs + 4*k);
+        }
+        let ofs = ofs + 4*SNAP_IBLK;
+        hc[ofs..ofs+SNAP_IMAP].copy_from_slice(&self.imap);
+        hc
+    }
+
+    fn from(hc : &[u8]) -> Self {
+        let mut snap = Snap {
+            name_len : hc[MAX_NAME_LEN] as usize,
+            name     : ['\0'; MAX_NAME_LEN],
+            iblks    : [0; SNAP_IBLK],
+            imap     : [0; SNAP_IMAP],
+        };
+        for (c, &byte) in snap.name.iter_mut().zip(hc) {
+            *c = byte as char;
+        }
+        let ofs = MAX_NAME_LEN + 4;
+        for k in 0..SNAP_IBLK {
+            snap.iblks[k] = fetch(hc, ofs + 4*k);
+        }
+        let ofs = ofs + 4*SNAP_IBLK;
+        snap.imap.copy_from_slice(&hc[ofs..ofs+SNAP_IMAP]);
+        snap
+    }
+
+    fn name(&self) -> String {
+        self.name[..self.name_len].iter().collect()
+    }
+
+    // inodes used by the snapshot
+    fn inodes(&self) -> Vec<u32> {
+        (1..SNAP_IMAP*8).filter(|&iid| select_bit(self.imap[iid/8], iid%8)).map(|iid| iid as u32).collect()
+    }
+}
+
+// limits of a directory tree, whose inodes carry the quota id (its index + 1)
+// | root | blocks | inodes | ... |  (0 for no limit)
+// There are no quotas per user : an inode has no owner.
+struct Quota {
+    root   : u32,   // 0 for a free entry
+    blocks : usize,
+    inodes : usize,
+}
+
+impl Quota {
+    fn hard_coded(&self) -> [u8; QUOTA_SIZE] {
+        let mut hc = [0; QUOTA_SIZE];
+        fill(&mut hc, self.root, 0);
+        fill(&mut hc, self.blocks as u32, 4);
+        fill(&mut hc, self.inodes as u32, 8);
+        hc
+    }
+
+    fn from(hc : &[u8]) -> Self {
+        Quota {
+            root   : fetch(hc, 0),
+            blocks : fetch(hc, 4) as usize,
+            inodes : fetch(hc, 8) as usize,
+        }
+    }
+}
+
 #[derive(Debug)] // TODO : remove it
 struct Dir {
     desc     : Fdesc,
@@ -376,6 +631,7 @@ impl Dir {
     fn hard_coded(&self) -> Block {
         let mut hc : Block = [0;BLK_SIZE];
         for i in 0..FDESC_PER_BLK{
+            if self.desc_tbl[i].iid == SNAPS_IID {continue} // virtual entry
             let fdesc = self.desc_tbl[i].hard_coded();
             for j in 0..FDESC_SIZE{
                 hc[i*FDESC_SIZE+j] = fdesc[j];
@@ -461,11 +717,36 @@ impl Dir {
     }
 }
 
+// encrypt (or decrypt) the names of the entries of a directory block,
+// the k-th entry uses the k-th block of the key stream
+fn crypt_names(blk : &mut Block, key : &Key, nonce : &[u8; crypt::NONCE_LEN]) {
+    for k in 2..FDESC_PER_BLK {
+        let ofs = k*FDESC_SIZE;
+        if fetch(blk, ofs) == 0 {continue};
+        let len = (blk[ofs+4+MAX_NAME_LEN] as usize).min(MAX_NAME_LEN);
+        crypt::chacha20(key, nonce, k as u32, &mut blk[ofs+4..ofs+4+len]);
+    }
+}
+
+// names of a locked directory : their encrypted bytes as printable characters
+fn scramble_names(blk : &mut Block) {
+    for k in 2..FDESC_PER_BLK {
+        let ofs = k*FDESC_SIZE;
+        if fetch(blk, ofs) == 0 {continue};
+        let len = (blk[ofs+4+MAX_NAME_LEN] as usize).min(MAX_NAME_LEN);
+        for c in &mut blk[ofs+4..ofs+4+len] {
+            *c = NAME_CHARS[(*c % 64) as usize];
+        }
+    }
+}
+
 pub struct Fs <'a> {
     hd  : &'a mut Hd,   // the mounted hard drive
     sup : Super,        // the corresponding super bloc
     imap: Bitmap,
     dmap: Bitmap,
+    refs: Refcounts,
+    keys: BTreeMap<u32, Key>, // unlocked keys, by encrypted tree
 }
 
 impl <'a> Fs <'a> {
@@ -477,6 +758,16 @@ impl <'a> Fs <'a> {
             id      : 1,
             ftype   : FType::Dir,
             size    : 1,
+            flags   : 0,
+            qid     : 0,
+            len     : 0,
+            clen    : 0,
+            kid     : 0,
+            gen     : 0,
+            salt    : [0; crypt::SALT_LEN],
+            check   : [0; crypt::CHECK_LEN],
+            xattr   : 0,
+            inline  : [0; INLINE_MAX],
             data_ptr : [8,0,0,0,0,
             0,0,0,0,0,
             0,0,0,0,0],
@@ -492,6 +783,12 @@ impl <'a> Fs <'a> {
             datas    : 8,
             imap     : 1,
             dmap     : 2,
+            rcnt     : 0,
+            snaps    : 0,
+            quota    : 0,
+            defrag   : 0,
+            trash    : 0,
+            nonces   : 0,
         
             root : ROOT,
         };
@@ -504,7 +801,9 @@ impl <'a> Fs <'a> {
             hd : hd,
             sup : SUPER,
             imap : imap,
+            refs : Refcounts::from_dmap(&dmap), // before dmap moves in
             dmap : dmap,
+            keys : BTreeMap::new(),
         };
 
         // write it on hard drive
@@ -533,12 +832,500 @@ impl <'a> Fs <'a> {
             sup : EMPTY_SUPER,
             imap : Bitmap{bmap : [0;BLK_SIZE]},
             dmap : Bitmap{bmap : [0;BLK_SIZE]},
+            refs : Refcounts{cnt : [0;BLK_SIZE]},
+            keys : BTreeMap::new(),
         };
+        fs.hd.reset_stats();
         if let Some(err) = fs.read_super() {return Err(err)};
         if let Some(err) = fs.read_tbls()  {return Err(err)};
+        // an interrupted defragmentation may leave copies marked as used
+        if fs.sup.defrag != 0 {
+            if let Some(err) = fs.recount() {return Err(err)};
+            fs.sup.defrag = 0;
+            if let Some(err) = fs.write_super() {return Err(err)};
+        }
         return Ok(fs)
     }
 
+    // reserve a free data block (1 reference), the caller writes the tables
+    fn alloc_blk(&mut self) -> Result<u32,FsErr> {
+        let did = self.dmap.find_free();
+        if did >= self.sup.dblk_nb {return Err(FsErr::DmapFull)};
+        self.dmap.unfree(did);
+        self.refs.cnt[did] = 1;
+        Ok(did as u32 + self.sup.datas)
+    }
+
+    // drop a reference to a data block, freed with its last reference
+    // (the caller writes the tables)
+    fn free_blk(&mut self, blk : u32) {
+        let did = (blk - self.sup.datas) as usize;
+        if self.refs.cnt[did] > 1 {self.refs.cnt[did] -= 1; return}
+        self.refs.cnt[did] = 0;
+        self.dmap.free(did);
+    }
+
+    fn refcount(&self, blk : u32) -> u8 {
+        self.refs.cnt[(blk - self.sup.datas) as usize]
+    }
+
+    // emplacement of the k-th block of a file (0 for a hole)
+    fn get_ptr(&mut self, inode : &Inode, k : usize) -> Result<u32,FsErr> {
+        if k < DIRECT_BLK {return Ok(inode.data_ptr[k])}
+        if inode.data_ptr[DIRECT_BLK] == 0 {return Ok(0)} // hole
+        let indirection = self.read_blk(inode.data_ptr[DIRECT_BLK])?;
+        Ok(fetch(&indirection, 4*(k-DIRECT_BLK))) // each addr is 4 bytes
+    }
+
+    // set the emplacement of the k-th block of a file, the indirection block
+    // is allocated (or copied if shared) when needed
+    fn set_ptr(&mut self, inode : &mut Inode, k : usize, blk : u32) -> Option<FsErr> {
+        if k < DIRECT_BLK {inode.data_ptr[k] = blk; return None}
+
+        let mut indirection = [0;BLK_SIZE];
+        let ind = inode.data_ptr[DIRECT_BLK];
+        if ind != 0 {
+            indirection = match self.read_blk(ind) {
+                Ok(blk) => blk,
+                Err(err) => return Some(err),
+            };
+        }
+        if ind == 0 || self.refcount(ind) > 1 {
+            inode.data_ptr[DIRECT_BLK] = match self.alloc_blk() {
+                Ok(blk) => blk,
+                Err(err) => return Some(err),
+            };
+            if ind != 0 {self.free_blk(ind)}
+        }
+        fill(&mut indirection, blk, 4*(k-DIRECT_BLK));
+        self.write_blk(indirection, inode.data_ptr[DIRECT_BLK])
+    }
+
+    // every data block used by a file (indirection and attributes included)
+    fn file_blks(&mut self, inode : &Inode) -> Result<Vec<u32>,FsErr> {
+        let mut blks = Vec::new();
+        for k in 0..inode.size {
+            let blk = self.get_ptr(inode, k)?;
+            if blk != 0 {blks.push(blk)}
+        }
+        if inode.data_ptr[DIRECT_BLK] != 0 {blks.push(inode.data_ptr[DIRECT_BLK])}
+        if inode.xattr != 0 {blks.push(inode.xattr)}
+        Ok(blks)
+    }
+
+    // drop the references of a file to its blocks (the caller writes the tables)
+    fn free_file(&mut self, inode : &Inode) -> Option<FsErr> {
+        let blks = match self.file_blks(inode) {
+            Ok(blks) => blks,
+            Err(err) => return Some(err),
+        };
+        for blk in blks {
+            self.free_blk(blk);
+        }
+        None
+    }
+
+    // key of an encrypted inode (None for a plain one)
+    fn key_of(&self, inode : &Inode) -> Result<Option<Key>,FsErr> {
+        if inode.flags & FLAG_CRYPT == 0 {return Ok(None)}
+        match self.keys.get(&inode.kid) {
+            Some(key) => Ok(Some(*key)),
+            None => Err(FsErr::Locked),
+        }
+    }
+
+    // a nonce counter for a write of an encrypted inode : the counter is kept
+    // by the super block, so that no key sees it twice, even when an inode
+    // id is reused or a snapshot is restored
+    fn next_gen(&mut self) -> Result<u32,FsErr> {
+        self.sup.nonces = self.sup.nonces.checked_add(1).ok_or(FsErr::NoncesExhausted)?;
+        if let Some(err) = self.write_super() {return Err(err)};
+        Ok(self.sup.nonces)
+    }
+
+    // the entries of an encrypted directory cannot change while it is locked
+    fn check_unlocked(&mut self, iid : u32) -> Option<FsErr> {
+        match self.read_inode(iid) {
+            Ok(inode) => self.key_of(&inode).err(),
+            Err(err) => Some(err),
+        }
+    }
+
+    // inodes used by the live file system
+    fn live_inodes(&self) -> Vec<u32> {
+        let inodes = self.sup.iblk_nb*INODE_PER_BLK;
+        (1..inodes).filter(|&iid| self.imap.is_free(iid)).map(|iid| iid as u32).collect()
+    }
+
+    // add a reference to every block of the live file system
+    fn share_live(&mut self) -> Option<FsErr> {
+        for iid in self.live_inodes() {
+            let inode = match self.read_inode(iid) {
+                Ok(inode) => inode,
+                Err(err) => return Some(err),
+            };
+            let blks = match self.file_blks(&inode) {
+                Ok(blks) => blks,
+                Err(err) => return Some(err),
+            };
+            for blk in blks {
+                let did = (blk - self.sup.datas) as usize;
+                if self.refs.cnt[did] == u8::MAX {return Some(FsErr::SnapFull)}
+                self.refs.cnt[did] += 1;
+            }
+        }
+        None
+    }
+
+    // rebuild the data bitmap & the reference counts from the pointers of the
+    // super block, of the snapshots and of the live inodes
+    fn recount(&mut self) -> Option<FsErr> {
+        let _op = self.hd.enter("recount");
+        let mut blks = vec![self.sup.rcnt, self.sup.snaps, self.sup.quota];
+        let snaps = match self.read_snaps() {
+            Ok(snaps) => snaps,
+            Err(err) => return Some(err),
+        };
+        for snap in snaps.iter().filter(|snap| snap.name_len != 0) {
+            blks.extend(snap.iblks);
+            for iid in snap.inodes() {
+                let inode = match self.read_snap_inode(snap, iid) {
+                    Ok(inode) => inode,
+                    Err(err) => return Some(err),
+                };
+                match self.file_blks(&inode) {
+                    Ok(file) => blks.extend(file),
+                    Err(err) => return Some(err),
+                }
+            }
+        }
+        for iid in self.live_inodes() {
+            let inode = match self.read_inode(iid) {
+                Ok(inode) => inode,
+                Err(err) => return Some(err),
+            };
+            match self.file_blks(&inode) {
+                Ok(file) => blks.extend(file),
+                Err(err) => return Some(err),
+            }
+        }
+
+        self.refs.cnt = [0; BLK_SIZE];
+        for blk in blks.into_iter().filter(|&blk| blk != 0) {
+            let did = (blk - self.sup.datas) as usize;
+            self.refs.cnt[did] = self.refs.cnt[did].saturating_add(1);
+        }
+        for did in 0..self.sup.dblk_nb {
+            if self.refs.cnt[did] == 0 {self.dmap.free(did)} else {self.dmap.unfree(did)}
+        }
+        self.write_tbls()
+    }
+
+    // first run of n free data blocks (index of its first one)
+    fn find_run(&self, n : usize) -> Option<usize> {
+        let mut len = 0;
+        for did in 0..self.sup.dblk_nb {
+            // is_free is true for a used block
+            if self.dmap.is_free(did) {len = 0; continue};
+            len += 1;
+            if len == n {return Some(did + 1 - n)}
+        }
+        None
+    }
+
+    // move the data blocks of a file (not shared) to the free run starting at
+    // the data block start : the copies are written and marked first, then the
+    // inode points to them (a single sector) and the old blocks are freed
+    fn move_file(&mut self, inode : &mut Inode, start : usize) -> Option<FsErr> {
+        let mut moved = BTreeMap::new();
+        let mut next = start;
+        for k in 0..inode.size {
+            let blk = match self.get_ptr(inode, k) {
+                Ok(0) => continue,
+                Ok(blk) => blk,
+                Err(err) => return Some(err),
+            };
+            let data = match self.read_blk(blk) {
+                Ok(data) => data,
+                Err(err) => return Some(err),
+            };
+            let new = next as u32 + self.sup.datas;
+            if let Some(err) = self.write_blk(data, new) {return Some(err)};
+            self.dmap.unfree(next);
+            self.refs.cnt[next] = 1;
+            moved.insert(blk, new);
+            next += 1;
+        }
+        // a new indirection rather than changing the one in use
+        let ind = inode.data_ptr[DIRECT_BLK];
+        if ind != 0 {
+            let new = match self.alloc_blk() {
+                Ok(new) => new,
+                Err(err) => return Some(err),
+            };
+            let indirection = match self.read_blk(ind) {
+                Ok(blk) => blk,
+                Err(err) => return Some(err),
+            };
+            if let Some(err) = self.write_blk(indirection, new) {return Some(err)};
+            moved.insert(ind, new);
+        }
+        if let Some(err) = self.write_tbls() {return Some(err)};
+
+        // (the xattr block stays where it is)
+        if let Some(err) = self.remap_inode(inode, &moved) {return Some(err)};
+        if let Some(err) = self.write_inode(inode) {return Some(err)};
+        for &blk in moved.keys() {
+            self.free_blk(blk);
+        }
+        self.write_tbls()
+    }
+
+    // share of the successive data blocks of the files which are not contiguous
+    // on the disk (in %), and number of runs of free blocks
+    fn fragmentation(&mut self) -> Result<(usize, usize),FsErr> {
+        let (mut pairs, mut breaks) : (usize, usize) = (0, 0);
+        for iid in self.live_inodes() {
+            let inode = self.read_inode(iid)?;
+            let mut last = 0;
+            for k in 0..inode.size {
+                let blk = self.get_ptr(&inode, k)?;
+                if blk == 0 {continue};
+                if last != 0 {
+                    pairs += 1;
+                    if blk != last + 1 {breaks += 1};
+                }
+                last = blk;
+            }
+        }
+        let runs = (0..self.sup.dblk_nb).filter(|&did| {
+            !self.dmap.is_free(did) && (did == 0 || self.dmap.is_free(did - 1))
+        }).count();
+        Ok(((breaks*100).checked_div(pairs).unwrap_or(0), runs))
+    }
+
+    // point an inode (and its indirection) to the new emplacements of moved blocks
+    fn remap_inode(&mut self, inode : &mut Inode, moved : &BTreeMap<u32, u32>) -> Option<FsErr> {
+        let remap = |blk : u32| *moved.get(&blk).unwrap_or(&blk);
+        for k in 0..DIRECT_BLK+1 {
+            inode.data_ptr[k] = remap(inode.data_ptr[k]);
+        }
+        inode.xattr = remap(inode.xattr);
+        if inode.data_ptr[DIRECT_BLK] == 0 {return None};
+        let mut indirection = match self.read_blk(inode.data_ptr[DIRECT_BLK]) {
+            Ok(blk) => blk,
+            Err(err) => return Some(err),
+        };
+        let mut changed = false;
+        for k in 0..BLK_SIZE/4 {
+            let blk = fetch(&indirection, 4*k);
+            if remap(blk) != blk {fill(&mut indirection, remap(blk), 4*k); changed = true}
+        }
+        // already done if shared with a snapshot
+        if !changed {return None};
+        self.write_blk(indirection, inode.data_ptr[DIRECT_BLK])
+    }
+
+    // extended attributes block : | name_len (u8) | value_len (u16) | name | value | ... | 0 |
+    fn read_xattrs(&mut self, inode : &Inode) -> Result<Vec<(String, Vec<u8>)>,FsErr> {
+        let mut attrs = Vec::new();
+        if inode.xattr == 0 {return Ok(attrs)}
+        let blk = self.read_blk(inode.xattr)?;
+        let mut ofs = 0;
+        while ofs + 3 <= BLK_SIZE && blk[ofs] != 0 {
+            let name_len = blk[ofs] as usize;
+            let value_len = ((blk[ofs+1] as usize) << 8) | blk[ofs+2] as usize;
+            let start = ofs + 3;
+            if start + name_len + value_len > BLK_SIZE {return Err(FsErr::Corrupted)}
+            let name = blk[start..start+name_len].iter().map(|&b| b as char).collect();
+            attrs.push((name, blk[start+name_len..start+name_len+value_len].to_vec()));
+            ofs = start + name_len + value_len;
+        }
+        Ok(attrs)
+    }
+
+    // write the attributes of an inode and the inode (the caller writes the tables)
+    fn write_xattrs(&mut self, inode : &mut Inode, attrs : &[(String, Vec<u8>)]) -> Option<FsErr> {
+        if attrs.is_empty() {
+            if inode.xattr != 0 {self.free_blk(inode.xattr)};
+            inode.xattr = 0;
+            return self.write_inode(inode)
+        }
+        let mut blk = [0; BLK_SIZE];
+        let mut ofs = 0;
+        for (name, value) in attrs {
+            let end = ofs + 3 + name.len() + value.len();
+            if end > BLK_SIZE {return Some(FsErr::XattrFull)};
+            blk[ofs] = name.len() as u8;
+            blk[ofs+1] = (value.len() >> 8) as u8;
+            blk[ofs+2] = value.len() as u8;
+            blk[ofs+3..ofs+3+name.len()].copy_from_slice(name.as_bytes());
+            blk[ofs+3+name.len()..end].copy_from_slice(value);
+            ofs = end;
+        }
+        if inode.xattr == 0 {
+            if let Some(err) = self.check_quota(inode.qid, 1, 0) {return Some(err)};
+        }
+        // copy on write, the block may be shared with a snapshot
+        if inode.xattr == 0 || self.refcount(inode.xattr) > 1 {
+            let new = match self.alloc_blk() {
+                Ok(new) => new,
+                Err(err) => return Some(err),
+            };
+            if inode.xattr != 0 {self.free_blk(inode.xattr)};
+            inode.xattr = new;
+        }
+        if let Some(err) = self.write_blk(blk, inode.xattr) {return Some(err)};
+        self.write_inode(inode)
+    }
+
+    fn read_snaps(&mut self) -> Result<Vec<Snap>,FsErr> {
+        let blk = if self.sup.snaps == 0 {[0;BLK_SIZE]} else {self.read_blk(self.sup.snaps)?};
+        Ok((0..SNAP_PER_BLK).map(|k| Snap::from(&blk[k*SNAP_SIZE..(k+1)*SNAP_SIZE])).collect())
+    }
+
+    fn write_snaps(&mut self, snaps : &[Snap]) -> Option<FsErr> {
+        let mut blk = [0;BLK_SIZE];
+        for (k, snap) in snaps.iter().enumerate() {
+            blk[k*SNAP_SIZE..(k+1)*SNAP_SIZE].copy_from_slice(&snap.hard_coded());
+        }
+        self.write_blk(blk, self.sup.snaps)
+    }
+
+    fn find_snap(snaps : &[Snap], name : &str) -> Result<usize,FsErr> {
+        for (k, snap) in snaps.iter().enumerate() {
+            if snap.name_len != 0 && snap.name() == name {return Ok(k)}
+        }
+        Err(FsErr::SnapNotFound)
+    }
+
+    fn read_quotas(&mut self) -> Result<Vec<Quota>,FsErr> {
+        let blk = if self.sup.quota == 0 {[0;BLK_SIZE]} else {self.read_blk(self.sup.quota)?};
+        Ok((0..QUOTA_PER_BLK).map(|k| Quota::from(&blk[k*QUOTA_SIZE..(k+1)*QUOTA_SIZE])).collect())
+    }
+
+    fn write_quotas(&mut self, quotas : &[Quota]) -> Option<FsErr> {
+        let mut blk = [0;BLK_SIZE];
+        for (k, quota) in quotas.iter().enumerate() {
+            blk[k*QUOTA_SIZE..(k+1)*QUOTA_SIZE].copy_from_slice(&quota.hard_coded());
+        }
+        self.write_blk(blk, self.sup.quota)
+    }
+
+    // an entry is left behind when its tree is removed
+    fn quota_alive(&mut self, quotas : &[Quota], qid : u16) -> Result<bool,FsErr> {
+        let root = quotas[qid as usize - 1].root;
+        if root == 0 || !self.imap.is_free(root as usize) {return Ok(false)}
+        Ok(self.read_inode(root)?.qid == qid)
+    }
+
+    // (blocks, inodes) used by the live inodes of a quota
+    fn quota_usage(&mut self, qid : u16) -> Result<(usize, usize),FsErr> {
+        let (mut blocks, mut inodes) = (0, 0);
+        for iid in self.live_inodes() {
+            let inode = self.read_inode(iid)?;
+            if inode.qid != qid {continue};
+            blocks += self.file_blks(&inode)?.len();
+            inodes += 1;
+        }
+        Ok((blocks, inodes))
+    }
+
+    // reject new blocks or inodes beyond the limits of a quota
+    fn check_quota(&mut self, qid : u16, blocks : usize, inodes : usize) -> Option<FsErr> {
+        if qid == 0 || (blocks == 0 && inodes == 0) {return None};
+        let check = |fs : &mut Self| -> Result<bool,FsErr> {
+            let quotas = fs.read_quotas()?;
+            if !fs.quota_alive(&quotas, qid)? {return Ok(true)};
+            let (used_blks, used_inodes) = fs.quota_usage(qid)?;
+            let quota = &quotas[qid as usize - 1];
+            Ok((quota.blocks == 0 || used_blks + blocks <= quota.blocks)
+                && (quota.inodes == 0 || used_inodes + inodes <= quota.inodes))
+        };
+        match check(self) {
+            Ok(true) => None,
+            Ok(false) => Some(FsErr::QuotaExceeded),
+            Err(err) => Some(err),
+        }
+    }
+
+    // (blocks, inodes) used by the part of a tree under the quota qid
+    fn tree_usage(&mut self, iid : u32, qid : u16) -> Result<(usize, usize),FsErr> {
+        let inode = self.read_inode(iid)?;
+        if inode.qid != qid {return Ok((0, 0))}; // a nested quota
+        let (mut blocks, mut inodes) = (self.file_blks(&inode)?.len(), 1);
+        if let FType::Dir = inode.ftype {
+            let dir = self.read_dir(iid)?;
+            for fd in &dir.desc_tbl[2..] {
+                if fd.iid == 0 || is_snap(fd.iid) {continue};
+                let (b, i) = self.tree_usage(fd.iid, qid)?;
+                blocks += b;
+                inodes += i;
+            }
+        }
+        Ok((blocks, inodes))
+    }
+
+    // move a tree from the quota from to the quota to (nested quotas excepted)
+    fn set_qid(&mut self, iid : u32, from : u16, to : u16) -> Option<FsErr> {
+        let mut inode = match self.read_inode(iid) {
+            Ok(inode) => inode,
+            Err(err) => return Some(err),
+        };
+        if inode.qid != from {return None};
+        inode.qid = to;
+        if let Some(err) = self.write_inode(&inode) {return Some(err)};
+        if let FType::Reg = inode.ftype {return None};
+        let dir = match self.read_dir(iid) {
+            Ok(dir) => dir,
+            Err(err) => return Some(err),
+        };
+        for fd in &dir.desc_tbl[2..] {
+            if fd.iid == 0 || is_snap(fd.iid) {continue};
+            if let Some(err) = self.set_qid(fd.iid, from, to) {return Some(err)};
+        }
+        None
+    }
+
+    // absolute path of a directory, through the .. entries
+    fn path_of(&mut self, iid : u32) -> Result<String,FsErr> {
+        if iid == self.sup.root.id {return Ok("/".to_string())}
+        let parent = self.read_dir(iid)?.desc_tbl[1].iid;
+        let parent = self.read_dir(parent)?;
+        let fd = parent.desc_tbl.iter().find(|fd| fd.iid == iid).ok_or(FsErr::Corrupted)?;
+        let name : String = fd.name[..fd.name_len].iter().collect();
+        let path = self.path_of(parent.desc_tbl[0].iid)?;
+        Ok(if path == "/" {format!("/{name}")} else {format!("{path}/{name}")})
+    }
+
+    fn read_snap_inode(&mut self, snap : &Snap, iid : u32) -> Result<Inode,FsErr> {
+        let blk = self.read_blk(snap.iblks[(iid as usize)/INODE_PER_BLK])?;
+        let mut hc = [0;INODE_SIZE];
+        let ofs = ((iid as usize)%INODE_PER_BLK)*INODE_SIZE;
+        hc.copy_from_slice(&blk[ofs..ofs+INODE_SIZE]);
+        Ok(Inode::from(hc))
+    }
+
+    // the virtual /.snapshots directory : one entry per snapshot root
+    fn read_snaps_dir(&mut self) -> Result<Dir,FsErr> {
+        let root = self.get_home_fdesc();
+        let mut dir = Dir::new(Fdesc {
+            name_len : 1,
+            name     : unsafe_name_from("."),
+            iid : SNAPS_IID,
+        }, &root);
+        for (k, snap) in self.read_snaps()?.iter().enumerate() {
+            if snap.name_len == 0 {continue}
+            dir.desc_tbl[dir.capacity] = Fdesc {
+                name_len : snap.name_len,
+                name     : snap.name,
+                iid : ((k as u32 + 1)<<SNAP_SHIFT) | self.sup.root.id,
+            };
+            dir.capacity += 1;
+        }
+        Ok(dir)
+    }
+
 
     fn write_blk(&mut self, blk: Block, offset : u32) -> Option<FsErr>{
         for i in 0..SECT_PER_BLK{
@@ -555,15 +1342,21 @@ impl <'a> Fs <'a> {
     }
 
     fn write_tbls(&mut self) -> Option<FsErr> {
+        let _op = self.hd.enter("write_tbls");
         if let Some(err) = self.write_blk(self.imap.bmap,1) {return Some(err)}
+        if self.sup.rcnt != 0 {
+            if let Some(err) = self.write_blk(self.refs.cnt,self.sup.rcnt) {return Some(err)}
+        }
         self.write_blk(self.dmap.bmap,2)
     }
 
     fn write_super(&mut self) -> Option<FsErr>{
+        let _op = self.hd.enter("write_super");
         self.write_blk(self.sup.hard_coded(), 0)
     }
 
     fn write_inode(&mut self, inode : &Inode) -> Option<FsErr>{
+        let _op = self.hd.enter("write_inode");
         let iid = inode.id;
         let hc = inode.hard_coded();
         let ofs = self.sup.inodes + iid/(INODE_PER_BLK as u32);
@@ -578,87 +1371,180 @@ impl <'a> Fs <'a> {
     }
 
     fn write_fblk(&mut self, iid : u32, blk_nm : usize, blk : Block) -> Option<FsErr>{
-        let inode = match self.read_inode(iid){
+        let _op = self.hd.enter("write_fblk");
+        let mut inode = match self.read_inode(iid){
             Ok(inode) => inode,
             Err(err)  => return Some(err),
         };
         if blk_nm >= inode.size {return Some(FsErr::UndefBlk)}
 
-        if blk_nm < DIRECT_BLK {
-            if let Some(err) = self.write_blk(blk, inode.data_ptr[blk_nm]) {return Some(err)}
-        }
-        else {
-            let indirection = match self.read_blk(inode.data_ptr[DIRECT_BLK]){
-                Ok(ind) => ind,
+        let mut ofs = match self.get_ptr(&inode, blk_nm) {
+            Ok(ofs) => ofs,
+            Err(err) => return Some(err),
+        };
+        // a hole gets its block, and copy on write : a shared block is
+        // never modified in place
+        if ofs == 0 || self.refcount(ofs) > 1 {
+            let new = match self.alloc_blk() {
+                Ok(new) => new,
                 Err(err) => return Some(err),
             };
-            let ofs = 4*(blk_nm - DIRECT_BLK); // each addr is 4 bytes
-            let ofs = fetch(&indirection, ofs as usize);
-            if let Some(err) = self.write_blk(blk, ofs) {return Some(err)}
+            if let Some(err) = self.set_ptr(&mut inode, blk_nm, new) {return Some(err)};
+            if ofs != 0 {self.free_blk(ofs)};
+            if let Some(err) = self.write_tbls() {return Some(err)};
+            if let Some(err) = self.write_inode(&inode) {return Some(err)};
+            ofs = new;
         }
-        return None
+        self.write_blk(blk, ofs)
     }
 
     fn write_dir(&mut self, dir : &Dir) -> Option<FsErr>{
-        if let Some(err) = self.write_fblk(dir.desc.iid, 0, dir.hard_coded()){
+        let _op = self.hd.enter("write_dir");
+        let mut blk = dir.hard_coded();
+        let mut inode = match self.read_inode(dir.desc.iid) {
+            Ok(inode) => inode,
+            Err(err)  => return Some(err),
+        };
+        match self.key_of(&inode) {
+            Ok(Some(key)) => {
+                // a fresh nonce for each version of the names
+                inode.gen = match self.next_gen() {
+                    Ok(gen) => gen,
+                    Err(err) => return Some(err),
+                };
+                if let Some(err) = self.write_inode(&inode) {return Some(err)};
+                crypt_names(&mut blk, &key, &nonce(inode.id, inode.gen, NONCE_NAMES));
+            },
+            Ok(None) => (),
+            Err(err) => return Some(err),
+        }
+        if let Some(err) = self.write_fblk(dir.desc.iid, 0, blk){
             return Some(err)
         }
         None
     }
 
     fn write_file(&mut self, cur : &mut Dir, name : &str, data : &Format) -> Option<FsErr>{
+        let _op = self.hd.enter("write_file");
+        if is_snap(cur.desc.iid) {return Some(FsErr::ReadOnly)};
         // access to the wanted inode
         let iid = cur.desc_tbl[
             match cur.find_file(name){
                 Ok(i)    => i,
                 Err(err) => return Some(err)
             }].iid;
-        let mut inode = match self.read_inode(iid) {
+        let inode = match self.read_inode(iid) {
             Ok(inode) => inode,
             Err(err)  => return Some(err)
         };
         if let FType::Dir = inode.ftype {return Some(FsErr::WriteDir)};
+        self.write_data(inode, data.iter().map(|&c| c as u8).collect())
+    }
+
+    // replace the whole content of a regular file
+    fn write_data(&mut self, mut inode : Inode, mut data : Vec<u8>) -> Option<FsErr>{
         inode.ftype = FType::Reg;
+        let key = match self.key_of(&inode) {
+            Ok(key) => key,
+            Err(err) => return Some(err),
+        };
 
-        // compute and add the good number of blocks
-        let old_size = inode.size;
-        inode.size = ceil(data.len(), BLK_SIZE);
-        for k in old_size..inode.size {
-            let did = self.dmap.find_free();
-            if did >= self.sup.dblk_nb {return Some(FsErr::DmapFull)};
-            self.dmap.unfree(did as usize);
-            if let Some(err) = self.write_tbls() {return Some(err)};
+        // what is really stored on the disk
+        inode.len = data.len();
+        if inode.flags & FLAG_COMPR != 0 {
+            data = lz::compress(&data);
+            inode.clen = data.len();
+        }
+        if let Some(key) = key {
+            inode.gen = match self.next_gen() {
+                Ok(gen) => gen,
+                Err(err) => return Some(err),
+            };
+            crypt::chacha20(&key, &nonce(inode.id, inode.gen, NONCE_DATA), 0, &mut data);
+        }
+        if ceil(data.len(), BLK_SIZE) > MAX_FILE_BLK {return Some(FsErr::FileTooBig)};
 
-            if k < DIRECT_BLK {
-                inode.data_ptr[k] = did as u32 + self.sup.datas
-            } 
-            else {
-                let mut indirection = match self.read_blk(inode.data_ptr[DIRECT_BLK]){
-                    Ok(ind) => ind,
-                    Err(err) => return Some(err),
-                };
-                fill(&mut indirection, did as u32 + self.sup.datas, 4*(k-DIRECT_BLK));
-            }
+        // a tiny file moves into its inode, a growing one back to blocks
+        inode.inline = [0; INLINE_MAX];
+        if data.len() <= INLINE_MAX {
+            inode.flags |= FLAG_INLINE;
+            inode.inline[..data.len()].copy_from_slice(&data);
         }
-        if let Some(err) = self.write_inode(&inode) {return Some(err)};
+        else {inode.flags &= !FLAG_INLINE}
 
-        // fill the file with new data
-        for i in 0..inode.size{
+        let new_size = if inode.flags & FLAG_INLINE != 0 {0} else {ceil(data.len(), BLK_SIZE)};
+        let blks : Vec<Block> = (0..new_size).map(|i| {
             let mut blk : Block = [0;BLK_SIZE];
-            for j in 0..BLK_SIZE {
-                let k = i*BLK_SIZE+j;
-                if k < data.len() {blk[j] = data[k] as u8}
+            let end = data.len().min((i+1)*BLK_SIZE);
+            blk[..end - i*BLK_SIZE].copy_from_slice(&data[i*BLK_SIZE..end]);
+            blk
+        }).collect();
+
+        // the blocks the file will use (indirection and attributes included)
+        let used : Vec<bool> = blks.iter().map(|blk| blk.iter().any(|&b| b != 0)).collect();
+        let indirect = used.iter().skip(DIRECT_BLK).any(|&u| u)
+            || (new_size > DIRECT_BLK && inode.data_ptr[DIRECT_BLK] != 0);
+        let wanted = used.iter().filter(|&&u| u).count() + indirect as usize + (inode.xattr != 0) as usize;
+        let owned = match self.file_blks(&inode) {
+            Ok(blks) => blks.len(),
+            Err(err) => return Some(err),
+        };
+        if let Some(err) = self.check_quota(inode.qid, wanted.saturating_sub(owned), 0) {return Some(err)};
+
+        // release the blocks past the end, and the ones becoming holes
+        if let Some(err) = self.shrink(&mut inode, new_size) {return Some(err)};
+        for (i, blk) in blks.iter().enumerate() {
+            if blk.iter().any(|&b| b != 0) {continue};
+            match self.get_ptr(&inode, i) {
+                Ok(0) => (),
+                Ok(ptr) => {
+                    self.free_blk(ptr);
+                    if let Some(err) = self.set_ptr(&mut inode, i, 0) {return Some(err)};
+                },
+                Err(err) => return Some(err),
             }
+        }
+        inode.size = new_size;
+        if let Some(err) = self.write_tbls() {return Some(err)};
+        if let Some(err) = self.write_inode(&inode) {return Some(err)};
+
+        // fill the file with new data (holes get their blocks when written)
+        for (i, blk) in blks.into_iter().enumerate() {
+            if blk.iter().all(|&b| b == 0) {continue};
             if let Some(err) = self.write_fblk(inode.id, i, blk) {return Some(err)};
         }
         None
     }
 
-
-    fn read_blk(&mut self, offset :u32) -> Result<Block, FsErr>{
-        let mut blk : Block = [0;BLK_SIZE];
-        for i in 0..SECT_PER_BLK{
-            let sect = match self.hd.dread(offset*(SECT_PER_BLK as u32)+(i as u32)) {
+    // release the blocks of a file from the new_size-th one (the caller writes
+    // the tables and the inode)
+    fn shrink(&mut self, inode : &mut Inode, new_size : usize) -> Option<FsErr>{
+        for k in new_size..inode.size {
+            let blk = match self.get_ptr(inode, k) {
+                Ok(0) => continue,
+                Ok(blk) => blk,
+                Err(err) => return Some(err),
+            };
+            self.free_blk(blk);
+            // growing again must give holes
+            if k < DIRECT_BLK {inode.data_ptr[k] = 0}
+            else if new_size > DIRECT_BLK {
+                if let Some(err) = self.set_ptr(inode, k, 0) {return Some(err)};
+            }
+        }
+        if new_size <= DIRECT_BLK && inode.data_ptr[DIRECT_BLK] != 0 {
+            self.free_blk(inode.data_ptr[DIRECT_BLK]);
+            inode.data_ptr[DIRECT_BLK] = 0;
+        }
+        inode.size = inode.size.min(new_size);
+        None
+    }
+
+
+    fn read_blk(&mut self, offset :u32) -> Result<Block, FsErr>{
+        let mut blk : Block = [0;BLK_SIZE];
+        for i in 0..SECT_PER_BLK{
+            let sect = match self.hd.dread(offset*(SECT_PER_BLK as u32)+(i as u32)) {
                 Ok(sect) => sect,
                 Err(err) => return Err(FsErr::HdErr(err)),
             };
@@ -670,6 +1556,7 @@ impl <'a> Fs <'a> {
     }
 
     fn read_tbls(&mut self) -> Option<FsErr>{
+        let _op = self.hd.enter("read_tbls");
         self.imap.bmap = match self.read_blk(self.sup.imap) {
             Ok(bmap) => bmap,
             Err(err) => return Some(err),
@@ -678,18 +1565,45 @@ impl <'a> Fs <'a> {
             Ok(bmap) => bmap,
             Err(err) => return Some(err),
         };
+        self.refs = if self.sup.rcnt == 0 {Refcounts::from_dmap(&self.dmap)} else {
+            match self.read_blk(self.sup.rcnt) {
+                Ok(cnt) => Refcounts {cnt},
+                Err(err) => return Some(err),
+            }
+        };
         return None
     }
     
     fn read_super(&mut self) -> Option<FsErr>{
-        self.sup = match self.read_blk(0){
-            Ok(sup) => Super::from(sup),
+        let _op = self.hd.enter("read_super");
+        let blk = match self.read_blk(0){
+            Ok(blk) => blk,
             Err(err) => return Some(err),
         };
+        let sup = Super::from(blk);
+        if fetch(&blk, INODE_SIZE+56) != SUPER_MAGIC || !sup.is_valid(self.hd.sectors()) {return Some(FsErr::NoFileSystem)};
+        self.sup = sup;
         return None
     }
 
     fn read_inode(&mut self, iid: u32) -> Result<Inode,FsErr>{
+        let _op = self.hd.enter("read_inode");
+        if iid == SNAPS_IID {
+            return Ok(Inode {
+                id : iid, ftype : FType::Dir, size : 1,
+                flags : 0, qid : 0, len : 0, clen : 0,
+                kid : 0, gen : 0, salt : [0; crypt::SALT_LEN], check : [0; crypt::CHECK_LEN],
+                xattr : 0, inline : [0; INLINE_MAX], data_ptr : [0;DIRECT_BLK+1],
+            })
+        }
+        if is_snap(iid) {
+            let snaps = self.read_snaps()?;
+            let snap = &snaps[((iid>>SNAP_SHIFT) - 1) as usize];
+            if snap.name_len == 0 {return Err(FsErr::SnapNotFound)}
+            let mut inode = self.read_snap_inode(snap, iid & IID_MASK)?;
+            inode.id = iid;
+            return Ok(inode)
+        }
         let blk : Block = self.read_blk(self.sup.inodes + iid/(INODE_PER_BLK as u32))?;
         let mut hc = [0;INODE_SIZE];
         for k in 0..INODE_SIZE {
@@ -699,23 +1613,28 @@ impl <'a> Fs <'a> {
     }
 
     fn read_fblk(&mut self, iid : u32, blk_nm : usize) -> Result<Block,FsErr>{
+        let _op = self.hd.enter("read_fblk");
         let inode = self.read_inode(iid)?;
         if blk_nm >= inode.size {return Err(FsErr::UndefBlk)}
-
-        if blk_nm < DIRECT_BLK {
-            return Ok(self.read_blk(inode.data_ptr[blk_nm])?);
-        }
-        else {
-            let indirection = self.read_blk(inode.data_ptr[DIRECT_BLK])?;
-            let ofs = 4*(blk_nm - DIRECT_BLK); // 4 bytes addr
-            return Ok(self.read_blk(fetch(&indirection, ofs as usize))?)
-        }
+        let ofs = self.get_ptr(&inode, blk_nm)?;
+        if ofs == 0 {return Ok([0;BLK_SIZE])} // hole
+        self.read_blk(ofs)
     }
 
     fn read_dir(&mut self, iid : u32) -> Result<Dir,FsErr>{
+        let _op = self.hd.enter("read_dir");
+        if iid == SNAPS_IID {return self.read_snaps_dir()}
         let dir_inode = self.read_inode(iid)?;
         let mut dir = match dir_inode.ftype {
-            FType::Dir => Dir::from(self.read_fblk(dir_inode.id,0)?),
+            FType::Dir => {
+                let mut blk = self.read_fblk(dir_inode.id,0)?;
+                match self.key_of(&dir_inode) {
+                    Ok(Some(key)) => crypt_names(&mut blk, &key, &nonce(iid, dir_inode.gen, NONCE_NAMES)),
+                    Ok(None) => (),
+                    Err(_) => scramble_names(&mut blk),
+                }
+                Dir::from(blk)
+            },
             _ => return Err(FsErr::NoDirectory),
         };
         dir.desc = Fdesc {
@@ -723,6 +1642,25 @@ impl <'a> Fs <'a> {
             name_len : 1,
             iid : iid,
         };
+
+        if is_snap(iid) {
+            // stay inside the snapshot, its root goes up to /.snapshots
+            let tag = iid & !IID_MASK;
+            for fd in dir.desc_tbl.iter_mut() {
+                if fd.iid != 0 {fd.iid |= tag}
+            }
+            if iid & IID_MASK == self.sup.root.id {dir.desc_tbl[1].iid = SNAPS_IID}
+        }
+        else if iid == self.sup.root.id {
+            // the (hidden) virtual directory of the snapshots
+            if let Ok(k) = dir.find_free() {
+                dir.desc_tbl[k] = Fdesc {
+                    name_len : SNAPS_NAME.len(),
+                    name     : unsafe_name_from(SNAPS_NAME),
+                    iid : SNAPS_IID,
+                };
+            }
+        }
         return Ok(dir);
         
     }
@@ -731,33 +1669,49 @@ impl <'a> Fs <'a> {
     fn mkdir__(&mut self, cur : &mut Dir, name : &str) -> Option<FsErr>{
         // check validity of the current directory
         let iid = cur.desc.iid;
+        if is_snap(iid) {return Some(FsErr::ReadOnly)};
         if !self.imap.is_free(iid as usize) {return Some(FsErr::InvalidCur)};
+        let parent = match self.read_inode(iid) {
+            Ok(inode) => inode,
+            Err(err) => return Some(err),
+        };
+        if let Err(err) = self.key_of(&parent) {return Some(err)};
 
         // reject if current directory is full or the name is already used
         if cur.capacity >= FDESC_PER_BLK {return Some(FsErr::DirFull)}
         if let Ok(_) = cur.find_file(name) {return Some(FsErr::FileExist)}
+        if let Some(err) = self.check_quota(parent.qid, 1, 1) {return Some(err)};
     
         // find free data & inode blocks 
         let iid = self.imap.find_free();
         if iid >= self.sup.iblk_nb*INODE_PER_BLK {
             return Some(FsErr::ImapFull)
         };
-        let did = self.dmap.find_free();
-        if did >= self.sup.dblk_nb {
-            return Some(FsErr::DmapFull)
+        let blk = match self.alloc_blk() {
+            Ok(blk) => blk,
+            Err(err) => return Some(err),
         };
-        // mark them unfree in imap & dmap and write tables
+        // mark the inode unfree in imap and write tables
         self.imap.unfree(iid as usize);
-        self.dmap.unfree(did as usize);
         if let Some(err) = self.write_tbls() {return Some(err)};
     
         // create and write a new inode
         let mut data_ptr = [0;DIRECT_BLK+1];
-        data_ptr[0] = (did as u32) + self.sup.datas;
+        data_ptr[0] = blk;
         let new_inode = Inode {
             id    : iid as u32,
             ftype : FType::Dir,
             size  : 1,
+            flags : parent.flags & FLAG_CRYPT, // an encrypted tree stays encrypted
+            qid   : parent.qid,
+            len   : 0,
+            clen  : 0,
+            kid   : parent.kid,
+            gen   : 0,
+            salt  : [0; crypt::SALT_LEN],
+            check : [0; crypt::CHECK_LEN],
+            xattr : 0,
+            inline: [0; INLINE_MAX],
             data_ptr : data_ptr,
         };
         if let Some(err) = self.write_inode(&new_inode) {return Some(err)}
@@ -803,7 +1757,7 @@ impl <'a> Fs <'a> {
                         };
                         // Update bitmaps tables
                         fs.imap.free(dinode.id as usize);
-                        fs.dmap.free(dinode.data_ptr[0] as usize);
+                        if let Some(err) = fs.free_file(&dinode) {return Some(err)};
                         if let Some(err) = fs.write_tbls() {return Some(err)};
                     },
                     // erase file
@@ -815,16 +1769,7 @@ impl <'a> Fs <'a> {
 
                         // Update bitmaps tables
                         fs.imap.free(finode.id as usize);
-                        for k in 0..finode.size {
-                            if k < DIRECT_BLK {fs.dmap.free(finode.data_ptr[k] as usize)}
-                            else {
-                                let indirection = match fs.read_blk(finode.data_ptr[DIRECT_BLK]){
-                                    Ok(ind) => ind,
-                                    Err(err) => return Some(err),
-                                };
-                                fs.dmap.free(fetch(&indirection, 4*(k-DIRECT_BLK)) as usize);
-                            }
-                        }
+                        if let Some(err) = fs.free_file(&finode) {return Some(err)};
                         if let Some(err) = fs.write_tbls() {return Some(err)};
                     },
                     Err(err) => return Some(err)
@@ -836,14 +1781,23 @@ impl <'a> Fs <'a> {
         }
 
         // Find the removed directory
+        if let Some(err) = self.check_unlocked(cur.desc.iid) {return Some(err)};
         let idx = match cur.find_file(name){
             Ok(idx) => idx,
             Err(err) => return Some(err),
         };
+        if is_snap(cur.desc.iid) || is_snap(cur.desc_tbl[idx].iid) {return Some(FsErr::ReadOnly)};
         let inode = match self.read_inode(cur.desc_tbl[idx].iid){
             Ok(inode) => inode,
             Err(err) => return Some(err),
         };
+        if let Err(err) = self.key_of(&inode) {return Some(err)};
+        // in trash mode, the whole tree only moves to /.trash
+        match self.move_to_trash(cur, idx) {
+            Ok(true) => return None,
+            Ok(false) => (),
+            Err(err) => return Some(err),
+        }
         // Update the current directory
         cur.desc_tbl[idx] = EMPTY_FILE;
         cur.capacity -= 1;
@@ -856,7 +1810,7 @@ impl <'a> Fs <'a> {
         clean_dir(self,&mut rm_dir);
         // Update bitmaps tables
         self.imap.free(inode.id as usize);
-        self.dmap.free(inode.data_ptr[0] as usize);
+        if let Some(err) = self.free_file(&inode) {return Some(err)};
         if let Some(err) = self.write_tbls() {return Some(err)};
         return None
     }
@@ -864,35 +1818,45 @@ impl <'a> Fs <'a> {
     fn touch__(&mut self, cur_dir : &mut Dir, name : &str)-> Option<FsErr> {
         // check validity of the current directory
         let iid = cur_dir.desc.iid;
+        if is_snap(iid) {return Some(FsErr::ReadOnly)};
         if !self.imap.is_free(iid as usize) {return Some(FsErr::InvalidCur)};
+        let parent = match self.read_inode(iid) {
+            Ok(inode) => inode,
+            Err(err) => return Some(err),
+        };
+        if let Err(err) = self.key_of(&parent) {return Some(err)};
 
         // reject if current directory is full
         if cur_dir.capacity >= FDESC_PER_BLK {return Some(FsErr::DirFull)}
         if let Ok(_) = cur_dir.find_file(name) {return Some(FsErr::FileExist)}
+        if let Some(err) = self.check_quota(parent.qid, 0, 1) {return Some(err)};
     
-        // find free data & inode blocks
+        // find a free inode (an empty file has no data block)
         let iid = self.imap.find_free();
         if iid >= self.sup.iblk_nb*INODE_PER_BLK {
             return Some(FsErr::ImapFull)
         };
-        let did = self.dmap.find_free();
-        if did >= self.sup.dblk_nb {
-            return Some(FsErr::DmapFull)
-        };
     
-        // mark them unfree in imap & dmap and write tables
+        // mark the inode unfree in imap and write tables
         self.imap.unfree(iid as usize);
-        self.dmap.unfree(did as usize);
         if let Some(err) = self.write_tbls() {return Some(err)};
     
         // create and write a new inode
-        let mut data_ptr = [0;DIRECT_BLK+1];
-        data_ptr[0] = (did as u32) + self.sup.datas;
         let file_inode = Inode {
             id    : iid as u32,
             ftype : FType::Reg,
-            size  : 1,
-            data_ptr : data_ptr,
+            size  : 0,
+            flags : (parent.flags & FLAG_CRYPT) | FLAG_INLINE, // an encrypted tree stays encrypted
+            qid   : parent.qid,
+            len   : 0,
+            clen  : 0,
+            kid   : parent.kid,
+            gen   : 0,
+            salt  : [0; crypt::SALT_LEN],
+            check : [0; crypt::CHECK_LEN],
+            xattr : 0,
+            inline: [0; INLINE_MAX],
+            data_ptr : [0;DIRECT_BLK+1],
         };
         if let Some(err) = self.write_inode(&file_inode) {return Some(err)}
     
@@ -917,12 +1881,14 @@ impl <'a> Fs <'a> {
     }
 
     fn rm__(&mut self, cur : &mut Dir, name : &str) -> Option<FsErr>{
+        if let Some(err) = self.check_unlocked(cur.desc.iid) {return Some(err)};
 
         // find the inode of the removed file
         let file_desc_idx = match cur.find_file(name){
             Ok(k) => k,
             Err(err) => return Some(err),
         };
+        if is_snap(cur.desc.iid) || is_snap(cur.desc_tbl[file_desc_idx].iid) {return Some(FsErr::ReadOnly)};
         let file_inode = match self.read_inode(cur.desc_tbl[file_desc_idx].iid){
             Ok(oi) => oi,
             Err(err) => return Some(err),
@@ -930,18 +1896,16 @@ impl <'a> Fs <'a> {
 
         if let FType::Dir = file_inode.ftype {return Some(FsErr::RemoveDir)};
 
+        // in trash mode, the file only moves to /.trash
+        match self.move_to_trash(cur, file_desc_idx) {
+            Ok(true) => return None,
+            Ok(false) => (),
+            Err(err) => return Some(err),
+        }
+
         // Update bitmaps tables
         self.imap.free(file_inode.id as usize);
-        for k in 0..file_inode.size {
-            if k < DIRECT_BLK {self.dmap.free(file_inode.data_ptr[k] as usize)}
-            else {
-                let indirection = match self.read_blk(file_inode.data_ptr[DIRECT_BLK]){
-                    Ok(ind) => ind,
-                    Err(err) => return Some(err),
-                };
-                self.dmap.free(fetch(&indirection, 4*(k-DIRECT_BLK)) as usize);
-            }
-        }
+        if let Some(err) = self.free_file(&file_inode) {return Some(err)};
         if let Some(err) = self.write_tbls() {return Some(err)};
 
         // Update the current directory
@@ -952,12 +1916,94 @@ impl <'a> Fs <'a> {
         return None
     }
 
+    // the /.trash directory (created if asked)
+    fn trash_dir(&mut self, create : bool) -> Result<Option<Dir>,FsErr> {
+        let mut root = self.read_dir(self.sup.root.id)?;
+        if root.find_file(TRASH_NAME).is_err() {
+            if !create {return Ok(None)};
+            if let Some(err) = self.mkdir__(&mut root, TRASH_NAME) {return Err(err)};
+        }
+        let iid = root.desc_tbl[root.find_file(TRASH_NAME)?].iid;
+        Ok(Some(self.read_dir(iid)?))
+    }
+
+    // the original path of a trashed entry is an attribute of /.trash, named
+    // like the entry (None to forget it)
+    fn set_origin(&mut self, trash : &Dir, name : &str, path : Option<String>) -> Option<FsErr> {
+        let mut inode = match self.read_inode(trash.desc.iid) {
+            Ok(inode) => inode,
+            Err(err) => return Some(err),
+        };
+        let mut attrs = match self.read_xattrs(&inode) {
+            Ok(attrs) => attrs,
+            Err(err) => return Some(err),
+        };
+        attrs.retain(|(n, _)| n != name);
+        if let Some(path) = path {attrs.push((name.to_string(), path.into_bytes()))};
+        if let Some(err) = self.write_xattrs(&mut inode, &attrs) {return Some(err)};
+        self.write_tbls()
+    }
+
+    // the .. entry of a moved directory
+    fn reparent(&mut self, iid : u32, parent : u32) -> Option<FsErr> {
+        let mut dir = match self.read_dir(iid) {
+            Ok(dir) => dir,
+            Err(err) => return Some(err),
+        };
+        dir.desc_tbl[1].iid = parent;
+        self.write_dir(&dir)
+    }
+
+    // move the idx-th entry of cur to /.trash, named by its iid : false when
+    // the deletion is final (no trash mode, a deletion inside the trash, or
+    // inside an encrypted tree whose names would leak)
+    fn move_to_trash(&mut self, cur : &mut Dir, idx : usize) -> Result<bool,FsErr> {
+        if self.sup.trash == 0 {return Ok(false)};
+        let mut trash = match self.trash_dir(false)? {
+            Some(trash) => trash,
+            None => return Ok(false),
+        };
+        let fd = cur.desc_tbl[idx].copy();
+        if cur.desc.iid == trash.desc.iid || fd.iid == trash.desc.iid {return Ok(false)};
+        if self.read_inode(cur.desc.iid)?.flags & FLAG_CRYPT != 0 {return Ok(false)};
+        if trash.capacity >= FDESC_PER_BLK {return Err(FsErr::TrashFull)};
+
+        let name = fd.iid.to_string();
+        let mut path = self.path_of(cur.desc.iid)?;
+        if path != "/" {path.push('/')};
+        path.extend(&fd.name[..fd.name_len]);
+        if let Some(err) = self.set_origin(&trash, &name, Some(path)) {return Err(err)};
+
+        let free = trash.find_free()?;
+        trash.desc_tbl[free] = Fdesc {
+            name_len : name.len(),
+            name     : unsafe_name_from(&name),
+            iid : fd.iid,
+        };
+        trash.capacity += 1;
+        if let Some(err) = self.write_dir(&trash) {return Err(err)};
+        if let FType::Dir = self.read_inode(fd.iid)?.ftype {
+            if let Some(err) = self.reparent(fd.iid, trash.desc.iid) {return Err(err)};
+        }
+        cur.desc_tbl[idx] = EMPTY_FILE;
+        cur.capacity -= 1;
+        if let Some(err) = self.write_dir(cur) {return Err(err)};
+        Ok(true)
+    }
+
+    // the virtual entry of the snapshots & the trash, in the root directory
+    fn is_hidden(&self, dir : &Dir, fd : &Fdesc) -> bool {
+        dir.desc.iid == self.sup.root.id
+            && (fd.iid == SNAPS_IID || fd.name[..fd.name_len].iter().copied().eq(TRASH_NAME.chars()))
+    }
+
     fn ls_dir(&mut self, dir : &Dir) -> Result<Format,FsErr>{
         let mut fmt : Format = Vec::new();
         for i in 0..FDESC_PER_BLK{
 
             let iid = dir.desc_tbl[i].iid;
             if iid == 0 {continue}
+            if self.is_hidden(dir, &dir.desc_tbl[i]) {continue}
             let inode = match self.read_inode(iid){
                 Ok(inode) => inode,
                 Err(err) => return Err(err),
@@ -985,20 +2031,220 @@ impl <'a> Fs <'a> {
         return Ok(fmt)
     }
 
+    // the logical content of a regular file
+    fn read_file(&mut self, inode : &Inode) -> Result<Vec<u8>,FsErr>{
+        let _op = self.hd.enter("read_file");
+        let key = self.key_of(inode)?;
+        // the sizes come from the disk : checked before any allocation
+        if inode.size > MAX_FILE_BLK || inode.len > MAX_FILE_BLK*BLK_SIZE {return Err(FsErr::Corrupted)}
+        let mut data = Vec::with_capacity(inode.size*BLK_SIZE);
+        if inode.flags & FLAG_INLINE != 0 {data.extend_from_slice(&inode.inline)}
+        for k in 0..inode.size{
+            data.extend_from_slice(&self.read_fblk(inode.id,k)?);
+        }
+        if let Some(key) = key {
+            crypt::chacha20(&key, &nonce(inode.id, inode.gen, NONCE_DATA), 0, &mut data);
+        }
+        if inode.flags & FLAG_COMPR != 0 {
+            if inode.clen > data.len() {return Err(FsErr::Corrupted)}
+            return lz::decompress(&data[..inode.clen], inode.len).ok_or(FsErr::Corrupted)
+        }
+        // a file written before the len field ends with its first '\0'
+        if inode.len == 0 && inode.size > 0 {
+            if let Some(end) = data.iter().position(|&b| b == 0) {data.truncate(end)}
+            return Ok(data)
+        }
+        data.truncate(inode.len);
+        Ok(data)
+    }
+
     fn cat_file(&mut self, cur_dir : &Dir, name : &str) -> Result<Format,FsErr>{
-        let mut v : Format = Vec::new();
-        let iid = cur_dir.desc_tbl[cur_dir.find_file(name)?].iid;
+        // the names of a locked directory cannot be found
+        let idx = match cur_dir.find_file(name) {
+            Err(FsErr::FileNotFound) => return Err(self.check_unlocked(cur_dir.desc.iid).unwrap_or(FsErr::FileNotFound)),
+            res => res?,
+        };
+        let iid = cur_dir.desc_tbl[idx].iid;
         let inode = self.read_inode(iid)?;
         if let FType::Dir = inode.ftype {return Err(FsErr::ReadDir)};
-        for k in 0..inode.size{
-            let blk = self.read_fblk(iid,k)?;
-            for l in 0..BLK_SIZE{
-                let c = blk[l] as char;
-                if c == '\0' {v.push('\n'); return Ok(v)}
-                v.push(c);
+        let mut v : Format = self.read_file(&inode)?.iter().map(|&b| b as char).collect();
+        v.push('\n');
+        Ok(v)
+    }
+
+    // switch the compression of a file, its content is rewritten accordingly
+    fn chattr__(&mut self, cur : &mut Dir, name : &str, compr : bool) -> Option<FsErr>{
+        if is_snap(cur.desc.iid) {return Some(FsErr::ReadOnly)};
+        let iid = cur.desc_tbl[
+            match cur.find_file(name){
+                Ok(i)    => i,
+                Err(err) => return Some(err)
+            }].iid;
+        let mut inode = match self.read_inode(iid) {
+            Ok(inode) => inode,
+            Err(err)  => return Some(err)
+        };
+        if let FType::Dir = inode.ftype {return Some(FsErr::WriteDir)};
+        if (inode.flags & FLAG_COMPR != 0) == compr {return None};
+
+        let data : Format = match self.read_file(&inode) {
+            Ok(data) => data.iter().map(|&b| b as char).collect(),
+            Err(err) => return Some(err),
+        };
+        if compr {inode.flags |= FLAG_COMPR} else {inode.flags &= !FLAG_COMPR};
+        inode.clen = 0;
+        if let Some(err) = self.write_inode(&inode) {return Some(err)};
+        self.write_file(cur, name, &data)
+    }
+
+    fn stat_file(&mut self, cur : &Dir, name : &str) -> Result<Format,FsErr>{
+        let name = if name.is_empty() {"."} else {name}; // aka stat of /
+        let iid = cur.desc_tbl[cur.find_file(name)?].iid;
+        let inode = self.read_inode(iid)?;
+        let blks = if iid == SNAPS_IID {0} else {self.file_blks(&inode)?.len()};
+        let (ftype, len) = match inode.ftype {
+            FType::Dir => ("directory", BLK_SIZE),
+            // a file written before the len field has to be read
+            _ if inode.len == 0 && inode.size > 0 => ("regular file", self.read_file(&inode)?.len()),
+            _ => ("regular file", inode.len),
+        };
+        let mut flags = String::new();
+        if inode.flags & FLAG_COMPR != 0 {flags.push('c')}
+        if inode.flags & FLAG_CRYPT != 0 {flags.push('e')}
+        if inode.flags & FLAG_INLINE != 0 {flags.push('i')}
+        if flags.is_empty() {flags.push('-')}
+
+        let mut res = format!("  File : {name}\n  Type : {ftype}\n Inode : {}\n", inode.id & IID_MASK);
+        res.push_str(&format!("  Size : {len} bytes ({} blocks)\nBlocks : {blks} allocated\n", inode.size));
+        if len != 0 && inode.flags & FLAG_COMPR != 0 {
+            res.push_str(&format!("Stored : {} bytes ({}% of the size)\n", inode.clen, inode.clen*100/len));
+        }
+        res.push_str(&format!(" Flags : {flags}\n"));
+        Ok(res.chars().collect())
+    }
+
+    // a plain file in blocks can be changed block by block, the other ones are
+    // rewritten as a whole
+    fn in_blocks(inode : &Inode, len : usize) -> bool {
+        inode.flags & (FLAG_COMPR | FLAG_CRYPT | FLAG_INLINE) == 0
+        && !(inode.len == 0 && inode.size > 0) // written before the len field
+        && len > INLINE_MAX
+    }
+
+    fn write_at__(&mut self, cur : &mut Dir, name : &str, offset : usize, data : &[u8]) -> Option<FsErr>{
+        if is_snap(cur.desc.iid) {return Some(FsErr::ReadOnly)};
+        let iid = cur.desc_tbl[
+            match cur.find_file(name){
+                Ok(i)    => i,
+                Err(err) => return Some(err)
+            }].iid;
+        let mut inode = match self.read_inode(iid) {
+            Ok(inode) => inode,
+            Err(err)  => return Some(err)
+        };
+        if let FType::Dir = inode.ftype {return Some(FsErr::WriteDir)};
+        let end = match offset.checked_add(data.len()) {
+            Some(end) => end,
+            None => return Some(FsErr::FileTooBig),
+        };
+        if ceil(end, BLK_SIZE) > MAX_FILE_BLK {return Some(FsErr::FileTooBig)};
+
+        if !Self::in_blocks(&inode, end.max(inode.len)) {
+            let mut content = match self.read_file(&inode) {
+                Ok(content) => content,
+                Err(err) => return Some(err),
+            };
+            if content.len() < end {content.resize(end, 0)};
+            content[offset..end].copy_from_slice(data);
+            return self.write_data(inode, content)
+        }
+
+        // the holes of the range get a block (and the indirection if needed)
+        let mut wanted = 0;
+        for i in offset/BLK_SIZE..ceil(end, BLK_SIZE) {
+            match self.get_ptr(&inode, i) {
+                Ok(0) => wanted += 1,
+                Ok(_) => (),
+                Err(err) => return Some(err),
             }
         }
-        Ok(v)
+        if ceil(end, BLK_SIZE) > DIRECT_BLK && inode.data_ptr[DIRECT_BLK] == 0 {wanted += 1};
+        if let Some(err) = self.check_quota(inode.qid, wanted, 0) {return Some(err)};
+
+        // the blocks before offset are left as they are (or as holes)
+        if end > inode.len {
+            inode.len = end;
+            inode.size = inode.size.max(ceil(end, BLK_SIZE));
+            if let Some(err) = self.write_inode(&inode) {return Some(err)};
+        }
+        for i in offset/BLK_SIZE..ceil(end, BLK_SIZE) {
+            let hole = match self.get_ptr(&inode, i) {
+                Ok(ptr) => ptr == 0,
+                Err(err) => return Some(err),
+            };
+            let mut blk = match self.read_fblk(iid, i) {
+                Ok(blk) => blk,
+                Err(err) => return Some(err),
+            };
+            let (start, stop) = (offset.max(i*BLK_SIZE), end.min((i+1)*BLK_SIZE));
+            blk[start - i*BLK_SIZE..stop - i*BLK_SIZE].copy_from_slice(&data[start - offset..stop - offset]);
+            if hole && blk.iter().all(|&b| b == 0) {continue};
+            if let Some(err) = self.write_fblk(iid, i, blk) {return Some(err)};
+        }
+        None
+    }
+
+    fn truncate__(&mut self, cur : &mut Dir, name : &str, len : usize) -> Option<FsErr>{
+        if is_snap(cur.desc.iid) {return Some(FsErr::ReadOnly)};
+        let iid = cur.desc_tbl[
+            match cur.find_file(name){
+                Ok(i)    => i,
+                Err(err) => return Some(err)
+            }].iid;
+        let inode = match self.read_inode(iid) {
+            Ok(inode) => inode,
+            Err(err)  => return Some(err)
+        };
+        if let FType::Dir = inode.ftype {return Some(FsErr::WriteDir)};
+        if ceil(len, BLK_SIZE) > MAX_FILE_BLK {return Some(FsErr::FileTooBig)};
+
+        if !Self::in_blocks(&inode, len) {
+            let mut content = match self.read_file(&inode) {
+                Ok(content) => content,
+                Err(err) => return Some(err),
+            };
+            content.resize(len, 0);
+            return self.write_data(inode, content)
+        }
+
+        // the end of the new last block must read as zeros if the file grows again
+        if len < inode.len && !len.is_multiple_of(BLK_SIZE) {
+            let i = len/BLK_SIZE;
+            match self.get_ptr(&inode, i) {
+                Ok(0) => (),
+                Ok(_) => {
+                    let mut blk = match self.read_fblk(iid, i) {
+                        Ok(blk) => blk,
+                        Err(err) => return Some(err),
+                    };
+                    blk[len % BLK_SIZE..].fill(0);
+                    if let Some(err) = self.write_fblk(iid, i, blk) {return Some(err)};
+                },
+                Err(err) => return Some(err),
+            }
+        }
+
+        // a longer file only gets holes
+        let mut inode = match self.read_inode(iid) {
+            Ok(inode) => inode,
+            Err(err)  => return Some(err)
+        };
+        let new_size = ceil(len, BLK_SIZE);
+        if let Some(err) = self.shrink(&mut inode, new_size) {return Some(err)};
+        inode.size = new_size;
+        inode.len = len;
+        if let Some(err) = self.write_tbls() {return Some(err)};
+        self.write_inode(&inode)
     }
 }
 
@@ -1042,94 +2288,718 @@ impl <'a> Path<'a> {
 
 impl<'a> Fs<'a> {
 
-    pub fn get_home_fdesc(&mut self) -> Fdesc {
-        return Fdesc {
-            name_len : 1,
-            name     : unsafe_name_from("/"),
-            iid : self.sup.root.id
-        };
+    // write the hard drive back to its storage
+    pub fn sync(&mut self) -> Option<FsErr> {
+        self.hd.flush().map(FsErr::HdErr)
     }
 
-    pub fn cd(&mut self, cur : &Fdesc, path: &str) -> Result<Fdesc,FsErr>{
-        let path = Path::from(path);
-        if path.abs {
-            let root = self.read_dir(self.sup.root.id)?.desc;
-            return self.cd(&root,path.next)
-        }
-
-        let cur = self.read_dir(cur.iid)?;
-        if path.next == ""  {
-            let fd = cur.desc_tbl[cur.find_file(path.cur)?].copy();
-            match self.read_inode(fd.iid)?.ftype {
-                FType::Dir => return Ok(fd),
-                _ => return Err(FsErr::NoDirectory)
-            }
+    // grow the file system to size bytes while mounted, the device is
+    // extended if needed. Only the data region grows : the bitmaps & the
+    // counts keep their single block (BLK_SIZE data blocks at most, a bigger
+    // size is refused) and the inode table its blocks
+    pub fn grow(&mut self, size : usize) -> Option<FsErr> {
+        let _op = self.hd.enter("grow");
+        let blk_nb = size/BLK_SIZE;
+        if blk_nb < self.sup.blk_nb {return Some(FsErr::ShrinkMounted)};
+        if blk_nb - self.sup.datas as usize > BLK_SIZE {return Some(FsErr::GrowLimit)};
+        if self.hd.sectors() < blk_nb*SECT_PER_BLK {
+            if let Some(err) = self.hd.resize(blk_nb*SECT_PER_BLK) {return Some(FsErr::HdErr(err))};
         }
-        
-        let next = &cur.desc_tbl[cur.find_file(path.cur)?];
-        return self.cd(next,path.next)
+        self.sup.blk_nb = blk_nb;
+        self.sup.dblk_nb = blk_nb - self.sup.datas as usize;
+        self.write_super()
     }
 
-    pub fn mv(&mut self, cur : &Fdesc, old_path : &str, new_path : &str) -> Option<FsErr>{
-        fn _mv_(fs: &mut Fs, old_dir : &mut Dir, new_dir : &mut Dir, old_name : &str, new_name : &str) -> Option<FsErr>{
-            // take and modify the file descriptor
-            let old_idx = match old_dir.find_file(old_name) {
-                Ok(idx) => idx,
-                Err(err) => return Some(err)
-            };
-            let mut fd = old_dir.desc_tbl[old_idx].copy();
-            fd.name = match name_from(new_name){
-                Ok(name) => name,
-                Err(err) => return Some(err)
+    // resize the file system of an unmounted hard drive to size bytes : when
+    // shrinking, the blocks of the cut region move to free ones before it and
+    // every pointer to them is rewritten, the device is cut at last
+    pub fn resize_offline(hd : &'a mut Hd, size : usize) -> Option<FsErr> {
+        let mut fs = match Fs::mount(hd) {
+            Ok(fs) => fs,
+            Err(err) => return Some(err),
+        };
+        let blk_nb = size/BLK_SIZE;
+        if blk_nb >= fs.sup.blk_nb {return fs.grow(size)};
+        let _op = fs.hd.enter("shrink");
+        if blk_nb <= fs.sup.datas as usize {return Some(FsErr::InvalidSize)};
+        let dblk_nb = blk_nb - fs.sup.datas as usize;
+        if fs.dmap.count_used(fs.sup.dblk_nb) > dblk_nb {return Some(FsErr::InvalidSize)};
+
+        // copy the blocks out of the cut region
+        let mut moved = BTreeMap::new();
+        let mut free = (0..dblk_nb).filter(|&did| !fs.dmap.is_free(did)).collect::<Vec<_>>().into_iter();
+        for did in dblk_nb..fs.sup.dblk_nb {
+            if !fs.dmap.is_free(did) {continue};
+            let new = free.next().unwrap(); // enough free ones (checked above)
+            let (old_blk, new_blk) = (did as u32 + fs.sup.datas, new as u32 + fs.sup.datas);
+            let blk = match fs.read_blk(old_blk) {
+                Ok(blk) => blk,
+                Err(err) => return Some(err),
             };
-            fd.name_len = new_name.len();
+            if let Some(err) = fs.write_blk(blk, new_blk) {return Some(err)};
+            fs.dmap.unfree(new);
+            fs.refs.cnt[new] = fs.refs.cnt[did];
+            fs.refs.cnt[did] = 0;
+            fs.dmap.free(did);
+            moved.insert(old_blk, new_blk);
+        }
 
-            // case old_dir == new_dir : cannot have 2 fresh desc !
-            if old_dir.desc.iid == new_dir.desc.iid {
-                new_dir.desc_tbl[old_idx] = fd;
-                if let Some(err) = fs.write_dir(new_dir) {return Some(err)};
-                return None
+        // the tables of the super block, the snapshots and the live inodes
+        let remap = |blk : u32| *moved.get(&blk).unwrap_or(&blk);
+        fs.sup.rcnt = remap(fs.sup.rcnt);
+        fs.sup.snaps = remap(fs.sup.snaps);
+        fs.sup.quota = remap(fs.sup.quota);
+        let mut root = Inode::from(fs.sup.root.hard_coded());
+        if let Some(err) = fs.remap_inode(&mut root, &moved) {return Some(err)};
+        fs.sup.root = root;
+        let mut snaps = match fs.read_snaps() {
+            Ok(snaps) => snaps,
+            Err(err) => return Some(err),
+        };
+        for snap in snaps.iter_mut().filter(|snap| snap.name_len != 0) {
+            let used = snap.inodes();
+            for k in 0..SNAP_IBLK {
+                if snap.iblks[k] == 0 {continue};
+                snap.iblks[k] = remap(snap.iblks[k]);
+                let mut blk = match fs.read_blk(snap.iblks[k]) {
+                    Ok(blk) => blk,
+                    Err(err) => return Some(err),
+                };
+                for j in 0..INODE_PER_BLK {
+                    if !used.contains(&((k*INODE_PER_BLK + j) as u32)) {continue};
+                    let mut hc = [0; INODE_SIZE];
+                    hc.copy_from_slice(&blk[j*INODE_SIZE..(j+1)*INODE_SIZE]);
+                    let mut inode = Inode::from(hc);
+                    if let Some(err) = fs.remap_inode(&mut inode, &moved) {return Some(err)};
+                    blk[j*INODE_SIZE..(j+1)*INODE_SIZE].copy_from_slice(&inode.hard_coded());
+                }
+                if let Some(err) = fs.write_blk(blk, snap.iblks[k]) {return Some(err)};
             }
-
-            // find a new emplacement inside the new directory
-            let new_idx = match new_dir.find_free() {
-                Ok(idx) => idx,
-                Err(err) => return Some(err)
+        }
+        if fs.sup.snaps != 0 {
+            if let Some(err) = fs.write_snaps(&snaps) {return Some(err)};
+        }
+        for iid in fs.live_inodes() {
+            let mut inode = match fs.read_inode(iid) {
+                Ok(inode) => inode,
+                Err(err) => return Some(err),
             };
-            new_dir.capacity += 1;
-            new_dir.desc_tbl[new_idx] = fd;
-            if let Some(err) = fs.write_dir(new_dir) {return Some(err)}
-
-            // remove the file from the old directory
-            old_dir.desc_tbl[old_idx] = EMPTY_FILE;
-            old_dir.capacity -= 1;
-            if let Some(err) = fs.write_dir(old_dir) {return Some(err)}
-            return None
+            if let Some(err) = fs.remap_inode(&mut inode, &moved) {return Some(err)};
+            if let Some(err) = fs.write_inode(&inode) {return Some(err)};
         }
 
-        // Find old directory and old name
-        fn _chassing1_<'a,'b>(fs : &'b mut Fs, cur: &'b Fdesc, path : &'a str) -> Result<(Dir,&'a str),FsErr>{
-            let path = Path::from(path);
-            if path.abs {
-                let root = fs.read_dir(fs.sup.root.id)?.desc;
-                return _chassing1_(fs,&root, path.next)
-            }
+        // nothing points to the cut region anymore
+        fs.sup.blk_nb = blk_nb;
+        fs.sup.dblk_nb = dblk_nb;
+        if let Some(err) = fs.write_tbls() {return Some(err)};
+        if let Some(err) = fs.write_super() {return Some(err)};
+        if let Some(err) = fs.hd.resize(blk_nb*SECT_PER_BLK) {return Some(FsErr::HdErr(err))};
+        fs.sync()
+    }
 
-            let cur = fs.read_dir(cur.iid)?;
-            if path.next == "" {return Ok((cur, path.cur))}
-            let next = &cur.desc_tbl[cur.find_file(path.cur)?];
-            return _chassing1_(fs,next,path.next)
-        }
+    // usage of the file system and of its hard drive
+    pub fn df(&mut self) -> Format {
+        let inodes = self.sup.iblk_nb*INODE_PER_BLK;
+        let mut res = format!("blocks : {}/{} used\n", self.dmap.count_used(self.sup.dblk_nb), self.sup.dblk_nb);
+        res += &format!("inodes : {}/{} used\n", self.imap.count_used(inodes), inodes);
+        res += &format!("device : {} bytes (logical), {} bytes (allocated)\n",
+            self.hd.sectors()*SECT_SIZE, self.hd.allocated());
+        res.chars().collect()
+    }
 
-        // Find new directory and optionnal new name
-        fn _chassing2_<'a,'b>(fs : &'b mut Fs, cur: &'b Fdesc, path : &'a str) -> Result<(Dir,Option<&'a str>),FsErr>{
-            let path = Path::from(path);
-            if path.abs {
-                let root = fs.read_dir(fs.sup.root.id)?.desc;
-                return _chassing2_(fs,&root, path.next)
-            }
+    pub fn overlay_status(&mut self) -> Result<Format,FsErr> {
+        let held = self.hd.overlay().map_err(FsErr::HdErr)?.held();
+        Ok(format!("overlay : {held} sectors ({} bytes) not committed\n", held*SECT_SIZE).chars().collect())
+    }
 
-            let cur = fs.read_dir(cur.iid)?;
+    pub fn overlay_commit(&mut self) -> Option<FsErr> {
+        match self.hd.overlay() {
+            Ok(overlay) => overlay.commit().map(FsErr::HdErr),
+            Err(err) => Some(FsErr::HdErr(err)),
+        }
+    }
+
+    // the in-memory super block & bitmaps are reloaded from the base
+    pub fn overlay_discard(&mut self) -> Option<FsErr> {
+        match self.hd.overlay() {
+            Ok(overlay) => overlay.discard(),
+            Err(err) => return Some(FsErr::HdErr(err)),
+        };
+        if let Some(err) = self.read_super() {return Some(err)};
+        self.read_tbls()
+    }
+
+    // offline deduplication : the identical data blocks of the live files
+    // share the same emplacement (a write copies them again)
+    pub fn dedupe(&mut self) -> Result<Format,FsErr> {
+        let _op = self.hd.enter("dedupe");
+        let mut seen : BTreeMap<[u8;32], u32> = BTreeMap::new(); // content hash -> block
+        let (mut shared, mut freed) = (0, 0);
+        for iid in self.live_inodes() {
+            let mut inode = self.read_inode(iid)?;
+            if let FType::Dir = inode.ftype {continue};
+            let mut changed = false;
+            for k in 0..inode.size {
+                let blk = self.get_ptr(&inode, k)?;
+                if blk == 0 {continue};
+                let data = self.read_blk(blk)?;
+                let hash = crypt::sha256(&data);
+                let same = match seen.get(&hash) {
+                    None => {seen.insert(hash, blk); continue},
+                    Some(&same) => same,
+                };
+                // a hash is not a proof
+                if same == blk || self.refcount(same) == u8::MAX || self.read_blk(same)? != data {continue};
+
+                // the counts must survive the mount from now on
+                if self.sup.rcnt == 0 {
+                    self.sup.rcnt = self.alloc_blk()?;
+                    if let Some(err) = self.write_super() {return Err(err)};
+                }
+                self.refs.cnt[(same - self.sup.datas) as usize] += 1;
+                if let Some(err) = self.set_ptr(&mut inode, k, same) {return Err(err)};
+                self.free_blk(blk);
+                if self.refcount(blk) == 0 {freed += 1};
+                shared += 1;
+                changed = true;
+            }
+            if changed {
+                if let Some(err) = self.write_inode(&inode) {return Err(err)};
+            }
+        }
+        if let Some(err) = self.write_tbls() {return Err(err)};
+        Ok(format!("{shared} blocks shared, {freed} blocks freed\n").chars().collect())
+    }
+
+    // move the blocks of each file into a contiguous run, as near to the beginning
+    // of the data region as possible (the free space gathers at the end), the
+    // files sharing blocks stay where they are
+    pub fn defrag(&mut self) -> Result<Format,FsErr> {
+        let _op = self.hd.enter("defrag");
+        let (score, runs) = self.fragmentation()?;
+        self.sup.defrag = 1;
+        if let Some(err) = self.write_super() {return Err(err)};
+
+        let mut moved = 0;
+        loop {
+            // the files by emplacement of their first block
+            let mut files = Vec::new();
+            for iid in self.live_inodes() {
+                let inode = self.read_inode(iid)?;
+                let mut blks = Vec::new();
+                for k in 0..inode.size {
+                    let blk = self.get_ptr(&inode, k)?;
+                    if blk != 0 {blks.push(blk)};
+                }
+                if blks.is_empty() || self.file_blks(&inode)?.iter().any(|&blk| self.refcount(blk) > 1) {continue};
+                files.push((blks, inode));
+            }
+            files.sort_by_key(|(blks, _)| blks[0]);
+
+            let mut changed = false;
+            for (blks, mut inode) in files {
+                let start = (blks[0] - self.sup.datas) as usize;
+                let contiguous = blks.windows(2).all(|pair| pair[1] == pair[0] + 1);
+                let run = match self.find_run(blks.len()) {
+                    Some(run) => run,
+                    None => continue,
+                };
+                if contiguous && run >= start {continue};
+                if let Some(err) = self.move_file(&mut inode, run) {return Err(err)};
+                moved += blks.len();
+                changed = true;
+            }
+            if !changed {break};
+        }
+
+        self.sup.defrag = 0;
+        if let Some(err) = self.write_super() {return Err(err)};
+        let (new_score, new_runs) = self.fragmentation()?;
+        Ok(format!("fragmentation : {score}% -> {new_score}%\nfree space : {runs} runs -> {new_runs} runs\n{moved} blocks moved\n").chars().collect())
+    }
+
+    pub fn snapshot_create(&mut self, name : &str) -> Option<FsErr> {
+        let _op = self.hd.enter("snapshot_create");
+        // on failure the blocks shared or allocated so far are given back :
+        // the tables & the super block are restored as they were
+        let (cnt, bmap) = (self.refs.cnt, self.dmap.bmap);
+        let (rcnt, snaps) = (self.sup.rcnt, self.sup.snaps);
+        let err = self.snapshot_create__(name)?;
+        self.refs.cnt = cnt;
+        self.dmap.bmap = bmap;
+        if (rcnt, snaps) != (self.sup.rcnt, self.sup.snaps) {
+            (self.sup.rcnt, self.sup.snaps) = (rcnt, snaps);
+            if let Some(err) = self.write_super() {return Some(err)};
+        }
+        if let Some(err) = self.write_tbls() {return Some(err)};
+        Some(err)
+    }
+
+    fn snapshot_create__(&mut self, name : &str) -> Option<FsErr> {
+        if let Err(err) = name_from(name) {return Some(err)};
+        if self.sup.iblk_nb > SNAP_IBLK {return Some(FsErr::SnapFull)};
+        let mut snaps = match self.read_snaps() {
+            Ok(snaps) => snaps,
+            Err(err) => return Some(err),
+        };
+        if Self::find_snap(&snaps, name).is_ok() {return Some(FsErr::SnapExist)};
+        let idx = match snaps.iter().position(|snap| snap.name_len == 0) {
+            Some(idx) => idx,
+            None => return Some(FsErr::SnapFull),
+        };
+
+        // reference counts & snapshots table are created with the first snapshot
+        if self.sup.rcnt == 0 {
+            self.sup.rcnt = match self.alloc_blk() {
+                Ok(blk) => blk,
+                Err(err) => return Some(err),
+            };
+        }
+        if self.sup.snaps == 0 {
+            self.sup.snaps = match self.alloc_blk() {
+                Ok(blk) => blk,
+                Err(err) => return Some(err),
+            };
+        }
+        if let Some(err) = self.write_super() {return Some(err)};
+
+        // the snapshot shares every block of the live file system ...
+        if let Some(err) = self.share_live() {return Some(err)};
+
+        // ... through its own copy of the inode table
+        let mut snap = Snap {
+            name_len : name.len(),
+            name     : unsafe_name_from(name),
+            iblks    : [0; SNAP_IBLK],
+            imap     : [0; SNAP_IMAP],
+        };
+        for k in 0..self.sup.iblk_nb {
+            let blk = match self.read_blk(self.sup.inodes + k as u32) {
+                Ok(blk) => blk,
+                Err(err) => return Some(err),
+            };
+            snap.iblks[k] = match self.alloc_blk() {
+                Ok(ofs) => ofs,
+                Err(err) => return Some(err),
+            };
+            if let Some(err) = self.write_blk(blk, snap.iblks[k]) {return Some(err)};
+        }
+        snap.imap.copy_from_slice(&self.imap.bmap[..SNAP_IMAP]);
+
+        // the snapshot exists once it is in the table : the counts go first
+        if let Some(err) = self.write_tbls() {return Some(err)};
+        snaps[idx] = snap;
+        self.write_snaps(&snaps)
+    }
+
+    pub fn snapshot_list(&mut self) -> Result<Format,FsErr> {
+        let mut fmt = Vec::new();
+        for snap in self.read_snaps()? {
+            if snap.name_len == 0 {continue}
+            fmt.extend(snap.name().chars());
+            fmt.push('\n');
+        }
+        Ok(fmt)
+    }
+
+    pub fn snapshot_delete(&mut self, name : &str) -> Option<FsErr> {
+        let _op = self.hd.enter("snapshot_delete");
+        let mut snaps = match self.read_snaps() {
+            Ok(snaps) => snaps,
+            Err(err) => return Some(err),
+        };
+        let idx = match Self::find_snap(&snaps, name) {
+            Ok(idx) => idx,
+            Err(err) => return Some(err),
+        };
+
+        // drop the references of the snapshot, then its inode table
+        for iid in snaps[idx].inodes() {
+            let inode = match self.read_snap_inode(&snaps[idx], iid) {
+                Ok(inode) => inode,
+                Err(err) => return Some(err),
+            };
+            if let Some(err) = self.free_file(&inode) {return Some(err)};
+        }
+        for &blk in snaps[idx].iblks.iter().filter(|&&blk| blk != 0) {
+            self.free_blk(blk);
+        }
+
+        snaps[idx].name_len = 0;
+        snaps[idx].iblks = [0; SNAP_IBLK];
+        if let Some(err) = self.write_snaps(&snaps) {return Some(err)};
+        self.write_tbls()
+    }
+
+    // the live file system goes back to the snapshot (which is kept)
+    pub fn snapshot_restore(&mut self, name : &str) -> Option<FsErr> {
+        let _op = self.hd.enter("snapshot_restore");
+        let snaps = match self.read_snaps() {
+            Ok(snaps) => snaps,
+            Err(err) => return Some(err),
+        };
+        let snap = &snaps[match Self::find_snap(&snaps, name) {
+            Ok(idx) => idx,
+            Err(err) => return Some(err),
+        }];
+
+        // drop the live tree
+        for iid in self.live_inodes() {
+            let inode = match self.read_inode(iid) {
+                Ok(inode) => inode,
+                Err(err) => return Some(err),
+            };
+            if let Some(err) = self.free_file(&inode) {return Some(err)};
+        }
+
+        // copy the inode table of the snapshot and share its blocks
+        for k in 0..self.sup.iblk_nb {
+            let blk = match self.read_blk(snap.iblks[k]) {
+                Ok(blk) => blk,
+                Err(err) => return Some(err),
+            };
+            if let Some(err) = self.write_blk(blk, self.sup.inodes + k as u32) {return Some(err)};
+        }
+        self.imap.bmap = [0;BLK_SIZE];
+        self.imap.bmap[..SNAP_IMAP].copy_from_slice(&snap.imap);
+        if let Some(err) = self.share_live() {return Some(err)};
+        self.write_tbls()
+    }
+
+    // deletions move the entries to /.trash (created now) or are final
+    pub fn set_trash(&mut self, on : bool) -> Option<FsErr> {
+        let _op = self.hd.enter("set_trash");
+        if on {
+            if let Err(err) = self.trash_dir(true) {return Some(err)};
+        }
+        self.sup.trash = on as u32;
+        self.write_super()
+    }
+
+    // the entries of /.trash with their original paths
+    pub fn trash_list(&mut self) -> Result<Format,FsErr> {
+        let _op = self.hd.enter("trash_list");
+        let trash = self.trash_dir(false)?.ok_or(FsErr::NoTrash)?;
+        let inode = self.read_inode(trash.desc.iid)?;
+        let attrs = self.read_xattrs(&inode)?;
+        let mut fmt = Vec::new();
+        for fd in trash.desc_tbl[2..].iter().filter(|fd| fd.iid != 0) {
+            let name : String = fd.name[..fd.name_len].iter().collect();
+            let origin = match attrs.iter().find(|(n, _)| *n == name) {
+                Some((_, path)) => path.iter().map(|&b| b as char).collect(),
+                None => "(unknown)".to_string(),
+            };
+            fmt.extend(format!("{name} : {origin}\n").chars());
+        }
+        Ok(fmt)
+    }
+
+    // put a trashed entry back at its original path, or at path
+    pub fn trash_restore(&mut self, cur: &Fdesc, name : &str, path : Option<&str>) -> Option<FsErr> {
+        let _op = self.hd.enter("trash_restore");
+        let mut trash = match self.trash_dir(false) {
+            Ok(Some(trash)) => trash,
+            Ok(None) => return Some(FsErr::NoTrash),
+            Err(err) => return Some(err),
+        };
+        let idx = match trash.find_file(name) {
+            Ok(idx) => idx,
+            Err(err) => return Some(err),
+        };
+        if idx < 2 {return Some(FsErr::Occuped)};
+        let path = match path {
+            Some(path) => path.to_string(),
+            None => {
+                let attrs = match self.read_inode(trash.desc.iid).and_then(|inode| self.read_xattrs(&inode)) {
+                    Ok(attrs) => attrs,
+                    Err(err) => return Some(err),
+                };
+                match attrs.into_iter().find(|(n, _)| n == name) {
+                    Some((_, path)) => path.iter().map(|&b| b as char).collect(),
+                    None => return Some(FsErr::NoOrigin),
+                }
+            },
+        };
+        let (mut dir, new_name) = match self.chase(cur, &path) {
+            Ok(res) => res,
+            Err(err) => return Some(err),
+        };
+        if dir.desc.iid == trash.desc.iid {return Some(FsErr::InvalidName)};
+        if let Some(err) = self.check_unlocked(dir.desc.iid) {return Some(err)};
+        if dir.find_file(new_name).is_ok() {return Some(FsErr::FileExist)};
+        if dir.capacity >= FDESC_PER_BLK {return Some(FsErr::DirFull)};
+        let new_name = match name_from(new_name) {
+            Ok(_) if new_name.is_empty() => return Some(FsErr::InvalidName),
+            Ok(chars) => (new_name.len(), chars),
+            Err(err) => return Some(err),
+        };
+
+        // the tree joins the quota of its new directory
+        let iid = trash.desc_tbl[idx].iid;
+        let (from, to) = match (self.read_inode(iid), self.read_inode(dir.desc.iid)) {
+            (Ok(moved), Ok(dest)) => (moved.qid, dest.qid),
+            (Err(err), _) | (_, Err(err)) => return Some(err),
+        };
+        if from != to {
+            let (blocks, inodes) = match self.tree_usage(iid, from) {
+                Ok(usage) => usage,
+                Err(err) => return Some(err),
+            };
+            if let Some(err) = self.check_quota(to, blocks, inodes) {return Some(err)};
+        }
+
+        let free = match dir.find_free() {
+            Ok(free) => free,
+            Err(err) => return Some(err),
+        };
+        dir.desc_tbl[free] = Fdesc {
+            name_len : new_name.0,
+            name     : new_name.1,
+            iid,
+        };
+        dir.capacity += 1;
+        if let Some(err) = self.write_dir(&dir) {return Some(err)};
+        trash.desc_tbl[idx] = EMPTY_FILE;
+        trash.capacity -= 1;
+        if let Some(err) = self.write_dir(&trash) {return Some(err)};
+        if let Ok(FType::Dir) = self.read_inode(iid).map(|inode| inode.ftype) {
+            if let Some(err) = self.reparent(iid, dir.desc.iid) {return Some(err)};
+        }
+        if from != to {
+            if let Some(err) = self.set_qid(iid, from, to) {return Some(err)};
+        }
+        self.set_origin(&trash, name, None)
+    }
+
+    // delete the entries of /.trash for good
+    pub fn trash_empty(&mut self) -> Option<FsErr> {
+        let _op = self.hd.enter("trash_empty");
+        let mut trash = match self.trash_dir(false) {
+            Ok(Some(trash)) => trash,
+            Ok(None) => return Some(FsErr::NoTrash),
+            Err(err) => return Some(err),
+        };
+        let entries : Vec<(String, u32)> = trash.desc_tbl[2..].iter()
+            .filter(|fd| fd.iid != 0)
+            .map(|fd| (fd.name[..fd.name_len].iter().collect(), fd.iid))
+            .collect();
+        for (name, iid) in entries {
+            let err = match self.read_inode(iid) {
+                Ok(Inode {ftype : FType::Dir, ..}) => self.rmdir__(&mut trash, &name),
+                Ok(_) => self.rm__(&mut trash, &name),
+                Err(err) => Some(err),
+            };
+            if let Some(err) = err {return Some(err)};
+        }
+        let mut inode = match self.read_inode(trash.desc.iid) {
+            Ok(inode) => inode,
+            Err(err) => return Some(err),
+        };
+        if let Some(err) = self.write_xattrs(&mut inode, &[]) {return Some(err)};
+        self.write_tbls()
+    }
+
+    // best-effort recovery of the files deleted for good : a freed inode still
+    // intact in the inode table, whose blocks are all still free, comes back
+    // into /.trash (without original path)
+    pub fn undelete(&mut self) -> Result<Format,FsErr> {
+        let _op = self.hd.enter("undelete");
+        let mut trash = self.trash_dir(true)?.ok_or(FsErr::NoTrash)?;
+        let qid = self.read_inode(trash.desc.iid)?.qid;
+        let (mut found, mut lost) = (0, 0);
+        for iid in 1..(self.sup.iblk_nb*INODE_PER_BLK) as u32 {
+            if self.imap.is_free(iid as usize) {continue}; // in use
+            let mut inode = self.read_inode(iid)?;
+            if inode.id != iid || inode.size > MAX_FILE_BLK {continue}; // never used
+            if let FType::Dir = inode.ftype {continue};
+            let datas = self.sup.datas..self.sup.datas + self.sup.dblk_nb as u32;
+            let blks = match self.file_blks(&inode) {
+                Ok(blks) if blks.iter().all(|blk| datas.contains(blk)) => blks,
+                _ => {lost += 1; continue},
+            };
+            let mut dids : Vec<usize> = blks.iter().map(|&blk| (blk - self.sup.datas) as usize).collect();
+            dids.sort();
+            dids.dedup();
+            if dids.len() != blks.len() || dids.iter().any(|&did| self.dmap.is_free(did)) {lost += 1; continue};
+            if trash.capacity >= FDESC_PER_BLK {return Err(FsErr::TrashFull)};
+
+            self.imap.unfree(iid as usize);
+            for did in dids {
+                self.dmap.unfree(did);
+                self.refs.cnt[did] = 1;
+            }
+            inode.qid = qid;
+            if let Some(err) = self.write_inode(&inode) {return Err(err)};
+            let name = iid.to_string();
+            let free = trash.find_free()?;
+            trash.desc_tbl[free] = Fdesc {
+                name_len : name.len(),
+                name     : unsafe_name_from(&name),
+                iid,
+            };
+            trash.capacity += 1;
+            found += 1;
+        }
+        if let Some(err) = self.write_tbls() {return Err(err)};
+        if let Some(err) = self.write_dir(&trash) {return Err(err)};
+        Ok(format!("{found} files recovered into /{TRASH_NAME}, {lost} lost (their blocks are used again)\n").chars().collect())
+    }
+
+    pub fn mark_cmd(&mut self) {
+        self.hd.mark_cmd();
+    }
+
+    pub fn set_trace(&mut self, trace : bool) {
+        self.hd.set_trace(trace);
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.hd.is_tracing()
+    }
+
+    // I/O statistics per disk region, since mount or for the last command
+    pub fn iostat(&mut self, last : bool) -> Format {
+        let stats = if last {self.hd.last_stats()} else {self.hd.stats()};
+        // statistics ranges are exactly one block wide
+        let regions = [
+            ("super",  0,                          1),
+            ("imap",   self.sup.imap as usize,   self.sup.imap_sz),
+            ("dmap",   self.sup.dmap as usize,   self.sup.dmap_sz),
+            ("inodes", self.sup.inodes as usize, self.sup.iblk_nb),
+            ("datas",  self.sup.datas as usize,  self.sup.dblk_nb),
+        ];
+
+        let mut res = format!("{:<8}{:>8}{:>8}{:>10}{:>10}\n", "region", "reads", "writes", "rbytes", "wbytes");
+        for (name, start, len) in regions {
+            let count = stats.sum(start, start+len);
+            res += &format!("{:<8}{:>8}{:>8}{:>10}{:>10}\n", name,
+                count.reads, count.writes, count.read_bytes(), count.write_bytes());
+        }
+        let total = stats.total();
+        res += &format!("{:<8}{:>8}{:>8}{:>10}{:>10}\n", "total",
+            total.reads, total.writes, total.read_bytes(), total.write_bytes());
+        res.chars().collect()
+    }
+
+    pub fn get_home_fdesc(&mut self) -> Fdesc {
+        return Fdesc {
+            name_len : 1,
+            name     : unsafe_name_from("/"),
+            iid : self.sup.root.id
+        };
+    }
+
+    // absolute path of the current directory
+    pub fn pwd(&mut self, cur : &Fdesc) -> Result<String,FsErr> {
+        self.path_of(cur.iid)
+    }
+
+    pub fn cd(&mut self, cur : &Fdesc, path: &str) -> Result<Fdesc,FsErr>{
+        let _op = self.hd.enter("cd");
+        let path = Path::from(path);
+        if path.abs {
+            let root = self.read_dir(self.sup.root.id)?.desc;
+            return self.cd(&root,path.next)
+        }
+
+        let cur = self.read_dir(cur.iid)?;
+        if path.next == ""  {
+            let fd = cur.desc_tbl[cur.find_file(path.cur)?].copy();
+            match self.read_inode(fd.iid)?.ftype {
+                FType::Dir => return Ok(fd),
+                _ => return Err(FsErr::NoDirectory)
+            }
+        }
+        
+        let next = &cur.desc_tbl[cur.find_file(path.cur)?];
+        return self.cd(next,path.next)
+    }
+
+    pub fn mv(&mut self, cur : &Fdesc, old_path : &str, new_path : &str) -> Option<FsErr>{
+        let _op = self.hd.enter("mv");
+        fn _mv_(fs: &mut Fs, old_dir : &mut Dir, new_dir : &mut Dir, old_name : &str, new_name : &str) -> Option<FsErr>{
+            // take and modify the file descriptor
+            let old_idx = match old_dir.find_file(old_name) {
+                Ok(idx) => idx,
+                Err(err) => return Some(err)
+            };
+            let mut fd = old_dir.desc_tbl[old_idx].copy();
+            if is_snap(old_dir.desc.iid) || is_snap(new_dir.desc.iid) || is_snap(fd.iid) {
+                return Some(FsErr::ReadOnly)
+            }
+            if let Some(err) = fs.check_unlocked(old_dir.desc.iid) {return Some(err)};
+            if let Some(err) = fs.check_unlocked(new_dir.desc.iid) {return Some(err)};
+            fd.name = match name_from(new_name){
+                Ok(name) => name,
+                Err(err) => return Some(err)
+            };
+            fd.name_len = new_name.len();
+
+            // case old_dir == new_dir : cannot have 2 fresh desc !
+            if old_dir.desc.iid == new_dir.desc.iid {
+                new_dir.desc_tbl[old_idx] = fd;
+                if let Some(err) = fs.write_dir(new_dir) {return Some(err)};
+                return None
+            }
+
+            // the moved tree joins the quota of its new directory (unless it has its own)
+            let (from, to) = match (fs.read_inode(fd.iid), fs.read_inode(new_dir.desc.iid)) {
+                (Ok(moved), Ok(dest)) => (moved.qid, dest.qid),
+                (Err(err), _) | (_, Err(err)) => return Some(err),
+            };
+            let own = match fs.read_quotas() {
+                Ok(quotas) => from != 0 && quotas[from as usize - 1].root == fd.iid,
+                Err(err) => return Some(err),
+            };
+            let requota = from != to && !own;
+            if requota {
+                let (blocks, inodes) = match fs.tree_usage(fd.iid, from) {
+                    Ok(usage) => usage,
+                    Err(err) => return Some(err),
+                };
+                if let Some(err) = fs.check_quota(to, blocks, inodes) {return Some(err)};
+            }
+
+            // find a new emplacement inside the new directory
+            let new_idx = match new_dir.find_free() {
+                Ok(idx) => idx,
+                Err(err) => return Some(err)
+            };
+            new_dir.capacity += 1;
+            new_dir.desc_tbl[new_idx] = fd;
+            if let Some(err) = fs.write_dir(new_dir) {return Some(err)}
+
+            // remove the file from the old directory
+            old_dir.desc_tbl[old_idx] = EMPTY_FILE;
+            old_dir.capacity -= 1;
+            if let Some(err) = fs.write_dir(old_dir) {return Some(err)}
+            if requota {return fs.set_qid(new_dir.desc_tbl[new_idx].iid, from, to)};
+            return None
+        }
+
+        // Find old directory and old name
+        fn _chassing1_<'a,'b>(fs : &'b mut Fs, cur: &'b Fdesc, path : &'a str) -> Result<(Dir,&'a str),FsErr>{
+            let path = Path::from(path);
+            if path.abs {
+                let root = fs.read_dir(fs.sup.root.id)?.desc;
+                return _chassing1_(fs,&root, path.next)
+            }
+
+            let cur = fs.read_dir(cur.iid)?;
+            if path.next == "" {return Ok((cur, path.cur))}
+            let next = &cur.desc_tbl[cur.find_file(path.cur)?];
+            return _chassing1_(fs,next,path.next)
+        }
+
+        // Find new directory and optionnal new name
+        fn _chassing2_<'a,'b>(fs : &'b mut Fs, cur: &'b Fdesc, path : &'a str) -> Result<(Dir,Option<&'a str>),FsErr>{
+            let path = Path::from(path);
+            if path.abs {
+                let root = fs.read_dir(fs.sup.root.id)?.desc;
+                return _chassing2_(fs,&root, path.next)
+            }
+
+            let cur = fs.read_dir(cur.iid)?;
             if path.next == "" {
                 match cur.find_file(path.cur) {
                     Ok(idx) => {let dir = fs.read_dir(cur.desc_tbl[idx].iid)?; return Ok((dir, None))},
@@ -1166,7 +3036,47 @@ impl<'a> Fs<'a> {
         return _mv_(self, &mut old_dir, &mut new_dir, old_name, new_name)
     }
 
+    // copy a regular file with its compression & its extended attributes,
+    // into a directory under the same name
+    pub fn cp(&mut self, cur : &Fdesc, src : &str, dest : &str) -> Option<FsErr> {
+        let _op = self.hd.enter("cp");
+        let inode = match self.find_inode(cur, src) {
+            Ok(inode) => inode,
+            Err(err) => return Some(err),
+        };
+        if let FType::Dir = inode.ftype {return Some(FsErr::ReadDir)};
+        let data : Format = match self.read_file(&inode) {
+            Ok(data) => data.iter().map(|&b| b as char).collect(),
+            Err(err) => return Some(err),
+        };
+        let attrs = match self.read_xattrs(&inode) {
+            Ok(attrs) => attrs,
+            Err(err) => return Some(err),
+        };
+
+        let dest = match self.find_inode(cur, dest) {
+            Ok(target) if matches!(target.ftype, FType::Dir) => {
+                format!("{}/{}", dest.trim_end_matches('/'), src.rsplit('/').next().unwrap_or(src))
+            },
+            _ => dest.to_string(),
+        };
+        if self.find_inode(cur, &dest).is_err() {
+            if let Some(err) = self.touch(cur, &dest) {return Some(err)};
+        }
+        if let Some(err) = self.write(cur, &dest, &data) {return Some(err)};
+        if inode.flags & FLAG_COMPR != 0 {
+            if let Some(err) = self.chattr(cur, &dest, true) {return Some(err)};
+        }
+        let mut copy = match self.find_inode(cur, &dest) {
+            Ok(copy) => copy,
+            Err(err) => return Some(err),
+        };
+        if let Some(err) = self.write_xattrs(&mut copy, &attrs) {return Some(err)};
+        self.write_tbls()
+    }
+
     pub fn mkdir(&mut self, cur: &Fdesc, path : &str) -> Option<FsErr> {
+        let _op = self.hd.enter("mkdir");
 
         let path = Path::from(path);
         if path.abs {
@@ -1194,6 +3104,7 @@ impl<'a> Fs<'a> {
     }
 
     pub fn rmdir(&mut self, cur: &Fdesc, path : &str) -> Option<FsErr> {
+        let _op = self.hd.enter("rmdir");
 
         let path = Path::from(path);
         if path.abs {
@@ -1226,6 +3137,7 @@ impl<'a> Fs<'a> {
     }
     
     pub fn touch(&mut self, cur: &Fdesc, path : &str) -> Option<FsErr> {
+        let _op = self.hd.enter("touch");
 
         let path = Path::from(path);
         if path.abs {
@@ -1253,6 +3165,7 @@ impl<'a> Fs<'a> {
     }
 
     pub fn rm(&mut self, cur: &Fdesc, path : &str) -> Option<FsErr> {
+        let _op = self.hd.enter("rm");
 
         let path = Path::from(path);
         if path.abs {
@@ -1281,6 +3194,7 @@ impl<'a> Fs<'a> {
     }
 
     pub fn ls(&mut self, cur: &Fdesc, path : &str) -> Result<Format,FsErr> {
+        let _op = self.hd.enter("ls");
 
         let path = Path::from(path);
         if path.abs {
@@ -1297,7 +3211,33 @@ impl<'a> Fs<'a> {
         
     }
 
+    // the names of a directory (without . and ..), each one telling if it is a directory
+    pub fn entries(&mut self, cur: &Fdesc, path : &str) -> Result<Vec<(String, bool)>,FsErr> {
+        let _op = self.hd.enter("entries");
+        let path = Path::from(path);
+        if path.abs {
+            let root = self.read_dir(self.sup.root.id)?.desc;
+            return self.entries(&root, path.next)
+        }
+
+        let cur = self.read_dir(cur.iid)?;
+        if path.cur.is_empty() {
+            let mut entries = Vec::new();
+            for fd in cur.desc_tbl.iter() {
+                if fd.iid == 0 || self.is_hidden(&cur, fd) {continue}
+                let name : String = fd.name[..fd.name_len].iter().collect();
+                if name == "." || name == ".." {continue}
+                let dir = matches!(self.read_inode(fd.iid)?.ftype, FType::Dir);
+                entries.push((name, dir));
+            }
+            return Ok(entries)
+        }
+        let next = &cur.desc_tbl[cur.find_file(path.cur)?];
+        self.entries(next, path.next)
+    }
+
     pub fn write(&mut self, cur: &Fdesc, path : &str, data : &Format) -> Option<FsErr> {
+        let _op = self.hd.enter("write");
         let path = Path::from(path);
         if path.abs {
             let root = match self.read_dir(self.sup.root.id) {
@@ -1325,6 +3265,7 @@ impl<'a> Fs<'a> {
     }
 
     pub fn cat(&mut self, cur: &Fdesc, path : &str) -> Result<Format,FsErr> {
+        let _op = self.hd.enter("cat");
         let path = Path::from(path);
         if path.abs {
             let root = self.read_dir(self.sup.root.id)?.desc;
@@ -1340,47 +3281,410 @@ impl<'a> Fs<'a> {
         return self.cat(next,path.next)
     }
 
-    pub fn grep(&mut self, cur: &Fdesc, path: &str, pattern: &str) -> Result<Format,FsErr> {
-        // get the content & add a space a the end
-        let mut content : Format = self.cat(cur, path)?;
-        content.push(' ');
-        // convert pattern to Format
+    pub fn chattr(&mut self, cur: &Fdesc, path : &str, compr : bool) -> Option<FsErr> {
+        let _op = self.hd.enter("chattr");
+        let path = Path::from(path);
+        if path.abs {
+            let root = match self.read_dir(self.sup.root.id) {
+                Ok(dir) => dir.desc,
+                Err(err) => return Some(err),
+            };
+            return self.chattr(&root, path.next, compr)
+        }
+
+        let mut cur = match self.read_dir(cur.iid) {
+            Ok(dir) => dir,
+            Err(err) => return Some(err),
+        };
+        if path.next.is_empty() {
+            return self.chattr__(&mut cur, path.cur, compr)
+        }
+        let next = &cur.desc_tbl[
+            match cur.find_file(path.cur){
+                Ok(i) => i,
+                Err(err) => return Some(err),
+            }
+        ];
+        self.chattr(next, path.next, compr)
+    }
+
+    pub fn stat(&mut self, cur: &Fdesc, path : &str) -> Result<Format,FsErr> {
+        let _op = self.hd.enter("stat");
+        let path = Path::from(path);
+        if path.abs {
+            let root = self.read_dir(self.sup.root.id)?.desc;
+            return self.stat(&root,path.next)
+        }
+
+        let cur = self.read_dir(cur.iid)?;
+        if path.next.is_empty() {
+            return self.stat_file(&cur, path.cur)
+        }
+
+        let next = &cur.desc_tbl[cur.find_file(path.cur)?];
+        self.stat(next,path.next)
+    }
+
+    // directory containing the last element of a path, and its name
+    fn chase<'p>(&mut self, cur: &Fdesc, path : &'p str) -> Result<(Dir,&'p str),FsErr> {
+        let path = Path::from(path);
+        if path.abs {
+            let root = self.read_dir(self.sup.root.id)?.desc;
+            return self.chase(&root, path.next)
+        }
+
+        let cur = self.read_dir(cur.iid)?;
+        if path.next.is_empty() {return Ok((cur, path.cur))}
+        let next = &cur.desc_tbl[cur.find_file(path.cur)?];
+        self.chase(next, path.next)
+    }
+
+    // write data at offset in a file, the skipped blocks are holes
+    pub fn write_at(&mut self, cur: &Fdesc, path : &str, offset : usize, data : &Format) -> Option<FsErr> {
+        let _op = self.hd.enter("write_at");
+        let (mut dir, name) = match self.chase(cur, path) {
+            Ok(res) => res,
+            Err(err) => return Some(err),
+        };
+        let data : Vec<u8> = data.iter().map(|&c| c as u8).collect();
+        self.write_at__(&mut dir, name, offset, &data)
+    }
+
+    // shrink or extend (with a hole) a file to len bytes
+    pub fn truncate(&mut self, cur: &Fdesc, path : &str, len : usize) -> Option<FsErr> {
+        let _op = self.hd.enter("truncate");
+        let (mut dir, name) = match self.chase(cur, path) {
+            Ok(res) => res,
+            Err(err) => return Some(err),
+        };
+        self.truncate__(&mut dir, name, len)
+    }
+
+    pub fn setxattr(&mut self, cur: &Fdesc, path : &str, name : &str, value : &Format) -> Option<FsErr> {
+        let _op = self.hd.enter("setxattr");
+        let mut inode = match self.find_inode(cur, path) {
+            Ok(inode) => inode,
+            Err(err) => return Some(err),
+        };
+        if is_snap(inode.id) {return Some(FsErr::ReadOnly)};
+        if !is_xattr_name(name) {return Some(FsErr::InvalidName)};
+        let mut attrs = match self.read_xattrs(&inode) {
+            Ok(attrs) => attrs,
+            Err(err) => return Some(err),
+        };
+        let value : Vec<u8> = value.iter().map(|&c| c as u8).collect();
+        match attrs.iter_mut().find(|(n, _)| n == name) {
+            Some(attr) => attr.1 = value,
+            None => attrs.push((name.to_string(), value)),
+        }
+        if let Some(err) = self.write_xattrs(&mut inode, &attrs) {return Some(err)};
+        self.write_tbls()
+    }
+
+    pub fn getxattr(&mut self, cur: &Fdesc, path : &str, name : &str) -> Result<Format,FsErr> {
+        let _op = self.hd.enter("getxattr");
+        let inode = self.find_inode(cur, path)?;
+        let attrs = self.read_xattrs(&inode)?;
+        let (_, value) = attrs.iter().find(|(n, _)| n == name).ok_or(FsErr::NoXattr)?;
+        let mut res : Format = value.iter().map(|&b| b as char).collect();
+        res.push('\n');
+        Ok(res)
+    }
+
+    pub fn listxattr(&mut self, cur: &Fdesc, path : &str) -> Result<Format,FsErr> {
+        let _op = self.hd.enter("listxattr");
+        let inode = self.find_inode(cur, path)?;
+        let mut res = Vec::new();
+        for (name, _) in self.read_xattrs(&inode)? {
+            res.extend(name.chars());
+            res.push('\n');
+        }
+        Ok(res)
+    }
+
+    pub fn removexattr(&mut self, cur: &Fdesc, path : &str, name : &str) -> Option<FsErr> {
+        let _op = self.hd.enter("removexattr");
+        let mut inode = match self.find_inode(cur, path) {
+            Ok(inode) => inode,
+            Err(err) => return Some(err),
+        };
+        if is_snap(inode.id) {return Some(FsErr::ReadOnly)};
+        let mut attrs = match self.read_xattrs(&inode) {
+            Ok(attrs) => attrs,
+            Err(err) => return Some(err),
+        };
+        let idx = match attrs.iter().position(|(n, _)| n == name) {
+            Some(idx) => idx,
+            None => return Some(FsErr::NoXattr),
+        };
+        attrs.remove(idx);
+        if let Some(err) = self.write_xattrs(&mut inode, &attrs) {return Some(err)};
+        self.write_tbls()
+    }
+
+    // inode at the end of a path
+    fn find_inode(&mut self, cur: &Fdesc, path : &str) -> Result<Inode,FsErr> {
+        let path = Path::from(path);
+        if path.abs {
+            let root = self.read_dir(self.sup.root.id)?.desc;
+            return self.find_inode(&root, path.next)
+        }
+
+        let cur = self.read_dir(cur.iid)?;
+        let name = if path.cur.is_empty() {"."} else {path.cur};
+        let next = &cur.desc_tbl[cur.find_file(name)?];
+        if path.next.is_empty() {return self.read_inode(next.iid)}
+        self.find_inode(next, path.next)
+    }
+
+    // encrypt the (empty) directory at path and everything that will be created inside,
+    // it stays unlocked until the next lock
+    pub fn encrypt(&mut self, cur: &Fdesc, path : &str, pass : &str) -> Option<FsErr> {
+        let _op = self.hd.enter("encrypt");
+        let mut inode = match self.find_inode(cur, path) {
+            Ok(inode) => inode,
+            Err(err) => return Some(err),
+        };
+        if is_snap(inode.id) {return Some(FsErr::ReadOnly)};
+        if let FType::Reg = inode.ftype {return Some(FsErr::NoDirectory)};
+        if inode.flags & FLAG_CRYPT != 0 {return Some(FsErr::Encrypted)};
+        let dir = match self.read_dir(inode.id) {
+            Ok(dir) => dir,
+            Err(err) => return Some(err),
+        };
+        if dir.desc_tbl[2..].iter().any(|fd| fd.iid != 0 && fd.iid != SNAPS_IID) {
+            return Some(FsErr::DirNotEmpty)
+        }
+
+        inode.salt = crypt::random_salt();
+        let key = crypt::derive_key(pass, &inode.salt);
+        inode.check = crypt::key_check(&key);
+        inode.flags |= FLAG_CRYPT;
+        inode.kid = inode.id;
+        if let Some(err) = self.write_inode(&inode) {return Some(err)};
+        self.keys.insert(inode.kid, key);
+        None
+    }
+
+    // unlock the encrypted tree containing path
+    pub fn unlock(&mut self, cur: &Fdesc, path : &str, pass : &str) -> Option<FsErr> {
+        let _op = self.hd.enter("unlock");
+        let inode = match self.find_inode(cur, path) {
+            Ok(inode) => inode,
+            Err(err) => return Some(err),
+        };
+        if inode.flags & FLAG_CRYPT == 0 {return Some(FsErr::NotEncrypted)};
+        // the key is kept by the root of the tree (in the same snapshot)
+        let root = match self.read_inode(inode.kid | (inode.id & !IID_MASK)) {
+            Ok(root) => root,
+            Err(err) => return Some(err),
+        };
+        let key = crypt::derive_key(pass, &root.salt);
+        if crypt::key_check(&key) != root.check {return Some(FsErr::BadPassphrase)};
+        self.keys.insert(inode.kid, key);
+        None
+    }
+
+    // lock the encrypted tree containing path (every tree without path)
+    pub fn lock(&mut self, cur: &Fdesc, path : Option<&str>) -> Option<FsErr> {
+        let _op = self.hd.enter("lock");
+        let path = match path {
+            Some(path) => path,
+            None => {self.keys.clear(); return None},
+        };
+        let inode = match self.find_inode(cur, path) {
+            Ok(inode) => inode,
+            Err(err) => return Some(err),
+        };
+        if inode.flags & FLAG_CRYPT == 0 {return Some(FsErr::NotEncrypted)};
+        self.keys.remove(&inode.kid);
+        None
+    }
+
+    // limit the blocks and inodes used by the tree of the directory at path
+    // (0 for no limit, both 0 remove the quota), a nested tree only counts
+    // for its own quota
+    pub fn setquota(&mut self, cur: &Fdesc, path : &str, blocks : usize, inodes : usize) -> Option<FsErr> {
+        let _op = self.hd.enter("setquota");
+        let inode = match self.find_inode(cur, path) {
+            Ok(inode) => inode,
+            Err(err) => return Some(err),
+        };
+        if is_snap(inode.id) {return Some(FsErr::ReadOnly)};
+        if let FType::Reg = inode.ftype {return Some(FsErr::NoDirectory)};
+        let mut quotas = match self.read_quotas() {
+            Ok(quotas) => quotas,
+            Err(err) => return Some(err),
+        };
+
+        // the directory is already the root of a quota
+        if inode.qid != 0 && quotas[inode.qid as usize - 1].root == inode.id {
+            let k = inode.qid as usize - 1;
+            if blocks == 0 && inodes == 0 {
+                let parent = match self.read_dir(inode.id) {
+                    Ok(dir) => dir.desc_tbl[1].iid,
+                    Err(err) => return Some(err),
+                };
+                let to = if parent == inode.id {0} else {
+                    match self.read_inode(parent) {
+                        Ok(parent) => parent.qid,
+                        Err(err) => return Some(err),
+                    }
+                };
+                if let Some(err) = self.set_qid(inode.id, inode.qid, to) {return Some(err)};
+                quotas[k].root = 0;
+            }
+            quotas[k].blocks = blocks;
+            quotas[k].inodes = inodes;
+            return self.write_quotas(&quotas)
+        }
+        if blocks == 0 && inodes == 0 {return None};
+
+        let mut free = None;
+        for k in 0..QUOTA_PER_BLK {
+            match self.quota_alive(&quotas, k as u16 + 1) {
+                Ok(false) => {free = Some(k); break},
+                Ok(true) => (),
+                Err(err) => return Some(err),
+            }
+        }
+        let k = match free {
+            Some(k) => k,
+            None => return Some(FsErr::QuotaFull),
+        };
+        if self.sup.quota == 0 {
+            self.sup.quota = match self.alloc_blk() {
+                Ok(blk) => blk,
+                Err(err) => return Some(err),
+            };
+            if let Some(err) = self.write_tbls() {return Some(err)};
+            if let Some(err) = self.write_super() {return Some(err)};
+        }
+        quotas[k] = Quota {root : inode.id, blocks, inodes};
+        if let Some(err) = self.write_quotas(&quotas) {return Some(err)};
+        self.set_qid(inode.id, inode.qid, k as u16 + 1)
+    }
+
+    // usage and limits of every quota, or of the one of the tree containing path
+    pub fn quota(&mut self, cur: &Fdesc, path : Option<&str>) -> Result<Format,FsErr> {
+        let _op = self.hd.enter("quota");
+        let quotas = self.read_quotas()?;
+        let qids : Vec<u16> = match path {
+            Some(path) => {
+                let qid = self.find_inode(cur, path)?.qid;
+                if qid == 0 {return Ok("no quota\n".chars().collect())};
+                vec![qid]
+            },
+            None => (1..=QUOTA_PER_BLK as u16).collect(),
+        };
+        let limit = |n : usize| if n == 0 {"no limit".to_string()} else {format!("limit {n}")};
         let mut fmt = Vec::new();
-        let mut buff = pattern.chars();
-        while let Some(c) = buff.next() {
-            fmt.push(c);
-        }
-        let pattern = fmt;
-
-        // begining of the research
-        let mut start = 0; let mut end = 0;
-        let mut res = vec![];
-        while end < content.len() {
-            if (content[end] == ' ') || (content[end] == '\n') {start = end+1;}
-
-            else if content[end] == pattern[0] {
-                if end + pattern.len() > content.len() {break}
-                let mut k = 1;
-                while k < pattern.len() {
-                    if content[end+k] != pattern[k] {break}
-                    k += 1;
+        for qid in qids {
+            if !self.quota_alive(&quotas, qid)? {continue};
+            let quota = &quotas[qid as usize - 1];
+            let (blocks, inodes) = self.quota_usage(qid)?;
+            let line = format!("{} : {blocks} blocks ({}), {inodes} inodes ({})\n",
+                self.path_of(quota.root)?, limit(quota.blocks), limit(quota.inodes));
+            fmt.extend(line.chars());
+        }
+        Ok(fmt)
+    }
+
+    pub fn grep(&mut self, cur: &Fdesc, path: &str, pattern: &str) -> Result<Format,FsErr> {
+        let _op = self.hd.enter("grep");
+        let content = self.cat(cur, path)?;
+        Ok(grep_words(&content, pattern))
+    }
+}
+
+// the words of content holding pattern, one per line
+pub fn grep_words(content : &Format, pattern : &str) -> Format {
+    if pattern.is_empty() {return vec![]}
+    // add a space a the end
+    let mut content = content.clone();
+    content.push(' ');
+    // convert pattern to Format
+    let mut fmt = Vec::new();
+    let mut buff = pattern.chars();
+    while let Some(c) = buff.next() {
+        fmt.push(c);
+    }
+    let pattern = fmt;
+
+    // begining of the research
+    let mut start = 0; let mut end = 0;
+    let mut res = vec![];
+    while end < content.len() {
+        if (content[end] == ' ') || (content[end] == '\n') {start = end+1;}
+
+        else if content[end] == pattern[0] {
+            if end + pattern.len() > content.len() {break}
+            let mut k = 1;
+            while k < pattern.len() {
+                if content[end+k] != pattern[k] {break}
+                k += 1;
+            }
+            if k == pattern.len() {
+                end += k;
+                while end < content.len() {
+                    if (content[end] == ' ') || (content[end] == '\n') {break}
+                    end += 1;
                 }
-                if k == pattern.len() {
-                    end += k;
-                    while end < content.len() {
-                        if (content[end] == ' ') || (content[end] == '\n') {break}
-                        end += 1;
-                    }
-                    for l in start..end{
-                        res.push(content[l])
-                    }
-                    res.push('\n');
-                    start = end+1;
+                for l in start..end{
+                    res.push(content[l])
                 }
+                res.push('\n');
+                start = end+1;
             }
+        }
+
+        end += 1;
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn used_blks(fs : &Fs) -> usize {
+        fs.dmap.count_used(fs.sup.dblk_nb)
+    }
+
+    // a snapshot which does not fit leaves no block shared nor allocated
+    #[test]
+    fn snapshot_near_full() {
+        let mut hd = Hd::new();
+        assert!(Fs::mkfs(&mut hd).is_none());
+        let mut fs = Fs::mount(&mut hd).unwrap();
+        let cur = fs.get_home_fdesc();
+        let data : Format = "ab".repeat(400).chars().collect(); // one block each
+        // room for the reference counts, the snapshots table & all the
+        // copied inode blocks but one : the live blocks get shared first
+        let files = fs.sup.dblk_nb - 1 - (2 + fs.sup.iblk_nb - 1);
+        for k in 0..files {
+            assert!(fs.touch(&cur, &format!("f{k}")).is_none());
+            assert!(fs.write(&cur, &format!("f{k}"), &data).is_none());
+        }
+        let used = used_blks(&fs);
+
+        assert!(matches!(fs.snapshot_create("s"), Some(FsErr::DmapFull)));
+        assert_eq!(used_blks(&fs), used);
+        assert!(fs.refs.cnt.iter().all(|&cnt| cnt <= 1));
+        assert_eq!((fs.sup.rcnt, fs.sup.snaps), (0, 0));
 
-            end += 1;
+        // the blocks of the removed files are freed, the snapshot fits
+        for k in 0..10 {
+            assert!(fs.rm(&cur, &format!("f{k}")).is_none());
         }
-        return Ok(res)
+        assert_eq!(used_blks(&fs), used - 10);
+        assert!(fs.snapshot_create("s").is_none());
+        let used = used_blks(&fs);
+
+        // the image holds the same tables
+        drop(fs);
+        let mut fs = Fs::mount(&mut hd).unwrap();
+        assert_eq!(used_blks(&fs), used);
+        assert!(fs.snapshot_list().unwrap().iter().collect::<String>() == "s\n");
     }
 }
@@ -0,0 +1,219 @@
+/*
+    shell/fs/crypt.rs
+*/
+
+// The primitives of the encrypted directories : SHA-256 (RFC 6234),
+// PBKDF2-HMAC-SHA256 (RFC 8018) to derive a key from a passphrase and
+// the ChaCha20 stream cipher (RFC 8439).
+
+pub const KEY_LEN   : usize = 32;
+pub const SALT_LEN  : usize = 16;
+pub const CHECK_LEN : usize = 16;
+pub const NONCE_LEN : usize = 12;
+const ITERATIONS    : u32 = 4096;   // of PBKDF2
+const SHA_BLK       : usize = 64;
+
+pub type Key = [u8;KEY_LEN];
+
+const K : [u32;64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+fn sha256_blk(h : &mut [u32;8], blk : &[u8]) {
+    let mut w = [0u32;64];
+    for t in 0..16 {
+        w[t] = u32::from_be_bytes([blk[4*t], blk[4*t+1], blk[4*t+2], blk[4*t+3]]);
+    }
+    for t in 16..64 {
+        let s0 = w[t-15].rotate_right(7) ^ w[t-15].rotate_right(18) ^ (w[t-15] >> 3);
+        let s1 = w[t-2].rotate_right(17) ^ w[t-2].rotate_right(19) ^ (w[t-2] >> 10);
+        w[t] = w[t-16].wrapping_add(s0).wrapping_add(w[t-7]).wrapping_add(s1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = *h;
+    for t in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = hh.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[t]).wrapping_add(w[t]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+        hh = g; g = f; f = e;
+        e = d.wrapping_add(t1);
+        d = c; c = b; b = a;
+        a = t1.wrapping_add(t2);
+    }
+    for (x, y) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+        *x = x.wrapping_add(y);
+    }
+}
+
+pub fn sha256(data : &[u8]) -> [u8;32] {
+    let mut h : [u32;8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+    // padding : 1 bit, zeros & the length in bits
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % SHA_BLK != SHA_BLK - 8 {msg.push(0)}
+    msg.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for blk in msg.chunks(SHA_BLK) {
+        sha256_blk(&mut h, blk);
+    }
+    let mut res = [0;32];
+    for k in 0..8 {
+        res[4*k..4*k+4].copy_from_slice(&h[k].to_be_bytes());
+    }
+    res
+}
+
+fn hmac_sha256(key : &[u8], data : &[u8]) -> [u8;32] {
+    let mut pad = [0u8;SHA_BLK];
+    if key.len() > SHA_BLK {pad[..32].copy_from_slice(&sha256(key))}
+    else {pad[..key.len()].copy_from_slice(key)}
+
+    let mut inner : Vec<u8> = pad.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(data);
+    let mut outer : Vec<u8> = pad.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&sha256(&inner));
+    sha256(&outer)
+}
+
+// PBKDF2 with a single output block
+pub fn derive_key(pass : &str, salt : &[u8]) -> Key {
+    let mut msg = salt.to_vec();
+    msg.extend_from_slice(&1u32.to_be_bytes());
+    let mut u = hmac_sha256(pass.as_bytes(), &msg);
+    let mut key = u;
+    for _ in 1..ITERATIONS {
+        u = hmac_sha256(pass.as_bytes(), &u);
+        for k in 0..KEY_LEN {key[k] ^= u[k]}
+    }
+    key
+}
+
+// stored beside the salt to recognize a wrong passphrase
+pub fn key_check(key : &Key) -> [u8;CHECK_LEN] {
+    let mut msg = key.to_vec();
+    msg.extend_from_slice(b"key check");
+    let mut check = [0;CHECK_LEN];
+    check.copy_from_slice(&sha256(&msg)[..CHECK_LEN]);
+    check
+}
+
+pub fn random_salt() -> [u8;SALT_LEN] {
+    use std::io::Read;
+    let mut salt = [0;SALT_LEN];
+    let read = std::fs::File::open("/dev/urandom").and_then(|mut file| file.read_exact(&mut salt));
+    if read.is_err() {
+        // not as good, but still differs from one salt to another
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+        salt.copy_from_slice(&sha256(&now.as_nanos().to_be_bytes())[..SALT_LEN]);
+    }
+    salt
+}
+
+#[inline(always)]
+fn quarter_round(s : &mut [u32;16], a : usize, b : usize, c : usize, d : usize) {
+    s[a] = s[a].wrapping_add(s[b]); s[d] ^= s[a]; s[d] = s[d].rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]); s[b] ^= s[c]; s[b] = s[b].rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]); s[d] ^= s[a]; s[d] = s[d].rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]); s[b] ^= s[c]; s[b] = s[b].rotate_left(7);
+}
+
+fn chacha20_blk(key : &Key, nonce : &[u8;NONCE_LEN], counter : u32) -> [u8;64] {
+    let word = |buff : &[u8], k : usize| u32::from_le_bytes([buff[4*k], buff[4*k+1], buff[4*k+2], buff[4*k+3]]);
+    let mut init = [0u32;16];
+    init[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]); // "expand 32-byte k"
+    for k in 0..8 {init[4+k] = word(key, k)}
+    init[12] = counter;
+    for k in 0..3 {init[13+k] = word(nonce, k)}
+
+    let mut s = init;
+    for _ in 0..10 {
+        quarter_round(&mut s, 0, 4, 8, 12);
+        quarter_round(&mut s, 1, 5, 9, 13);
+        quarter_round(&mut s, 2, 6, 10, 14);
+        quarter_round(&mut s, 3, 7, 11, 15);
+        quarter_round(&mut s, 0, 5, 10, 15);
+        quarter_round(&mut s, 1, 6, 11, 12);
+        quarter_round(&mut s, 2, 7, 8, 13);
+        quarter_round(&mut s, 3, 4, 9, 14);
+    }
+    let mut res = [0;64];
+    for k in 0..16 {
+        res[4*k..4*k+4].copy_from_slice(&s[k].wrapping_add(init[k]).to_le_bytes());
+    }
+    res
+}
+
+// encrypt (or decrypt) data in place, starting at the counter-th 64 bytes
+// block of the key stream
+pub fn chacha20(key : &Key, nonce : &[u8;NONCE_LEN], counter : u32, data : &mut [u8]) {
+    for (k, chunk) in data.chunks_mut(64).enumerate() {
+        let stream = chacha20_blk(key, nonce, counter.wrapping_add(k as u32));
+        for (b, s) in chunk.iter_mut().zip(stream) {
+            *b ^= s;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes : &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn sha256_vectors() {
+        assert_eq!(hex(&sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+    }
+
+    // RFC 4231, cases 1, 2 and 6
+    #[test]
+    fn hmac_vectors() {
+        assert_eq!(hex(&hmac_sha256(&[0x0b;20], b"Hi There")),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7");
+        assert_eq!(hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        assert_eq!(hex(&hmac_sha256(&[0xaa;131], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+    }
+
+    #[test]
+    fn pbkdf2_vector() {
+        assert_eq!(hex(&derive_key("password", b"salt")),
+            "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a");
+    }
+
+    // RFC 8439, 2.4.2
+    #[test]
+    fn chacha20_vector() {
+        let mut key = [0u8;KEY_LEN];
+        for (k, b) in key.iter_mut().enumerate() {*b = k as u8}
+        let nonce = [0, 0, 0, 0, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+        let plain = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+        let mut data = plain.to_vec();
+        chacha20(&key, &nonce, 1, &mut data);
+        assert_eq!(hex(&data), concat!(
+            "6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0b",
+            "f91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d8",
+            "07ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab7793736",
+            "5af90bbf74a35be6b40b8eedf2785e42874d"));
+        chacha20(&key, &nonce, 1, &mut data);
+        assert_eq!(&data[..], &plain[..]);
+    }
+}
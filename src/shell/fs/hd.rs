@@ -2,10 +2,25 @@
     shell/fs/hd.rs
 */
 
+// the bindings of mmap use the linux values of the libc constants
+#[cfg(target_os = "linux")]
+mod mmap;
+mod overlay;
+mod sparse;
+#[cfg(target_os = "linux")]
+pub use mmap::MmapImage;
+pub use overlay::Overlay;
+pub use sparse::SparseImage;
+
 pub const SECT_SIZE : usize = 0x200 ;       // 512 bytes sectors
-pub const HD_SIZE : usize = 64*8*SECT_SIZE; // 512 sectors hard drive (aka 64 blocks disk)
+pub const HD_SIZE : usize = 64*8*SECT_SIZE; // default size : 512 sectors hard drive (aka 64 blocks disk)
+pub const RANGE_SIZE : usize = 8;           // sectors per statistics range (aka 1 block)
 pub type Sector = [u8;SECT_SIZE];
 
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::cell::RefCell;
+
 enum HdState {
     Writing,
     Reading,
@@ -15,42 +30,238 @@ enum HdState {
 #[derive(Debug)] // TODO : remove it
 pub enum HdErr {
     Occuped,
+    OutOfRange,
+    InvalidImage,   // empty, or a bad header of a sparse image
+    NoOverlay,
+    NoResize,
+    ReadOnly,
+    Io(std::io::Error),
+}
+
+// where the sectors of a hard drive actually live
+pub trait Backend {
+    fn sectors(&self) -> usize;                                // logical size of the device (in sectors)
+    fn allocated(&self) -> usize {self.sectors()*SECT_SIZE}    // storage really used on the host (in bytes)
+    fn read_sect(&mut self, sect_nb : usize, sect : &mut Sector) -> Option<HdErr>;
+    fn write_sect(&mut self, sect_nb : usize, sect : &Sector) -> Option<HdErr>;
+    fn flush(&mut self) -> Option<HdErr> {None}
+    fn resize(&mut self, _sectors : usize) -> Option<HdErr> {Some(HdErr::NoResize)} // new logical size
+    fn overlay(&mut self) -> Option<&mut Overlay> {None}       // only for copy-on-write devices
+    fn make_writable(&mut self) -> Option<HdErr> {None}        // a read-only image opened again for writing
+}
+
+// the whole disk kept in memory
+pub struct MemImage {
+    array : Vec<u8>,
+}
+
+impl MemImage {
+    pub fn new(size : usize) -> MemImage {
+        MemImage {
+            array : vec![0; size],
+        }
+    }
+}
+
+impl Backend for MemImage {
+    fn sectors(&self) -> usize {
+        self.array.len()/SECT_SIZE
+    }
+
+    fn read_sect(&mut self, sect_nb : usize, sect : &mut Sector) -> Option<HdErr> {
+        sect.copy_from_slice(&self.array[sect_nb*SECT_SIZE..(sect_nb+1)*SECT_SIZE]);
+        None
+    }
+
+    fn write_sect(&mut self, sect_nb : usize, sect : &Sector) -> Option<HdErr> {
+        self.array[sect_nb*SECT_SIZE..(sect_nb+1)*SECT_SIZE].copy_from_slice(sect);
+        None
+    }
+
+    fn resize(&mut self, sectors : usize) -> Option<HdErr> {
+        self.array.resize(sectors*SECT_SIZE, 0);
+        None
+    }
+}
+
+// reads & writes counters of a sector range
+#[derive(Clone, Copy, Default)]
+pub struct IoCount {
+    pub reads  : u64,
+    pub writes : u64,
+}
+
+impl IoCount {
+    pub fn read_bytes(&self) -> u64 {
+        self.reads * SECT_SIZE as u64
+    }
+
+    pub fn write_bytes(&self) -> u64 {
+        self.writes * SECT_SIZE as u64
+    }
+
+    pub fn add(&mut self, other : &IoCount) {
+        self.reads += other.reads;
+        self.writes += other.writes;
+    }
+}
+
+// one IoCount for each accessed range of RANGE_SIZE sectors
+// (a map rather than a table, to stay small on large disks)
+#[derive(Clone, Default)]
+pub struct IoStats {
+    ranges : BTreeMap<usize, IoCount>,
+}
+
+impl IoStats {
+    fn new() -> IoStats {
+        IoStats::default()
+    }
+
+    fn range_mut(&mut self, range : usize) -> &mut IoCount {
+        self.ranges.entry(range).or_default()
+    }
+
+    // sum of the counters of the ranges in [start, end)
+    pub fn sum(&self, start : usize, end : usize) -> IoCount {
+        let mut total = IoCount::default();
+        for (_, count) in self.ranges.range(start..end) {
+            total.add(count);
+        }
+        total
+    }
+
+    pub fn total(&self) -> IoCount {
+        self.sum(0, usize::MAX)
+    }
+
+    // counters accumulated since the `since` snapshot
+    fn diff(&self, since : &IoStats) -> IoStats {
+        let mut res = IoStats::new();
+        for (&range, count) in &self.ranges {
+            let old = since.ranges.get(&range).copied().unwrap_or_default();
+            if count.reads == old.reads && count.writes == old.writes {continue}
+            res.ranges.insert(range, IoCount {
+                reads  : count.reads - old.reads,
+                writes : count.writes - old.writes,
+            });
+        }
+        res
+    }
+}
+
+// the Fs operation being run, popped from the trace stack when dropped
+pub struct OpGuard {
+    ops : Rc<RefCell<Vec<&'static str>>>,
+}
+
+impl Drop for OpGuard {
+    fn drop(&mut self) {
+        self.ops.borrow_mut().pop();
+    }
 }
 
 pub struct Hd {
-    state : HdState,
-    array : [u8; HD_SIZE],
+    state   : HdState,
+    backend : Box<dyn Backend>,
+
+    stats     : IoStats,      // since the last reset (aka mount)
+    cmd_start : IoStats,      // snapshot taken at the beginning of the current command
+    last      : IoStats,      // activity of the previous command
+    trace     : bool,         // log each dread/dwrite
+    ops       : Rc<RefCell<Vec<&'static str>>>, // stack of the Fs operations being run
 }
 
 impl Hd {
     pub fn new() -> Hd {
+        Hd::from(Box::new(MemImage::new(HD_SIZE)))
+    }
+
+    pub fn from(backend : Box<dyn Backend>) -> Hd {
         Hd {
             state : HdState::Free,
-            array : [0;64*8*SECT_SIZE]
+            backend,
+            stats : IoStats::new(),
+            cmd_start : IoStats::new(),
+            last : IoStats::new(),
+            trace : false,
+            ops : Rc::new(RefCell::new(Vec::new())),
         }
     }
 
-    #[test] // TODO : remove it
+    // map the host image file into memory (created if it does not exist,
+    // unless it is read-only)
+    #[cfg(target_os = "linux")]
+    pub fn mmap(path : &str, size : usize, writable : bool) -> Result<Hd,HdErr> {
+        Ok(Hd::from(Box::new(MmapImage::open(path, size, writable)?)))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn mmap(_path : &str, _size : usize, _writable : bool) -> Result<Hd,HdErr> {
+        Err(HdErr::Io(std::io::ErrorKind::Unsupported.into()))
+    }
+
+    // thin-provisioned image : only written sectors take place on the host
+    pub fn sparse(path : &str, size : usize, writable : bool) -> Result<Hd,HdErr> {
+        Ok(Hd::from(Box::new(SparseImage::open(path, size, writable)?)))
+    }
+
+    // copy-on-write device over base (see Overlay)
+    pub fn with_overlay(base : Hd) -> Hd {
+        Hd::from(Box::new(Overlay::new(base)))
+    }
+
+    #[allow(dead_code)] // TODO : remove it
     pub fn init() -> Hd {
-        let mut hd = Hd {
-            state : HdState::Free,
-            array : [0; 64*8*SECT_SIZE],
-        };
+        let mut hd = Hd::new();
         match std::fs::read("disk") {
-            Ok(vec) => for k in 0..(64*8*SECT_SIZE) {
-                hd.array[k] = vec[k];
+            Ok(vec) => for k in 0..HD_SIZE/SECT_SIZE {
+                let mut sect : Sector = [0;SECT_SIZE];
+                sect.copy_from_slice(&vec[k*SECT_SIZE..(k+1)*SECT_SIZE]);
+                if let Some(err) = hd.backend.write_sect(k, &sect) {panic!("Error during init disk : {err:?}")};
             },
             Err(_) => panic!("Error during init disk"),
         }
         return hd
     }
 
-    #[test] // TODO : remove it
-    pub fn save(self) {
-        std::fs::write("disk",self.array).expect("Error during saving disk");
+    #[allow(dead_code)] // TODO : remove it
+    pub fn save(mut self) {
+        let mut image = Vec::with_capacity(self.sectors()*SECT_SIZE);
+        for k in 0..self.sectors() {
+            let mut sect : Sector = [0;SECT_SIZE];
+            if let Some(err) = self.backend.read_sect(k, &mut sect) {panic!("Error during saving disk : {err:?}")};
+            image.extend_from_slice(&sect);
+        }
+        std::fs::write("disk",image).expect("Error during saving disk");
+    }
+
+    pub fn sectors(&self) -> usize {
+        self.backend.sectors()
+    }
+
+    pub fn allocated(&self) -> usize {
+        self.backend.allocated()
+    }
+
+    pub fn flush(&mut self) -> Option<HdErr> {
+        self.backend.flush()
+    }
+
+    // grow (or cut) the device, the new sectors read as zeros
+    pub fn resize(&mut self, sectors : usize) -> Option<HdErr> {
+        self.backend.resize(sectors)
+    }
+
+    pub fn make_writable(&mut self) -> Option<HdErr> {
+        self.backend.make_writable()
     }
 
-    #[test]
+    pub fn overlay(&mut self) -> Result<&mut Overlay,HdErr> {
+        self.backend.overlay().ok_or(HdErr::NoOverlay)
+    }
+
+    #[allow(dead_code)]
     pub fn display(&mut self, offset : u32) {
         let sect = match self.dread(offset){
             Ok(sect) => sect,
@@ -73,17 +284,62 @@ impl Hd {
         println!("");
     }
 
+    pub fn reset_stats(&mut self) {
+        self.stats = IoStats::new();
+        self.cmd_start = IoStats::new();
+        self.last = IoStats::new();
+    }
+
+    // to call at the beginning of each command : the activity since the
+    // previous call becomes the one of the last command
+    pub fn mark_cmd(&mut self) {
+        self.last = self.stats.diff(&self.cmd_start);
+        self.cmd_start = self.stats.clone();
+    }
+
+    pub fn stats(&self) -> &IoStats {
+        &self.stats
+    }
+
+    pub fn last_stats(&self) -> &IoStats {
+        &self.last
+    }
+
+    pub fn set_trace(&mut self, trace : bool) {
+        self.trace = trace;
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.trace
+    }
+
+    // op stays on the trace stack until the returned guard is dropped
+    pub fn enter(&mut self, op : &'static str) -> OpGuard {
+        self.ops.borrow_mut().push(op);
+        OpGuard {ops: Rc::clone(&self.ops)}
+    }
+
+    fn log(&self, access : &str, sect_nb : usize) {
+        let mut ops : Vec<&str> = Vec::new();
+        for &op in self.ops.borrow().iter() {
+            if ops.last() != Some(&op) {ops.push(op)} // collapse recursive calls
+        }
+        eprintln!("[trace] {access} sect {sect_nb:>4} ({})", ops.join(" > "));
+    }
+
     pub fn dread(&mut self, sect_nb : u32) -> Result<Sector,HdErr> {
 
         let sect_nb = sect_nb as usize;
         let mut sect : Sector = [0;512];
+        if sect_nb >= self.sectors() {return Err(HdErr::OutOfRange)}
         match self.state {
             HdState::Free => {
                 self.state = HdState::Reading;
-                for k in 0..SECT_SIZE {
-                    sect[k] = self.array[k+SECT_SIZE*sect_nb];
-                }; 
+                let res = self.backend.read_sect(sect_nb, &mut sect);
                 self.state = HdState::Free;
+                if let Some(err) = res {return Err(err)};
+                self.stats.range_mut(sect_nb/RANGE_SIZE).reads += 1;
+                if self.trace {self.log("dread ", sect_nb)};
                 Ok(sect)
             }
             HdState::Reading => Err(HdErr::Occuped),
@@ -93,13 +349,15 @@ impl Hd {
 
     pub fn dwrite(&mut self, offset : u32, sect : Sector) -> Option<HdErr>{
         let offset = offset as usize;
+        if offset >= self.sectors() {return Some(HdErr::OutOfRange)}
         match self.state {
             HdState::Free => {
                 self.state = HdState::Writing;
-                for k in 0..SECT_SIZE{
-                    self.array[k+offset*SECT_SIZE] = sect[k];
-                }
+                let res = self.backend.write_sect(offset, &sect);
                 self.state = HdState::Free;
+                if let Some(err) = res {return Some(err)};
+                self.stats.range_mut(offset/RANGE_SIZE).writes += 1;
+                if self.trace {self.log("dwrite", offset)};
                 None
             },
             _ => Some(HdErr::Occuped),
@@ -0,0 +1,154 @@
+/*
+    shell/fs/hd/mmap.rs
+*/
+
+use super::{Backend, HdErr, Sector, SECT_SIZE};
+use std::fs::{File, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::os::raw::{c_int, c_void};
+
+// bindings to the libc already linked by std (linux values, the module is
+// only built on linux)
+const PROT_READ  : c_int = 0x1;
+const PROT_WRITE : c_int = 0x2;
+const MAP_SHARED : c_int = 0x1;
+const MS_SYNC    : c_int = 0x4;
+const MAP_FAILED : *mut c_void = !0 as *mut c_void;
+
+extern "C" {
+    fn mmap(addr : *mut c_void, len : usize, prot : c_int, flags : c_int, fd : c_int, offset : i64) -> *mut c_void;
+    fn msync(addr : *mut c_void, len : usize, flags : c_int) -> c_int;
+    fn munmap(addr : *mut c_void, len : usize) -> c_int;
+}
+
+// a host image file mapped into memory : sectors are copied directly
+// from/to the mapping and the kernel writes them back to the file
+pub struct MmapImage {
+    path  : String,     // to open it again for writing
+    file  : File,       // kept open as long as it is mapped
+    ptr   : *mut u8,
+    len   : usize,      // size of the mapping (in bytes)
+    writable : bool,    // false for the base of an overlay, until a commit
+}
+
+impl MmapImage {
+    // open (or create) the image, growing it to at least `min_size` bytes ;
+    // a read-only image must exist and keeps its size
+    pub fn open(path : &str, min_size : usize, writable : bool) -> Result<MmapImage,HdErr> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .create(writable)
+            .truncate(false)
+            .open(path)
+            .map_err(HdErr::Io)?;
+
+        let len = file.metadata().map_err(HdErr::Io)?.len() as usize;
+        if writable && len < min_size {
+            // the extension is a hole on most host filesystems
+            file.set_len(min_size as u64).map_err(HdErr::Io)?;
+        }
+        let len = (if writable {len.max(min_size)} else {len}) / SECT_SIZE * SECT_SIZE;
+        // an empty mapping is refused by mmap
+        if len == 0 {return Err(HdErr::InvalidImage)}
+
+        Ok(MmapImage {
+            path  : path.to_string(),
+            ptr   : Self::map(&file, len, writable)?,
+            file,
+            len,
+            writable,
+        })
+    }
+
+    fn map(file : &File, len : usize, writable : bool) -> Result<*mut u8,HdErr> {
+        let prot = if writable {PROT_READ | PROT_WRITE} else {PROT_READ};
+        let ptr = unsafe {
+            mmap(std::ptr::null_mut(), len, prot, MAP_SHARED, file.as_raw_fd(), 0)
+        };
+        if ptr == MAP_FAILED {return Err(HdErr::Io(std::io::Error::last_os_error()))}
+        Ok(ptr as *mut u8)
+    }
+}
+
+impl Backend for MmapImage {
+    fn sectors(&self) -> usize {
+        self.len/SECT_SIZE
+    }
+
+    fn allocated(&self) -> usize {
+        // holes of the host file are not counted
+        match self.file.metadata() {
+            Ok(meta) => (meta.blocks() as usize)*512,
+            Err(_) => self.len,
+        }
+    }
+
+    fn read_sect(&mut self, sect_nb : usize, sect : &mut Sector) -> Option<HdErr> {
+        // safe : Hd checks sect_nb against sectors()
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.ptr.add(sect_nb*SECT_SIZE), sect.as_mut_ptr(), SECT_SIZE);
+        }
+        None
+    }
+
+    fn write_sect(&mut self, sect_nb : usize, sect : &Sector) -> Option<HdErr> {
+        // the mapping itself is read-only : it would fault
+        if !self.writable {return Some(HdErr::ReadOnly)}
+        unsafe {
+            std::ptr::copy_nonoverlapping(sect.as_ptr(), self.ptr.add(sect_nb*SECT_SIZE), SECT_SIZE);
+        }
+        None
+    }
+
+    fn flush(&mut self) -> Option<HdErr> {
+        if !self.writable {return None}
+        if unsafe {msync(self.ptr as *mut c_void, self.len, MS_SYNC)} != 0 {
+            return Some(HdErr::Io(std::io::Error::last_os_error()))
+        }
+        None
+    }
+
+    // the mapping cannot follow the file : unmap, resize & map again
+    fn resize(&mut self, sectors : usize) -> Option<HdErr> {
+        if !self.writable {return Some(HdErr::ReadOnly)}
+        if let Some(err) = self.flush() {return Some(err)};
+        unsafe {munmap(self.ptr as *mut c_void, self.len);}
+        let len = sectors*SECT_SIZE;
+        let res = self.file.set_len(len as u64).map_err(HdErr::Io);
+        // on failure, the old size is mapped again
+        if res.is_ok() {self.len = len};
+        match Self::map(&self.file, self.len, true) {
+            Ok(ptr) => self.ptr = ptr,
+            // an empty device rather than a dangling mapping
+            Err(err) => {self.ptr = std::ptr::null_mut(); self.len = 0; return Some(err)},
+        }
+        res.err()
+    }
+
+    // the file is opened & mapped again, for writing
+    fn make_writable(&mut self) -> Option<HdErr> {
+        if self.writable {return None}
+        let file = match OpenOptions::new().read(true).write(true).open(&self.path) {
+            Ok(file) => file,
+            Err(err) => return Some(HdErr::Io(err)),
+        };
+        let ptr = match Self::map(&file, self.len, true) {
+            Ok(ptr) => ptr,
+            Err(err) => return Some(err),
+        };
+        unsafe {munmap(self.ptr as *mut c_void, self.len);}
+        self.file = file;
+        self.ptr = ptr;
+        self.writable = true;
+        None
+    }
+}
+
+impl Drop for MmapImage {
+    fn drop(&mut self) {
+        self.flush();
+        unsafe {munmap(self.ptr as *mut c_void, self.len);}
+    }
+}
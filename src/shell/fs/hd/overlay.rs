@@ -0,0 +1,77 @@
+/*
+    shell/fs/hd/overlay.rs
+*/
+
+use super::{Backend, Hd, HdErr, Sector, SECT_SIZE};
+use std::collections::BTreeMap;
+
+// copy-on-write device over a base hard drive : reads fall through to the
+// base unless the overlay holds the sector, writes only go to the overlay
+pub struct Overlay {
+    base : Hd,                      // read-only, written by commit only
+    top  : BTreeMap<usize, Sector>, // sectors written since the last commit/discard
+}
+
+impl Overlay {
+    pub fn new(base : Hd) -> Overlay {
+        Overlay {
+            base,
+            top  : BTreeMap::new(),
+        }
+    }
+
+    // number of sectors held by the overlay
+    pub fn held(&self) -> usize {
+        self.top.len()
+    }
+
+    // write the overlay into the base and empty it
+    pub fn commit(&mut self) -> Option<HdErr> {
+        if let Some(err) = self.base.make_writable() {return Some(err)};
+        while let Some((sect_nb, sect)) = self.top.pop_first() {
+            if let Some(err) = self.base.dwrite(sect_nb as u32, sect) {
+                self.top.insert(sect_nb, sect);
+                return Some(err)
+            }
+        }
+        self.base.flush()
+    }
+
+    // throw the overlay away : the device is the base again
+    pub fn discard(&mut self) {
+        self.top.clear();
+    }
+}
+
+impl Backend for Overlay {
+    fn sectors(&self) -> usize {
+        self.base.sectors()
+    }
+
+    fn allocated(&self) -> usize {
+        self.base.allocated() + self.top.len()*SECT_SIZE
+    }
+
+    fn read_sect(&mut self, sect_nb : usize, sect : &mut Sector) -> Option<HdErr> {
+        if let Some(held) = self.top.get(&sect_nb) {
+            *sect = *held;
+            return None
+        }
+        match self.base.dread(sect_nb as u32) {
+            Ok(base) => {*sect = base; None},
+            Err(err) => Some(err),
+        }
+    }
+
+    fn write_sect(&mut self, sect_nb : usize, sect : &Sector) -> Option<HdErr> {
+        self.top.insert(sect_nb, *sect);
+        None
+    }
+
+    // the overlay only lives in memory until it is committed
+    fn flush(&mut self) -> Option<HdErr> {None}
+
+    fn overlay(&mut self) -> Option<&mut Overlay> {
+        Some(self)
+    }
+}
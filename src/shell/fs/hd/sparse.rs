@@ -0,0 +1,221 @@
+/*
+    shell/fs/hd/sparse.rs
+*/
+
+use super::{Backend, HdErr, Sector, SECT_SIZE};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+
+// Image layout :
+// | header (1 sector) | cluster table (tbl_sz sectors) | CLUSTERS ... |
+// - header  : | magic (8 bytes) | logical size (in sectors) | used clusters |
+// - table   : one 4 bytes slot per logical cluster, 0 for a hole
+// - cluster : CLUST_SIZE sectors, allocated at the end of the image on first non-zero write
+const MAGIC      : &[u8;8] = b"SPARSEHD";
+const CLUST_SIZE : usize = 8;                       // sectors per cluster (aka 1 block)
+const CLUST_BYTES: usize = CLUST_SIZE*SECT_SIZE;
+
+pub struct SparseImage {
+    path    : String,    // to open it again for writing
+    file    : File,      // read-only for the base of an overlay, until a commit
+    sectors : usize,     // logical size
+    table   : Vec<u32>,  // logical cluster -> slot (1-based, 0 for a hole)
+    used    : u32,       // number of allocated clusters
+}
+
+impl SparseImage {
+    // open an image, or create a new one of `size` bytes if it does not exist
+    // or is empty (a read-only image must exist)
+    pub fn open(path : &str, size : usize, writable : bool) -> Result<SparseImage,HdErr> {
+        let exists = std::fs::metadata(path).is_ok_and(|meta| meta.len() > 0);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .create(writable)
+            .truncate(false)
+            .open(path)
+            .map_err(HdErr::Io)?;
+
+        if !exists && !writable {return Err(HdErr::InvalidImage)}
+        if !exists {
+            let sectors = size/SECT_SIZE;
+            let mut img = SparseImage {
+                path    : path.to_string(),
+                file,
+                sectors,
+                table   : Self::new_table(sectors)?,
+                used    : 0,
+            };
+            img.write_header()?;
+            // the table is a hole of the host file until it is filled
+            img.file.set_len(img.data_start() as u64).map_err(HdErr::Io)?;
+            return Ok(img)
+        }
+
+        let mut header : Sector = [0;SECT_SIZE];
+        if file.read_exact_at(&mut header, 0).is_err() {return Err(HdErr::InvalidImage)}
+        if &header[0..8] != MAGIC {return Err(HdErr::InvalidImage)}
+        let sectors = u64::from_be_bytes(header[8..16].try_into().unwrap()) as usize;
+        let used = u32::from_be_bytes(header[16..20].try_into().unwrap());
+
+        // the table must fit in the host file before it is allocated
+        let host_len = file.metadata().map_err(HdErr::Io)?.len();
+        let tbl_bytes = sectors.div_ceil(CLUST_SIZE).checked_mul(4).ok_or(HdErr::InvalidImage)?;
+        if (tbl_bytes as u64).saturating_add(SECT_SIZE as u64) > host_len {return Err(HdErr::InvalidImage)}
+        let mut raw = vec![0u8; tbl_bytes];
+        if file.read_exact_at(&mut raw, SECT_SIZE as u64).is_err() {return Err(HdErr::InvalidImage)}
+        let table : Vec<u32> = raw.chunks(4).map(|w| u32::from_be_bytes(w.try_into().unwrap())).collect();
+        if table.iter().any(|&slot| slot > used) {return Err(HdErr::InvalidImage)}
+
+        Ok(SparseImage {
+            path    : path.to_string(),
+            file,
+            sectors,
+            table,
+            used,
+        })
+    }
+
+    // an empty table of the clusters of `sectors`, an error rather than an
+    // abort if it does not fit in memory
+    fn new_table(sectors : usize) -> Result<Vec<u32>,HdErr> {
+        let clusters = sectors.div_ceil(CLUST_SIZE);
+        let mut table = Vec::new();
+        if table.try_reserve_exact(clusters).is_err() {return Err(HdErr::Io(std::io::ErrorKind::OutOfMemory.into()))}
+        table.resize(clusters, 0);
+        Ok(table)
+    }
+
+    fn tbl_sz(&self) -> usize {
+        (self.table.len()*4).div_ceil(SECT_SIZE)
+    }
+
+    // offset of the first cluster in the image (in bytes)
+    fn data_start(&self) -> usize {
+        (1 + self.tbl_sz())*SECT_SIZE
+    }
+
+    fn write_header(&mut self) -> Result<(),HdErr> {
+        let mut header : Sector = [0;SECT_SIZE];
+        header[0..8].copy_from_slice(MAGIC);
+        header[8..16].copy_from_slice(&(self.sectors as u64).to_be_bytes());
+        header[16..20].copy_from_slice(&self.used.to_be_bytes());
+        self.file.write_all_at(&header, 0).map_err(HdErr::Io)
+    }
+
+    // position of a sector inside the image, if its cluster is allocated
+    fn locate(&self, sect_nb : usize) -> Option<u64> {
+        let slot = self.table[sect_nb/CLUST_SIZE] as usize;
+        if slot == 0 {return None}
+        Some((self.data_start() + (slot-1)*CLUST_BYTES + (sect_nb%CLUST_SIZE)*SECT_SIZE) as u64)
+    }
+
+    fn alloc(&mut self, clust : usize) -> Result<(),HdErr> {
+        self.used += 1;
+        self.table[clust] = self.used;
+        self.file.write_all_at(&self.used.to_be_bytes(), (SECT_SIZE + 4*clust) as u64).map_err(HdErr::Io)?;
+        self.write_header()
+    }
+}
+
+impl SparseImage {
+    // a copy of the image resized to `sectors` at tmp_path, one cluster at a time
+    fn repack(&self, sectors : usize, tmp_path : &str) -> Result<SparseImage,HdErr> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(tmp_path)
+            .map_err(HdErr::Io)?;
+        let mut img = SparseImage {
+            path    : self.path.clone(),
+            file,
+            sectors,
+            table   : Self::new_table(sectors)?,
+            used    : 0,
+        };
+        img.file.set_len(img.data_start() as u64).map_err(HdErr::Io)?;
+        let mut data = vec![0u8; CLUST_BYTES];
+        for clust in 0..img.table.len().min(self.table.len()) {
+            let slot = self.table[clust] as usize;
+            if slot == 0 {continue}
+            // a short read means the end of a freshly allocated cluster : zeros
+            data.fill(0);
+            let ofs = self.data_start() + (slot-1)*CLUST_BYTES;
+            self.file.read_at(&mut data, ofs as u64).map_err(HdErr::Io)?;
+            img.used += 1;
+            img.table[clust] = img.used;
+            let ofs = img.data_start() + (img.used as usize - 1)*CLUST_BYTES;
+            img.file.write_all_at(&data, ofs as u64).map_err(HdErr::Io)?;
+        }
+        let raw : Vec<u8> = img.table.iter().flat_map(|slot| slot.to_be_bytes()).collect();
+        img.file.write_all_at(&raw, SECT_SIZE as u64).map_err(HdErr::Io)?;
+        img.write_header()?;
+        img.file.sync_data().map_err(HdErr::Io)?;
+        Ok(img)
+    }
+}
+
+impl Backend for SparseImage {
+    fn sectors(&self) -> usize {
+        self.sectors
+    }
+
+    fn allocated(&self) -> usize {
+        // the header, the table sectors with at least one slot, and the clusters
+        let tbl_used = self.table.chunks(SECT_SIZE/4).filter(|slots| slots.iter().any(|&slot| slot != 0)).count();
+        (1 + tbl_used)*SECT_SIZE + (self.used as usize)*CLUST_BYTES
+    }
+
+    fn read_sect(&mut self, sect_nb : usize, sect : &mut Sector) -> Option<HdErr> {
+        *sect = [0;SECT_SIZE];
+        if let Some(ofs) = self.locate(sect_nb) {
+            // a short read means the end of a freshly allocated cluster : zeros
+            if let Err(err) = self.file.read_at(sect, ofs) {return Some(HdErr::Io(err))}
+        }
+        None
+    }
+
+    fn write_sect(&mut self, sect_nb : usize, sect : &Sector) -> Option<HdErr> {
+        if self.locate(sect_nb).is_none() {
+            // writing zeros in a hole is a no-op
+            if sect.iter().all(|&b| b == 0) {return None}
+            if let Err(err) = self.alloc(sect_nb/CLUST_SIZE) {return Some(err)}
+        }
+        let ofs = self.locate(sect_nb).unwrap();
+        self.file.write_all_at(sect, ofs).err().map(HdErr::Io)
+    }
+
+    fn flush(&mut self) -> Option<HdErr> {
+        self.file.sync_data().err().map(HdErr::Io)
+    }
+
+    fn make_writable(&mut self) -> Option<HdErr> {
+        match OpenOptions::new().read(true).write(true).open(&self.path) {
+            Ok(file) => {self.file = file; None},
+            Err(err) => Some(HdErr::Io(err)),
+        }
+    }
+
+    fn resize(&mut self, sectors : usize) -> Option<HdErr> {
+        let clusters = sectors.div_ceil(CLUST_SIZE);
+        // enough room in the sectors of the table : only new holes
+        if clusters >= self.table.len() && (clusters*4).div_ceil(SECT_SIZE) == self.tbl_sz() {
+            self.table.resize(clusters, 0);
+            self.sectors = sectors;
+            return self.write_header().err()
+        }
+
+        // otherwise the clusters move : the kept ones are packed again behind
+        // the new table, in a copy which replaces the image at last (a crash
+        // leaves the old one intact)
+        let tmp_path = format!("{}.resize", self.path);
+        let res = self.repack(sectors, &tmp_path)
+            .and_then(|img| std::fs::rename(&tmp_path, &self.path).map(|_| img).map_err(HdErr::Io));
+        match res {
+            Ok(img) => {*self = img; None},
+            Err(err) => {let _ = std::fs::remove_file(&tmp_path); Some(err)},
+        }
+    }
+}
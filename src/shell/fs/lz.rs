@@ -0,0 +1,156 @@
+/*
+    shell/fs/lz.rs
+*/
+
+// A small LZSS codec : the stream is made of groups of 8 items preceded by
+// a flag byte (bit k set : the k-th item is a match, else a literal byte).
+// A match is 2 bytes : | offset - 1 (12 bits) | length - MIN_MATCH (4 bits) |
+
+const WINDOW    : usize = 1<<12;
+const MIN_MATCH : usize = 3;
+const MAX_MATCH : usize = MIN_MATCH + 0xF;
+const HASH_SIZE : usize = 1<<12;
+const MAX_CHAIN : usize = 32;   // candidates looked at for each position
+
+#[inline(always)]
+fn hash(data : &[u8], k : usize) -> usize {
+    ((data[k] as usize) << 8 ^ (data[k+1] as usize) << 4 ^ data[k+2] as usize) % HASH_SIZE
+}
+
+pub fn compress(data : &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut head = vec![usize::MAX; HASH_SIZE];  // last position of each hash
+    let mut prev = vec![usize::MAX; data.len()]; // previous position with the same hash
+
+    let mut flag_pos = 0;
+    let mut item = 8;
+    let mut k = 0;
+    while k < data.len() {
+        if item == 8 {
+            flag_pos = out.len();
+            out.push(0);
+            item = 0;
+        }
+
+        // longest match in the window
+        let (mut best_len, mut best_ofs) = (0, 0);
+        if k + MIN_MATCH <= data.len() {
+            let mut cand = head[hash(data, k)];
+            let mut chain = 0;
+            while cand != usize::MAX && k - cand <= WINDOW && chain < MAX_CHAIN {
+                let mut len = 0;
+                while len < MAX_MATCH && k + len < data.len() && data[cand + len] == data[k + len] {
+                    len += 1;
+                }
+                if len > best_len {best_len = len; best_ofs = k - cand}
+                cand = prev[cand];
+                chain += 1;
+            }
+        }
+
+        let step = if best_len >= MIN_MATCH {
+            out[flag_pos] |= 1<<item;
+            let word = ((best_ofs - 1) << 4) | (best_len - MIN_MATCH);
+            out.push((word >> 8) as u8);
+            out.push(word as u8);
+            best_len
+        } else {
+            out.push(data[k]);
+            1
+        };
+
+        // index the consumed positions
+        for (pos, link) in prev.iter_mut().enumerate().skip(k).take(step) {
+            if pos + MIN_MATCH <= data.len() {
+                let h = hash(data, pos);
+                *link = head[h];
+                head[h] = pos;
+            }
+        }
+        k += step;
+        item += 1;
+    }
+    out
+}
+
+// None for a corrupted stream (len is not trusted : out grows with the
+// stream)
+pub fn decompress(data : &[u8], len : usize) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut k = 0;
+    while out.len() < len {
+        let flags = *data.get(k)?;
+        k += 1;
+        for item in 0..8 {
+            if out.len() >= len {break}
+            if flags & (1<<item) == 0 {
+                out.push(*data.get(k)?);
+                k += 1;
+                continue
+            }
+            let word = ((*data.get(k)? as usize) << 8) | *data.get(k+1)? as usize;
+            k += 2;
+            let ofs = (word >> 4) + 1;
+            if ofs > out.len() {return None}
+            for _ in 0..(word & 0xF) + MIN_MATCH {
+                out.push(out[out.len() - ofs]);
+            }
+        }
+    }
+    out.truncate(len);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data : &[u8]) {
+        let packed = compress(data);
+        assert_eq!(decompress(&packed, data.len()).as_deref(), Some(data));
+    }
+
+    // a xorshift generator : the same data at each run
+    fn noise(seed : u64, len : usize, range : u8) -> Vec<u8> {
+        let mut x = seed;
+        (0..len).map(|_| {
+            x ^= x << 13; x ^= x >> 7; x ^= x << 17;
+            (x % range as u64) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn small_inputs() {
+        round_trip(b"");
+        round_trip(b"a");
+        round_trip(b"ab");
+        round_trip(b"abc");
+        round_trip(b"abcabcabcabc");
+    }
+
+    #[test]
+    fn repetitive_input_shrinks() {
+        let data = vec![b'x'; 10_000];
+        assert!(compress(&data).len() < data.len()/4);
+        round_trip(&data);
+        round_trip(&b"hello world ".repeat(500));
+    }
+
+    #[test]
+    fn random_inputs() {
+        for seed in 1..50 {
+            // a small alphabet gives many matches, a large one few
+            round_trip(&noise(seed, 3*WINDOW + seed as usize, if seed % 2 == 0 {4} else {255}));
+        }
+    }
+
+    #[test]
+    fn corrupted_stream() {
+        // a match before the start of the output
+        assert_eq!(decompress(&[0x01, 0x00, 0x00], 3), None);
+        // a stream shorter than announced
+        let packed = compress(b"abcdefgh");
+        assert_eq!(decompress(&packed[..packed.len() - 1], 8), None);
+        assert_eq!(decompress(&[], 1), None);
+    }
+}
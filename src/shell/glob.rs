@@ -0,0 +1,228 @@
+/*
+    shell/glob.rs
+*/
+
+use super::Format;
+use super::fs::Fs;
+use super::fs::Fdesc;
+
+// Expands the patterns of the words against the directories of the file
+// system. A pattern is a word where the quoted chars are escaped with a
+// backslash, the others are special :
+//   *      any name part (not a leading '.')
+//   ?      any char
+//   [...]  any char of the set (a-z ranges, [!...] or [^...] for the others)
+//   **     as a whole path component, any number of directories
+// The names found are sorted.
+
+// what a pattern without match gives
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoMatch {
+    Keep,   // the pattern itself
+    Fail,   // an error, the command does not run
+    Empty,  // nothing
+}
+
+// escapes the chars of quoted text, so that they are not special
+pub fn quote(text : &[char]) -> Format {
+    let mut res = Vec::new();
+    for &c in text {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {res.push('\\')}
+        res.push(c);
+    }
+    res
+}
+
+pub fn unquote(pattern : &[char]) -> Format {
+    let mut res = Vec::new();
+    let mut k = 0;
+    while k < pattern.len() {
+        if pattern[k] == '\\' && k + 1 < pattern.len() {k += 1}
+        res.push(pattern[k]);
+        k += 1;
+    }
+    res
+}
+
+// the names matching a pattern, None if there is none, the word itself if it
+// is not a pattern
+pub fn glob(fs : &mut Fs, cur : &Fdesc, pattern : &Format) -> Option<Vec<Format>> {
+    if !pattern.split(|&c| c == '/').any(is_pattern) {return Some(vec![unquote(pattern)])}
+    let (prefix, rest) = if pattern.first() == Some(&'/') {("/", &pattern[1..])} else {("", &pattern[..])};
+    let comps : Vec<&[char]> = rest.split(|&c| c == '/').collect();
+    let mut names = Vec::new();
+    walk(fs, cur, prefix.to_string(), &comps, &mut names);
+    names.sort();
+    names.dedup();
+    if names.is_empty() {return None}
+    Some(names.iter().map(|name| name.chars().collect()).collect())
+}
+
+fn is_pattern(comp : &[char]) -> bool {
+    let mut k = 0;
+    while k < comp.len() {
+        match comp[k] {
+            '\\' => k += 1,
+            '*' | '?' => return true,
+            '[' if set_end(comp, k).is_some() => return true,
+            _ => (),
+        }
+        k += 1;
+    }
+    false
+}
+
+// adds the paths matching the components, from the directory prefix (empty
+// for the current one, else ending with '/')
+fn walk(fs : &mut Fs, cur : &Fdesc, prefix : String, comps : &[&[char]], names : &mut Vec<String>) {
+    let (comp, rest) = match comps.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    // a//b or a trailing '/'
+    if comp.is_empty() {
+        if rest.is_empty() {names.push(prefix)} else {walk(fs, cur, prefix, rest, names)}
+        return
+    }
+    if !is_pattern(comp) {
+        let name = unquote(comp).iter().collect::<String>();
+        let path = format!("{prefix}{name}");
+        if !rest.is_empty() {return walk(fs, cur, path + "/", rest, names)}
+        let found = name == "." || name == ".." || fs.entries(cur, &prefix).is_ok_and(|entries| entries.iter().any(|(entry, _)| *entry == name));
+        if found {names.push(path)}
+        return
+    }
+    let entries = match fs.entries(cur, &prefix) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    // '**' stands for no directory, or for one more, everything below at the end
+    if comp[..] == ['*', '*'] {
+        walk(fs, cur, prefix.clone(), rest, names);
+        for (entry, dir) in entries {
+            if entry.starts_with('.') {continue}
+            if rest.is_empty() {names.push(format!("{prefix}{entry}"))}
+            if dir {walk(fs, cur, format!("{prefix}{entry}/"), comps, names)}
+        }
+        return
+    }
+    for (entry, dir) in entries {
+        let name : Format = entry.chars().collect();
+        // a hidden name only matches a leading '.'
+        if name[0] == '.' && comp[0] != '.' {continue}
+        if !matches(comp, &name) {continue}
+        if rest.is_empty() {names.push(format!("{prefix}{entry}"))}
+        else if dir {walk(fs, cur, format!("{prefix}{entry}/"), rest, names)}
+    }
+}
+
+// the index of the ']' closing the set opened at k
+fn set_end(pattern : &[char], k : usize) -> Option<usize> {
+    let mut end = k + 1;
+    if matches!(pattern.get(end), Some('!' | '^')) {end += 1}
+    // a ']' first is in the set
+    if pattern.get(end) == Some(&']') {end += 1}
+    while end < pattern.len() && pattern[end] != ']' {
+        if pattern[end] == '\\' {end += 1}
+        end += 1;
+    }
+    if end < pattern.len() {Some(end)} else {None}
+}
+
+// whether c is in the set pattern[k..=end]
+fn in_set(pattern : &[char], k : usize, end : usize, c : char) -> bool {
+    let mut i = k + 1;
+    let negated = matches!(pattern[i], '!' | '^');
+    if negated {i += 1}
+    let mut found = false;
+    while i < end {
+        if pattern[i] == '\\' {i += 1}
+        let low = pattern[i];
+        if pattern.get(i + 1) == Some(&'-') && i + 2 < end {
+            let high = if pattern[i + 2] == '\\' {i += 1; pattern[i + 2]} else {pattern[i + 2]};
+            if low <= c && c <= high {found = true}
+            i += 3;
+        } else {
+            if low == c {found = true}
+            i += 1;
+        }
+    }
+    found != negated
+}
+
+// whether the whole name matches the pattern (a path component, a word of a case)
+pub fn matches(pattern : &[char], name : &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // where to go on after the last '*' : pattern and name indexes
+    let mut star : Option<(usize, usize)> = None;
+    while n < name.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {star = Some((p + 1, n)); p += 1; continue},
+            Some('?') => Some(p + 1),
+            Some('[') => match set_end(pattern, p) {
+                Some(end) => if in_set(pattern, p, end, name[n]) {Some(end + 1)} else {None},
+                None => if name[n] == '[' {Some(p + 1)} else {None},
+            },
+            Some('\\') if p + 1 < pattern.len() => if pattern[p + 1] == name[n] {Some(p + 2)} else {None},
+            Some(&c) => if c == name[n] {Some(p + 1)} else {None},
+            None => None,
+        };
+        match (step, star) {
+            (Some(next), _) => {p = next; n += 1},
+            // the last '*' takes one more char
+            (None, Some((after, from))) => {star = Some((after, from + 1)); p = after; n = from + 1},
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fmt(text : &str) -> Format {
+        text.chars().collect()
+    }
+
+    fn is_match(pattern : &str, name : &str) -> bool {
+        matches(&fmt(pattern), &fmt(name))
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(is_match("abc", "abc"));
+        assert!(!is_match("abc", "abcd"));
+        assert!(is_match("*", ""));
+        assert!(is_match("a*", "abc"));
+        assert!(is_match("*c", "abc"));
+        assert!(is_match("a*b*c", "aXbYbZc"));
+        assert!(!is_match("a*b*c", "aXbYbZ"));
+        assert!(is_match("a?c", "abc"));
+        assert!(!is_match("a?c", "ac"));
+        assert!(is_match("**", "abc"));
+    }
+
+    #[test]
+    fn sets() {
+        assert!(is_match("[abc]x", "bx"));
+        assert!(!is_match("[abc]x", "dx"));
+        assert!(is_match("[a-f]", "c"));
+        assert!(!is_match("[a-f]", "g"));
+        assert!(is_match("[!a-f]", "g"));
+        assert!(is_match("[^a-f]", "g"));
+        assert!(!is_match("[!a-f]", "c"));
+        assert!(is_match("[0-9][0-9]", "42"));
+        // an unclosed '[' is literal
+        assert!(is_match("[a", "[a"));
+    }
+
+    #[test]
+    fn quoting() {
+        let text = fmt("a*b?[c]\\");
+        assert_eq!(quote(&text), fmt("a\\*b\\?\\[c\\]\\\\"));
+        assert_eq!(unquote(&quote(&text)), text);
+        assert!(matches(&quote(&text), &text));
+        assert!(!matches(&quote(&fmt("a*")), &fmt("abc")));
+    }
+}
@@ -0,0 +1,455 @@
+/*
+    shell/lexer.rs
+*/
+
+use super::Format;
+use super::ParsingErr;
+use super::SyntaxErr;
+use super::parser;
+
+// Splits a command line into words and operators. The quotes and the
+// backslashes are only seen here : a word is made of parts, which tell what
+// was quoted once they are removed.
+//   '...'  everything is literal
+//   "..."  only \" \\ \$ \` are escapes, the parameters are expanded
+//   \c     c is literal (an operator or a blank as well), a backslash before
+//          a newline removes both
+//   $NAME, ${NAME}, $?, $#, $@, $1 ... parameters (a lone $ is literal)
+//   $(cmd), `cmd`  the output of the command, checked here but run later
+//   $((expr))      an arithmetic expression
+// A newline ends a command like ';', a '#' at the start of a word comments
+// out the end of the line.
+// Unquoted digits right before '<' or '>' are the file descriptor of the
+// redirection (2>).
+// The lines following the one of a '<<' are the body of the here-document,
+// up to a line made of its delimiter (the word after '<<'). The body is
+// literal if a part of the delimiter is quoted, else only the expansions
+// and the backslashes before \ $ ` are seen.
+// An error does not stop the lexer : an unterminated quote runs to the end.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Part {
+    Lit(Format),            // unquoted text
+    Quoted(Format),         // quoted or escaped text
+    Param(Format, bool),    // a parameter to expand, quoted or not
+    Cmd(Format, bool),      // a command to substitute, quoted or not
+    Arith(Format),          // an arithmetic expression
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tok {
+    Word(Vec<Part>),
+    IoNumber(usize),
+    Pipe,
+    AndIf,
+    OrIf,
+    Semi,
+    DSemi,
+    Newline,
+    LParen,
+    RParen,
+    Great,
+    DGreat,
+    GreatAnd,
+    Less,
+    LessGreat,
+    DLess(Vec<Part>),   // and the body of the here-document
+    TLess,
+}
+
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub tok : Tok,
+    pub col : usize,    // column of the first char (from 1)
+}
+
+fn is_blank(c : char) -> bool {
+    c == ' ' || c == '\t' || c == '\n' || c == '\r'
+}
+
+fn is_operator(input : &Format, k : usize) -> bool {
+    match input[k] {
+        '|' | '>' | '<' | ';' | '(' | ')' | '\n' => true,
+        '&' => input.get(k + 1) == Some(&'&'),
+        _ => false,
+    }
+}
+
+// a here-document waiting for its body
+struct HereDoc {
+    token : usize,      // index of the '<<'
+    delim : Format,
+    quoted : bool,
+}
+
+pub fn lex(input : &Format) -> (Vec<Token>, Vec<SyntaxErr>) {
+    let mut tokens = Vec::new();
+    let mut errs = Vec::new();
+    let mut heredocs : Vec<HereDoc> = Vec::new();
+    let mut k = 0;
+    while k < input.len() {
+        let col = k + 1;
+        let next = input.get(k + 1).copied();
+        let tok = match input[k] {
+            // the here-documents of the line come right after it
+            '\n' if heredocs.iter().any(|heredoc| !heredoc.delim.is_empty()) => {
+                tokens.push(Token {tok: Tok::Newline, col});
+                k = bodies(input, k + 1, &mut heredocs, &mut tokens, &mut errs);
+                continue
+            },
+            '\n' => Tok::Newline,
+            '\\' if next == Some('\n') => {k += 2; continue},
+            '#' => {
+                k = input[k..].iter().position(|&c| c == '\n').map_or(input.len(), |len| k + len);
+                continue
+            },
+            c if is_blank(c) => {k += 1; continue},
+            '|' if next == Some('|') => {k += 1; Tok::OrIf},
+            '|' => Tok::Pipe,
+            '&' if next == Some('&') => {k += 1; Tok::AndIf},
+            ';' if next == Some(';') => {k += 1; Tok::DSemi},
+            ';' => Tok::Semi,
+            '(' => Tok::LParen,
+            ')' => Tok::RParen,
+            '>' if next == Some('&') => {k += 1; Tok::GreatAnd},
+            '>' if next == Some('>') => {k += 1; Tok::DGreat},
+            '>' => Tok::Great,
+            '<' if next == Some('>') => {k += 1; Tok::LessGreat},
+            '<' if next == Some('<') && input.get(k + 2) == Some(&'<') => {k += 2; Tok::TLess},
+            '<' if next == Some('<') => {
+                heredocs.push(HereDoc {token: tokens.len(), delim: Vec::new(), quoted: false});
+                k += 1;
+                Tok::DLess(Vec::new())
+            },
+            '<' => Tok::Less,
+            _ => {
+                let (parts, end) = word(input, k, &mut errs);
+                // the word right after a '<<' is its delimiter
+                if let Some(heredoc) = heredocs.last_mut() {
+                    if heredoc.delim.is_empty() && heredoc.token + 1 == tokens.len() {
+                        for part in &parts {
+                            match part {
+                                Part::Lit(text) => heredoc.delim.extend_from_slice(text),
+                                Part::Quoted(text) => {heredoc.delim.extend_from_slice(text); heredoc.quoted = true},
+                                Part::Param(name, _) => {heredoc.delim.push('$'); heredoc.delim.extend_from_slice(name)},
+                                Part::Cmd(cmd, _) => {heredoc.delim.extend_from_slice(&['$', '(']); heredoc.delim.extend_from_slice(cmd); heredoc.delim.push(')')},
+                                Part::Arith(expr) => {heredoc.delim.extend_from_slice(&['$', '(', '(']); heredoc.delim.extend_from_slice(expr); heredoc.delim.extend_from_slice(&[')', ')'])},
+                            }
+                        }
+                    }
+                }
+                let fd = match &parts[..] {
+                    [Part::Lit(text)] if text.iter().all(|c| c.is_ascii_digit()) && matches!(input.get(end), Some('<' | '>')) => {
+                        text.iter().collect::<String>().parse::<usize>().ok()
+                    },
+                    _ => None,
+                };
+                let tok = match fd {
+                    Some(fd) => Tok::IoNumber(fd),
+                    None => Tok::Word(parts),
+                };
+                tokens.push(Token {tok, col});
+                k = end;
+                continue
+            },
+        };
+        tokens.push(Token {tok, col});
+        k += 1;
+    }
+    for heredoc in heredocs.iter().filter(|heredoc| !heredoc.delim.is_empty()) {
+        errs.push(SyntaxErr {err: ParsingErr::UnterminatedHereDoc, col: tokens[heredoc.token].col});
+    }
+    (tokens, errs)
+}
+
+// reads the bodies of the here-documents waiting for them, from the start
+// of a line, returns the index following the last delimiter
+fn bodies(input : &Format, start : usize, heredocs : &mut Vec<HereDoc>, tokens : &mut [Token], errs : &mut Vec<SyntaxErr>) -> usize {
+    let mut k = start;
+    // a '<<' without delimiter is an error of the parser
+    heredocs.retain(|heredoc| !heredoc.delim.is_empty());
+    while !heredocs.is_empty() && k < input.len() {
+        let end = input[k..].iter().position(|&c| c == '\n').map_or(input.len(), |len| k + len);
+        let line = &input[k..end];
+        let heredoc = &heredocs[0];
+        if line == &heredoc.delim[..] {
+            heredocs.remove(0);
+        } else if let Tok::DLess(body) = &mut tokens[heredoc.token].tok {
+            if heredoc.quoted {
+                line.iter().for_each(|&c| push(body, c, true));
+            } else {
+                body.append(&mut quoted_parts(input, k, end, errs));
+            }
+            push(body, '\n', true);
+        }
+        k = end + 1;
+    }
+    k
+}
+
+// the parts of input[start..end] read as between double quotes, without
+// the quotes : a line of a here-document with an unquoted delimiter, or an
+// arithmetic expression
+pub fn quoted_parts(input : &[char], start : usize, end : usize, errs : &mut Vec<SyntaxErr>) -> Vec<Part> {
+    let mut parts = Vec::new();
+    let mut k = start;
+    while k < end {
+        if let Some((part, last)) = expansion(&input[..end], k, true, errs) {
+            parts.push(part);
+            k = last + 1;
+            continue;
+        }
+        if input[k] == '\\' && matches!(input[..end].get(k + 1), Some('\\' | '$' | '`')) {k += 1}
+        push(&mut parts, input[k], true);
+        k += 1;
+    }
+    parts
+}
+
+// reads the expansion ($..., `...`) at k, returns its part and the index of
+// its last char, None for a literal char
+fn expansion(input : &[char], k : usize, quoted : bool, errs : &mut Vec<SyntaxErr>) -> Option<(Part, usize)> {
+    match (input[k], input.get(k + 1), input.get(k + 2)) {
+        ('$', Some('('), Some('(')) => {
+            // up to the first ')' outside of parentheses, followed by another one
+            let end = match close(input, k + 3) {
+                Some(end) if input.get(end + 1) == Some(&')') => end,
+                _ => {errs.push(SyntaxErr {err: ParsingErr::UnterminatedSubst, col: k + 1}); return Some((Part::Arith(Vec::new()), input.len() - 1))},
+            };
+            // the expansions of the expression are checked as well
+            quoted_parts(input, k + 3, end, errs);
+            Some((Part::Arith(input[k + 3..end].to_vec()), end + 1))
+        },
+        ('$', Some('('), _) => {
+            let end = match close(input, k + 2) {
+                Some(end) => end,
+                None => {errs.push(SyntaxErr {err: ParsingErr::UnterminatedSubst, col: k + 1}); return Some((Part::Cmd(Vec::new(), quoted), input.len() - 1))},
+            };
+            let cmd = input[k + 2..end].to_vec();
+            check(&cmd, k + 2, errs);
+            Some((Part::Cmd(cmd, quoted), end))
+        },
+        ('$', _, _) => param(input, k).map(|(name, end)| (Part::Param(name, quoted), end)),
+        ('`', _, _) => {
+            // the backslashes before ` \ $ are removed
+            let mut cmd = Vec::new();
+            let mut end = k + 1;
+            while end < input.len() && input[end] != '`' {
+                if input[end] == '\\' && matches!(input.get(end + 1), Some('`' | '\\' | '$')) {end += 1}
+                cmd.push(input[end]);
+                end += 1;
+            }
+            if end == input.len() {
+                errs.push(SyntaxErr {err: ParsingErr::UnterminatedSubst, col: k + 1});
+                return Some((Part::Cmd(Vec::new(), quoted), input.len() - 1))
+            }
+            // the columns of the errors are only right without escapes
+            check(&cmd, k + 1, errs);
+            Some((Part::Cmd(cmd, quoted), end))
+        },
+        _ => None,
+    }
+}
+
+// the syntax errors of a command to substitute, starting at the index start
+fn check(cmd : &Format, start : usize, errs : &mut Vec<SyntaxErr>) {
+    if let Err(cmd_errs) = parser::parse(cmd) {
+        errs.extend(cmd_errs.into_iter().map(|err| SyntaxErr {err: err.err, col: err.col + start}));
+    }
+}
+
+// the index of the ')' closing a '(' before start, the quotes and the
+// backslashes are skipped
+fn close(input : &[char], start : usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut k = start;
+    while k < input.len() {
+        match input[k] {
+            '\\' => k += 1,
+            '\'' => k += input[k + 1..].iter().position(|&c| c == '\'')? + 1,
+            '"' | '`' => {
+                let quote = input[k];
+                k += 1;
+                while input.get(k) != Some(&quote) {
+                    if input.get(k)? == &'\\' {k += 1}
+                    k += 1;
+                }
+            },
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some(k),
+            ')' => depth -= 1,
+            _ => (),
+        }
+        k += 1;
+    }
+    None
+}
+
+// adds a literal char to the parts of a word
+fn push(parts : &mut Vec<Part>, c : char, quoted : bool) {
+    match parts.last_mut() {
+        Some(Part::Lit(text)) if !quoted => text.push(c),
+        Some(Part::Quoted(text)) if quoted => text.push(c),
+        _ => parts.push(if quoted {Part::Quoted(vec![c])} else {Part::Lit(vec![c])}),
+    }
+}
+
+// reads the parameter of the '$' at k, returns its name and the index of its last char
+fn param(input : &[char], k : usize) -> Option<(Format, usize)> {
+    let is_name = |c : &char| c.is_ascii_alphanumeric() || *c == '_';
+    match input.get(k + 1)? {
+        '?' | '#' | '@' => Some((vec![input[k + 1]], k + 1)),
+        c if c.is_ascii_digit() => Some((vec![*c], k + 1)),
+        c if c.is_ascii_alphabetic() || *c == '_' => {
+            let len = input[k + 1..].iter().take_while(|c| is_name(c)).count();
+            Some((input[k + 1..k + 1 + len].to_vec(), k + len))
+        },
+        '{' => {
+            let len = input[k + 2..].iter().position(|&c| c == '}')?;
+            let name = input[k + 2..k + 2 + len].to_vec();
+            let valid = match name[..] {
+                ['?'] | ['#'] | ['@'] => true,
+                [c, ..] if c.is_ascii_digit() => name.iter().all(|c| c.is_ascii_digit()),
+                [c, ..] => (c.is_ascii_alphabetic() || c == '_') && name.iter().all(is_name),
+                [] => false,
+            };
+            if valid {Some((name, k + 2 + len))} else {None}
+        },
+        _ => None,
+    }
+}
+
+// reads the word starting at start, returns its parts and the index following it
+fn word(input : &Format, start : usize, errs : &mut Vec<SyntaxErr>) -> (Vec<Part>, usize) {
+    let mut parts = Vec::new();
+    let mut k = start;
+    while k < input.len() {
+        match input[k] {
+            c if is_blank(c) || is_operator(input, k) => break,
+            '\\' => {
+                k += 1;
+                match input.get(k) {
+                    Some('\n') => (),
+                    Some(&c) => push(&mut parts, c, true),
+                    None => errs.push(SyntaxErr {err: ParsingErr::TrailingEscape, col: k}),
+                }
+            },
+            '\'' => {
+                let open = k;
+                parts.push(Part::Quoted(Vec::new()));
+                k += 1;
+                while k < input.len() && input[k] != '\'' {
+                    push(&mut parts, input[k], true);
+                    k += 1;
+                }
+                if k == input.len() {errs.push(SyntaxErr {err: ParsingErr::UnterminatedQuote, col: open + 1})}
+            },
+            '"' => {
+                let open = k;
+                parts.push(Part::Quoted(Vec::new()));
+                k += 1;
+                while k < input.len() && input[k] != '"' {
+                    if let Some((part, end)) = expansion(input, k, true, errs) {
+                        parts.push(part);
+                        k = end + 1;
+                        continue;
+                    }
+                    if input[k] == '\\' && matches!(input.get(k + 1), Some('"' | '\\' | '$' | '`')) {k += 1}
+                    push(&mut parts, input[k], true);
+                    k += 1;
+                }
+                if k == input.len() {errs.push(SyntaxErr {err: ParsingErr::UnterminatedQuote, col: open + 1})}
+            },
+            '$' | '`' => match expansion(input, k, false, errs) {
+                Some((part, end)) => {
+                    parts.push(part);
+                    k = end;
+                },
+                None => push(&mut parts, '$', false),
+            },
+            c => push(&mut parts, c, false),
+        }
+        k += 1;
+    }
+    (parts, k)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fmt(text : &str) -> Format {
+        text.chars().collect()
+    }
+
+    fn toks(line : &str) -> Vec<Tok> {
+        let (tokens, errs) = lex(&fmt(line));
+        assert!(errs.is_empty(), "{:?}", errs);
+        tokens.into_iter().map(|token| token.tok).collect()
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(toks("a'b c'\\ d\"$x\"$y"), vec![Tok::Word(vec![
+            Part::Lit(fmt("a")),
+            Part::Quoted(fmt("b c ")),
+            Part::Lit(fmt("d")),
+            Part::Quoted(fmt("")),   // keeps "" an empty word
+            Part::Param(fmt("x"), true),
+            Part::Param(fmt("y"), false),
+        ])]);
+        assert_eq!(toks("'$x' $"), vec![
+            Tok::Word(vec![Part::Quoted(fmt("$x"))]),
+            Tok::Word(vec![Part::Lit(fmt("$"))]),
+        ]);
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(toks("|| | && ;; ; ( ) >& >> > <> <<< <"), vec![
+            Tok::OrIf, Tok::Pipe, Tok::AndIf, Tok::DSemi, Tok::Semi, Tok::LParen, Tok::RParen,
+            Tok::GreatAnd, Tok::DGreat, Tok::Great, Tok::LessGreat, Tok::TLess, Tok::Less,
+        ]);
+        assert_eq!(toks("a|b"), vec![
+            Tok::Word(vec![Part::Lit(fmt("a"))]), Tok::Pipe, Tok::Word(vec![Part::Lit(fmt("b"))]),
+        ]);
+    }
+
+    #[test]
+    fn io_number() {
+        assert_eq!(toks("2>&1"), vec![Tok::IoNumber(2), Tok::GreatAnd, Tok::Word(vec![Part::Lit(fmt("1"))])]);
+        // quoted or followed by a blank, it is a word
+        assert_eq!(toks("'2'>f")[0], Tok::Word(vec![Part::Quoted(fmt("2"))]));
+        assert_eq!(toks("2 >f")[0], Tok::Word(vec![Part::Lit(fmt("2"))]));
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(toks("a #b c\nd"), vec![
+            Tok::Word(vec![Part::Lit(fmt("a"))]), Tok::Newline, Tok::Word(vec![Part::Lit(fmt("d"))]),
+        ]);
+        assert_eq!(toks("a#b"), vec![Tok::Word(vec![Part::Lit(fmt("a#b"))])]);
+    }
+
+    #[test]
+    fn errors() {
+        let (_, errs) = lex(&fmt("echo 'abc"));
+        assert!(matches!(errs[..], [SyntaxErr {err: ParsingErr::UnterminatedQuote, col: 6}]));
+        let (_, errs) = lex(&fmt("echo \\"));
+        assert!(matches!(errs[..], [SyntaxErr {err: ParsingErr::TrailingEscape, ..}]));
+        let (_, errs) = lex(&fmt("cat <<END\nabc"));
+        assert!(matches!(errs[..], [SyntaxErr {err: ParsingErr::UnterminatedHereDoc, col: 5}]));
+    }
+
+    #[test]
+    fn here_document() {
+        let tokens = toks("cat <<END\na $x\n'b'\nEND\necho");
+        assert_eq!(tokens[1], Tok::DLess(vec![
+            Part::Quoted(fmt("a ")), Part::Param(fmt("x"), true), Part::Quoted(fmt("\n")), Part::Quoted(fmt("'b'\n")),
+        ]));
+        assert_eq!(tokens[4], Tok::Word(vec![Part::Lit(fmt("echo"))]));
+        // a quoted delimiter makes the body literal
+        let tokens = toks("cat <<'END'\n$x\nEND");
+        assert_eq!(tokens[1], Tok::DLess(vec![Part::Quoted(fmt("$x\n"))]));
+    }
+}
@@ -0,0 +1,545 @@
+/*
+    shell/parser.rs
+*/
+
+use super::Format;
+use super::ParsingErr;
+use super::SyntaxErr;
+use super::lexer;
+use super::lexer::Part;
+use super::lexer::Tok;
+use super::lexer::Token;
+use std::rc::Rc;
+
+// The grammar of a command line (or of a script) :
+//   list     := [and_or (sep and_or)* [sep]]
+//   sep      := ';' | '\n'
+//   and_or   := pipeline (('&&' | '||') pipeline)*
+//   pipeline := command ('|' command)*
+//   command  := simple | compound | NAME '(' ')' compound
+//   compound := (if | loop | for | case | '{' list '}') redirect*
+//   if       := 'if' list 'then' list ('elif' list 'then' list)* ['else' list] 'fi'
+//   loop     := ('while' | 'until') list 'do' list 'done'
+//   for      := 'for' NAME ['in' word*] [sep] 'do' list 'done'
+//   case     := 'case' word 'in' (['('] word ('|' word)* ')' list [';;'])* 'esac'
+//   simple   := (assign | redirect)* (word | redirect)*
+//   assign   := NAME '=' [word]
+//   redirect := [fd] ('<' | '<>' | '<<' | '<<<' | '>' | '>>') word | [fd] '>&' fd
+// An input is read from the fd 0 (the default), an output goes to the fd 1
+// (the default) or 2. When a fd is redirected several times, the last one
+// wins (the files of the others are created all the same).
+// The assignments before the command only hold for it, they stay in the
+// shell when there is no command. The redirections after a compound command
+// hold for the whole of it (for each call of a function).
+// The reserved words (if, then, do, {, ...) are only seen as the first word
+// of a command. Newlines may come between the commands of a list, after an
+// operator and around the reserved words.
+// The parser goes on after an error, so that every error of the line is
+// reported at once : the faulty part is left out of the tree.
+
+#[derive(Debug)]
+pub struct Word {
+    pub parts : Vec<Part>,
+    pub col   : usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RedirectKind {
+    Input,
+    ReadWrite,  // an input created if needed
+    HereDoc,    // the target is the body
+    HereString,
+    Output,
+    Append,
+    Dup,        // the target is a fd
+}
+
+#[derive(Debug)]
+pub struct Redirect {
+    pub fd     : usize,
+    pub kind   : RedirectKind,
+    pub target : Word,
+}
+
+// NAME=value, the value is a word without the 'NAME='
+#[derive(Debug)]
+pub struct Assign {
+    pub name  : Format,
+    pub value : Word,
+}
+
+#[derive(Debug)]
+pub struct Simple {
+    pub assigns   : Vec<Assign>,
+    pub words     : Vec<Word>,
+    pub redirects : Vec<Redirect>,
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Simple(Simple),
+    If(If),
+    Loop(Loop),
+    For(For),
+    Case(Case),
+    Group(List),
+    Function(Format, Rc<Command>),  // the definition of a function
+    Redirected(Box<Command>, Vec<Redirect>),  // a compound command
+}
+
+// the body of the first branch whose condition succeeds runs
+#[derive(Debug)]
+pub struct If {
+    pub branches  : Vec<(List, List)>,
+    pub otherwise : Option<List>,
+}
+
+// while the condition succeeds (fails with until)
+#[derive(Debug)]
+pub struct Loop {
+    pub until : bool,
+    pub cond  : List,
+    pub body  : List,
+}
+
+// without words, the loop is on the positional parameters
+#[derive(Debug)]
+pub struct For {
+    pub var   : Format,
+    pub words : Option<Vec<Word>>,
+    pub body  : List,
+}
+
+// the body of the first item with a pattern matching the word runs
+#[derive(Debug)]
+pub struct Case {
+    pub word  : Word,
+    pub items : Vec<(Vec<Word>, List)>,
+}
+
+#[derive(Debug)]
+pub struct Pipeline {
+    pub cmds : Vec<Command>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Connector {
+    And,
+    Or,
+}
+
+// the pipelines after the first one only run if the status of the previous
+// one is a success (&&) or a failure (||)
+#[derive(Debug)]
+pub struct AndOr {
+    pub first : Pipeline,
+    pub rest  : Vec<(Connector, Pipeline)>,
+}
+
+#[derive(Debug)]
+pub struct List {
+    pub items : Vec<AndOr>,
+}
+
+const RESERVED : [&str; 15] = ["if", "then", "elif", "else", "fi", "while", "until", "do", "done", "for", "in", "case", "esac", "{", "}"];
+
+// the reserved words which begin a compound command, the others end a list
+const OPENING : [&str; 6] = ["if", "while", "until", "for", "case", "{"];
+
+struct Parser {
+    tokens : Vec<Token>,
+    pos    : usize,
+    errs   : Vec<SyntaxErr>,
+}
+
+pub fn parse(input : &Format) -> Result<List, Vec<SyntaxErr>> {
+    let (tokens, errs) = lexer::lex(input);
+    let mut parser = Parser {tokens, pos: 0, errs};
+    let mut list = parser.list();
+    // what ends a list is out of place at the top (once reported by the
+    // compound command before it)
+    while let Some(token) = parser.peek() {
+        let col = token.col;
+        if !parser.errs.iter().any(|err| err.col == col) {parser.error(ParsingErr::UnexpectedToken, col)}
+        parser.pos += 1;
+        list.items.append(&mut parser.list().items);
+    }
+    if !parser.errs.is_empty() {return Err(parser.errs)}
+    Ok(list)
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn error(&mut self, err : ParsingErr, col : usize) {
+        self.errs.push(SyntaxErr {err, col});
+    }
+
+    // the reserved word at pos
+    fn keyword(&self) -> Option<&'static str> {
+        match &self.peek()?.tok {
+            Tok::Word(parts) => match &parts[..] {
+                [Part::Lit(text)] => RESERVED.iter().find(|word| text.iter().copied().eq(word.chars())).copied(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn newlines(&mut self) {
+        while self.peek().is_some_and(|token| token.tok == Tok::Newline) {self.pos += 1}
+    }
+
+    // reads the reserved word, else the compound command opened at col is
+    // left unterminated or something is out of place
+    fn expect(&mut self, word : &str, col : usize) -> bool {
+        if self.keyword() == Some(word) {
+            self.pos += 1;
+            return true
+        }
+        match self.peek() {
+            Some(token) => {let col = token.col; self.error(ParsingErr::UnexpectedToken, col)},
+            None => self.error(ParsingErr::IncompleteCommand, col),
+        }
+        false
+    }
+
+    // stops on what cannot begin a command (a reserved word ending a
+    // compound command, ')', ';;') or at the end
+    fn list(&mut self) -> List {
+        let mut items = Vec::new();
+        loop {
+            self.newlines();
+            if self.peek().is_none() || self.keyword().is_some_and(|word| !OPENING.contains(&word)) {break}
+            let item = self.and_or();
+            let empty = item.is_empty();
+            if !empty {items.push(item)}
+            match self.peek() {
+                Some(Token {tok: Tok::Semi, col}) => {
+                    let col = *col;
+                    self.pos += 1;
+                    if empty {self.error(ParsingErr::MissingCommand, col)}
+                },
+                Some(Token {tok: Tok::Newline, ..}) => self.pos += 1,
+                _ => break,
+            }
+        }
+        List {items}
+    }
+
+    fn and_or(&mut self) -> AndOr {
+        let mut and_or = AndOr {first: self.pipeline(), rest: Vec::new()};
+        while let Some(token) = self.peek() {
+            let connector = match token.tok {
+                Tok::AndIf => Connector::And,
+                Tok::OrIf => Connector::Or,
+                _ => break,
+            };
+            let col = token.col;
+            self.pos += 1;
+            self.newlines();
+            let pipeline = self.pipeline();
+            if pipeline.is_empty() || (and_or.rest.is_empty() && and_or.first.is_empty()) {self.error(ParsingErr::MissingCommand, col)}
+            and_or.rest.push((connector, pipeline));
+        }
+        and_or
+    }
+
+    fn pipeline(&mut self) -> Pipeline {
+        let mut cmds = vec![self.command()];
+        while let Some(token) = self.peek() {
+            if token.tok != Tok::Pipe {break}
+            let col = token.col;
+            self.pos += 1;
+            self.newlines();
+            // a pipe needs a command on both sides
+            let cmd = self.command();
+            if cmd.is_empty() || (cmds.len() == 1 && cmds[0].is_empty()) {self.error(ParsingErr::MissingCommand, col)}
+            cmds.push(cmd);
+        }
+        Pipeline {cmds}
+    }
+
+    fn command(&mut self) -> Command {
+        let col = self.peek().map_or(0, |token| token.col);
+        let keyword = self.keyword();
+        if keyword.is_some_and(|word| OPENING.contains(&word)) {self.pos += 1}
+        let compound = match keyword {
+            Some("if") => self.if_clause(col),
+            Some("while") | Some("until") => {
+                let cond = self.list();
+                let body = self.do_group(col);
+                Command::Loop(Loop {until: keyword == Some("until"), cond, body})
+            },
+            Some("for") => self.for_clause(col),
+            Some("case") => self.case_clause(col),
+            Some("{") => {
+                let list = self.list();
+                self.expect("}", col);
+                Command::Group(list)
+            },
+            _ => return match self.function_name() {
+                Some(name) => {
+                    self.pos += 3;
+                    self.newlines();
+                    // the body is a compound command
+                    let start = self.peek().map(|token| token.col);
+                    let body = self.command();
+                    if let Command::Simple(_) = body {
+                        match start {
+                            Some(start) => self.error(ParsingErr::UnexpectedToken, start),
+                            None => self.error(ParsingErr::IncompleteCommand, col),
+                        }
+                    }
+                    Command::Function(name, Rc::new(body))
+                },
+                None => Command::Simple(self.simple()),
+            },
+        };
+        let mut redirects = Vec::new();
+        while self.redirect(&mut redirects) {}
+        if redirects.is_empty() {return compound}
+        Command::Redirected(Box::new(compound), redirects)
+    }
+
+    // the name of the function defined at pos : NAME '(' ')'
+    fn function_name(&self) -> Option<Format> {
+        let name = match &self.tokens.get(self.pos)?.tok {
+            Tok::Word(parts) => match &parts[..] {
+                [Part::Lit(name)] if is_name(name) => name.clone(),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let paren = |k : usize, tok : Tok| self.tokens.get(self.pos + k).is_some_and(|token| token.tok == tok);
+        if paren(1, Tok::LParen) && paren(2, Tok::RParen) {Some(name)} else {None}
+    }
+
+    fn if_clause(&mut self, col : usize) -> Command {
+        let mut clause = If {branches: Vec::new(), otherwise: None};
+        loop {
+            let cond = self.list();
+            if !self.expect("then", col) {break}
+            let body = self.list();
+            clause.branches.push((cond, body));
+            match self.keyword() {
+                Some("elif") => self.pos += 1,
+                Some("else") => {
+                    self.pos += 1;
+                    clause.otherwise = Some(self.list());
+                    self.expect("fi", col);
+                    break
+                },
+                _ => {self.expect("fi", col); break},
+            }
+        }
+        Command::If(clause)
+    }
+
+    // 'do' list 'done'
+    fn do_group(&mut self, col : usize) -> List {
+        if !self.expect("do", col) {return List {items: Vec::new()}}
+        let body = self.list();
+        self.expect("done", col);
+        body
+    }
+
+    // a word where one is needed, else an error
+    fn word(&mut self, col : usize) -> Option<Word> {
+        match self.peek() {
+            Some(Token {tok: Tok::Word(parts), col}) => {
+                let word = Word {parts: parts.clone(), col: *col};
+                self.pos += 1;
+                Some(word)
+            },
+            Some(token) => {let col = token.col; self.error(ParsingErr::UnexpectedToken, col); None},
+            None => {self.error(ParsingErr::IncompleteCommand, col); None},
+        }
+    }
+
+    fn for_clause(&mut self, col : usize) -> Command {
+        let mut clause = For {var: Vec::new(), words: None, body: List {items: Vec::new()}};
+        match self.word(col) {
+            Some(Word {parts, col}) => match &parts[..] {
+                [Part::Lit(name)] if is_name(name) => clause.var = name.clone(),
+                _ => self.error(ParsingErr::InvalidVariable, col),
+            },
+            None => return Command::For(clause),
+        }
+        self.newlines();
+        if self.keyword() == Some("in") {
+            self.pos += 1;
+            let mut words = Vec::new();
+            while let Some(Token {tok: Tok::Word(parts), col}) = self.peek() {
+                words.push(Word {parts: parts.clone(), col: *col});
+                self.pos += 1;
+            }
+            clause.words = Some(words);
+        }
+        if self.peek().is_some_and(|token| token.tok == Tok::Semi) {self.pos += 1}
+        self.newlines();
+        clause.body = self.do_group(col);
+        Command::For(clause)
+    }
+
+    fn case_clause(&mut self, col : usize) -> Command {
+        let mut clause = Case {word: Word {parts: Vec::new(), col}, items: Vec::new()};
+        match self.word(col) {
+            Some(word) => clause.word = word,
+            None => return Command::Case(clause),
+        }
+        self.newlines();
+        if !self.expect("in", col) {return Command::Case(clause)}
+        loop {
+            self.newlines();
+            if self.keyword() == Some("esac") {self.pos += 1; break}
+            if self.peek().is_some_and(|token| token.tok == Tok::LParen) {self.pos += 1}
+            // the patterns, up to the ')'
+            let mut patterns = Vec::new();
+            loop {
+                match self.word(col) {
+                    Some(word) => patterns.push(word),
+                    None => return Command::Case(clause),
+                }
+                match self.peek() {
+                    Some(Token {tok: Tok::Pipe, ..}) => self.pos += 1,
+                    Some(Token {tok: Tok::RParen, ..}) => {self.pos += 1; break},
+                    Some(token) => {let col = token.col; self.error(ParsingErr::UnexpectedToken, col); return Command::Case(clause)},
+                    None => {self.error(ParsingErr::IncompleteCommand, col); return Command::Case(clause)},
+                }
+            }
+            let body = self.list();
+            clause.items.push((patterns, body));
+            // the last item may go without ';;'
+            if self.peek().is_some_and(|token| token.tok == Tok::DSemi) {
+                self.pos += 1;
+                continue
+            }
+            self.expect("esac", col);
+            break
+        }
+        Command::Case(clause)
+    }
+
+    // stops on an operator between commands or at the end of the line
+    fn simple(&mut self) -> Simple {
+        let mut simple = Simple {assigns: Vec::new(), words: Vec::new(), redirects: Vec::new()};
+        while let Some(token) = self.peek() {
+            // a reserved word is not a command
+            if simple.is_empty() && self.keyword().is_some() {break}
+            let col = token.col;
+            if let Tok::Word(parts) = &token.tok {
+                let parts = parts.clone();
+                match assign(&parts) {
+                    Some((name, parts)) if simple.words.is_empty() => simple.assigns.push(Assign {name, value: Word {parts, col}}),
+                    _ => simple.words.push(Word {parts, col}),
+                }
+                self.pos += 1;
+                continue
+            }
+            if !self.redirect(&mut simple.redirects) {break}
+        }
+        simple
+    }
+
+    // reads the redirection at pos into redirects (left out if it is faulty),
+    // false if there is none
+    fn redirect(&mut self, redirects : &mut Vec<Redirect>) -> bool {
+        let col = match self.peek() {
+            Some(token) => token.col,
+            None => return false,
+        };
+        // the lexer only gives a fd before a redirection
+        let mut k = self.pos;
+        let mut fd = None;
+        if let Tok::IoNumber(n) = self.tokens[k].tok {
+            fd = Some(n);
+            k += 1;
+        }
+        let mut body = None;
+        let kind = match &self.tokens[k].tok {
+            Tok::Less => RedirectKind::Input,
+            Tok::LessGreat => RedirectKind::ReadWrite,
+            Tok::DLess(parts) => {body = Some(parts.clone()); RedirectKind::HereDoc},
+            Tok::TLess => RedirectKind::HereString,
+            Tok::Great => RedirectKind::Output,
+            Tok::DGreat => RedirectKind::Append,
+            Tok::GreatAnd => RedirectKind::Dup,
+            _ => return false,
+        };
+        self.pos = k + 1;
+        let mut target = match self.peek() {
+            Some(Token {tok: Tok::Word(parts), col}) => Word {parts: parts.clone(), col: *col},
+            _ => {self.error(ParsingErr::IncorrectRedirect, col); return true},
+        };
+        self.pos += 1;
+        // the word after '<<' is only the delimiter
+        if let Some(parts) = body {target.parts = parts};
+
+        let input = matches!(kind, RedirectKind::Input | RedirectKind::ReadWrite | RedirectKind::HereDoc | RedirectKind::HereString);
+        let fd = fd.unwrap_or(if input {0} else {1});
+        let valid = match kind {
+            _ if input => fd == 0,
+            RedirectKind::Dup => (fd == 1 || fd == 2) && matches!(&target.parts[..], [Part::Lit(text)] if text[..] == ['1'] || text[..] == ['2']),
+            _ => fd == 1 || fd == 2,
+        };
+        if !valid {
+            self.error(ParsingErr::BadFd, col);
+            return true
+        }
+        redirects.push(Redirect {fd, kind, target});
+        true
+    }
+}
+
+// a word starting with an unquoted NAME=, split into the name and the value
+fn assign(parts : &[Part]) -> Option<(Format, Vec<Part>)> {
+    let text = match parts.first()? {
+        Part::Lit(text) => text,
+        _ => return None,
+    };
+    let eq = text.iter().position(|&c| c == '=')?;
+    let name = text[..eq].to_vec();
+    if !is_name(&name) {return None}
+    let mut value = Vec::new();
+    if eq + 1 < text.len() {value.push(Part::Lit(text[eq + 1..].to_vec()))}
+    value.extend_from_slice(&parts[1..]);
+    Some((name, value))
+}
+
+// a variable name : a letter or '_', then letters, digits or '_'
+pub fn is_name(name : &Format) -> bool {
+    match name.first() {
+        Some(c) if c.is_ascii_alphabetic() || *c == '_' => name.iter().all(|c| c.is_ascii_alphanumeric() || *c == '_'),
+        _ => false,
+    }
+}
+
+impl Simple {
+    fn is_empty(&self) -> bool {
+        self.assigns.is_empty() && self.words.is_empty() && self.redirects.is_empty()
+    }
+}
+
+impl Command {
+    fn is_empty(&self) -> bool {
+        match self {
+            Command::Simple(simple) => simple.is_empty(),
+            _ => false,
+        }
+    }
+}
+
+impl Pipeline {
+    fn is_empty(&self) -> bool {
+        self.cmds.len() == 1 && self.cmds[0].is_empty()
+    }
+}
+
+impl AndOr {
+    fn is_empty(&self) -> bool {
+        self.rest.is_empty() && self.first.is_empty()
+    }
+}